@@ -0,0 +1,113 @@
+// physical address space, every access is routed to the region containing it
+// DRAM and the built-in peripherals are regions too, embedders can register their own devices
+use crate::error::EmulatorError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusFault {
+    // nothing is mapped there or the device refused the access
+    AccessFault,
+    // device doesn't support the alignment of the access
+    Misaligned,
+}
+
+// memory mapped device, offsets are relative to the base of its region
+// size is always 1, 2, 4 or 8 and the access fits inside the region
+pub trait Device {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault>;
+    fn write(&mut self, offset: u64, size: u64, val: u64) -> Result<(), BusFault>;
+
+    // bytes of host memory holding device contents, reported with --mem-stats
+    fn resident(&self) -> usize {
+        0
+    }
+}
+
+struct Region {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    fn contains(&self, addr: u64, size: u64) -> bool {
+        addr >= self.base
+            && addr
+                .checked_add(size)
+                .is_some_and(|end| end <= self.base + self.size)
+    }
+}
+
+#[derive(Default)]
+pub struct Bus {
+    regions: Vec<Region>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // maps device at [base, base + size), where regions overlap the one registered later wins
+    // so peripherals can shadow parts of memory
+    pub fn register(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), EmulatorError> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(EmulatorError::BusRegion(format!(
+                "{} at {:#x} with size {:#x}",
+                name, base, size
+            )));
+        }
+        self.regions.push(Region { base, size, device });
+        Ok(())
+    }
+
+    fn region(&mut self, addr: u64, size: u64) -> Option<&mut Region> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|r| r.contains(addr, size))
+    }
+
+    // true when size bytes at addr are inside a single region
+    pub fn contains(&self, addr: u64, size: u64) -> bool {
+        self.regions.iter().any(|r| r.contains(addr, size))
+    }
+
+    // host memory used by all regions
+    pub fn resident(&self) -> usize {
+        self.regions.iter().map(|r| r.device.resident()).sum()
+    }
+
+    pub fn read(&mut self, addr: u64, size: u64) -> Result<u64, BusFault> {
+        let region = self.region(addr, size).ok_or(BusFault::AccessFault)?;
+        let offset = addr - region.base;
+        region.device.read(offset, size)
+    }
+
+    pub fn write(&mut self, addr: u64, size: u64, val: u64) -> Result<(), BusFault> {
+        let region = self.region(addr, size).ok_or(BusFault::AccessFault)?;
+        let offset = addr - region.base;
+        region.device.write(offset, size, val)
+    }
+
+    pub fn get_u32(&mut self, addr: usize) -> Result<u32, BusFault> {
+        self.read(addr as u64, 4).map(|val| val as u32)
+    }
+
+    pub fn get_u64(&mut self, addr: usize) -> Result<u64, BusFault> {
+        self.read(addr as u64, 8)
+    }
+
+    pub fn set_u32(&mut self, addr: usize, val: u32) -> Result<(), BusFault> {
+        self.write(addr as u64, 4, val as u64)
+    }
+
+    pub fn set_u64(&mut self, addr: usize, val: u64) -> Result<(), BusFault> {
+        self.write(addr as u64, 8, val)
+    }
+}
@@ -0,0 +1,250 @@
+// core local interruptor, machine timer and software interrupt of the only hart
+// registers follow the SiFive layout: msip at +0x0, mtimecmp at +0x4000, mtime at +0xBFF8
+use std::time::Instant;
+
+use crate::{
+    bus::{BusFault, Device},
+    csr::{read_csr_raw, set_csr_raw, MIP, TIME},
+    CLINT,
+};
+
+pub const DEFAULT_CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+// register offsets
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+// mtime frequency when it follows host time
+const HOST_TIMEBASE_HZ: u128 = 10_000_000;
+
+// machine software and timer interrupt pending bits
+const MIP_MSIP: u64 = 1 << 3;
+const MIP_MTIP: u64 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timebase {
+    // mtime advances by one every instruction
+    Instret,
+    // mtime advances at HOST_TIMEBASE_HZ of wall clock time
+    Host,
+}
+
+#[derive(Debug)]
+pub struct Clint {
+    timebase: Timebase,
+    start: Instant,
+    // mtime in instret mode, difference from host time in host mode
+    mtime: u64,
+    mtimecmp: u64,
+    msip: u32,
+}
+
+impl Clint {
+    pub fn new(timebase: Timebase) -> Self {
+        Self {
+            timebase,
+            start: Instant::now(),
+            mtime: 0,
+            mtimecmp: u64::MAX,
+            msip: 0,
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        match self.timebase {
+            Timebase::Instret => self.mtime,
+            Timebase::Host => {
+                let ticks = self.start.elapsed().as_nanos() * HOST_TIMEBASE_HZ / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+        }
+    }
+
+    fn set_mtime(&mut self, val: u64) {
+        let now = self.mtime();
+        self.mtime = self.mtime.wrapping_add(val.wrapping_sub(now));
+    }
+
+    // offset of the register containing addr, its width and value
+    fn register(&self, offset: u64) -> Option<(u64, u64, u64)> {
+        match offset {
+            MSIP..=0x3 => Some((MSIP, 4, self.msip as u64)),
+            MTIMECMP..=0x4007 => Some((MTIMECMP, 8, self.mtimecmp)),
+            MTIME..=0xBFFF => Some((MTIME, 8, self.mtime())),
+            _ => None,
+        }
+    }
+
+    fn read(&self, offset: u64, size: u64) -> u64 {
+        match self.register(offset) {
+            // accesses narrower than the register read part of it, RV32 reads halves of mtime
+            Some((start, width, val)) if offset + size <= start + width => {
+                let val = val >> ((offset - start) * 8);
+                if size == 8 {
+                    val
+                } else {
+                    val & ((1 << (size * 8)) - 1)
+                }
+            }
+            // unmapped offsets read as zero
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, val: u64) {
+        let Some((start, width, old)) = self.register(offset) else {
+            return;
+        };
+        if offset + size > start + width {
+            return;
+        }
+        let shift = (offset - start) * 8;
+        let mask = if size == 8 {
+            u64::MAX
+        } else {
+            ((1 << (size * 8)) - 1) << shift
+        };
+        let new = (old & !mask) | (val << shift & mask);
+        match start {
+            // only bit 0 of msip is implemented
+            MSIP => self.msip = new as u32 & 1,
+            MTIMECMP => self.mtimecmp = new,
+            _ => self.set_mtime(new),
+        }
+    }
+
+    // updates time CSR and machine timer and software interrupt pending bits
+    fn update_mip(&self) {
+        let mtime = self.mtime();
+        set_csr_raw(TIME, mtime);
+        let mut mip = read_csr_raw(MIP) & !(MIP_MSIP | MIP_MTIP);
+        if self.msip & 1 != 0 {
+            mip |= MIP_MSIP;
+        }
+        if mtime >= self.mtimecmp {
+            mip |= MIP_MTIP;
+        }
+        set_csr_raw(MIP, mip);
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Clint::new(Timebase::Instret)
+    }
+}
+
+// advances mtime by one instruction and raises interrupts, called once per step
+pub fn tick() {
+    CLINT.with(|x| {
+        let mut clint = x.borrow_mut();
+        if clint.timebase == Timebase::Instret {
+            clint.mtime = clint.mtime.wrapping_add(1);
+        }
+        clint.update_mip();
+    });
+}
+
+// bus handle of the CLINT, state stays in the thread local so tick can reach it
+pub struct ClintDevice;
+
+// registers can be accessed only with naturally aligned accesses
+impl Device for ClintDevice {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        Ok(CLINT.with(|x| x.borrow().read(offset, size)))
+    }
+
+    fn write(&mut self, offset: u64, size: u64, val: u64) -> Result<(), BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        CLINT.with(|x| {
+            let mut clint = x.borrow_mut();
+            clint.write(offset, size, val);
+            clint.update_mip();
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mip() -> u64 {
+        read_csr_raw(MIP)
+    }
+
+    #[test]
+    fn rv32_halves() {
+        let mut clint = ClintDevice;
+        // mtimecmp written as two words, low first like RV32 software does
+        clint.write(MTIMECMP, 4, 0x89AB_CDEF).unwrap();
+        clint.write(MTIMECMP + 4, 4, 0x0123_4567).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(clint.read(MTIMECMP + 4, 4), Ok(0x0123_4567));
+        // writing a half keeps the other one
+        clint.write(MTIMECMP, 4, 0).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Ok(0x0123_4567_0000_0000));
+
+        clint.write(MTIME, 8, 0x1_FFFF_FFFF).unwrap();
+        assert_eq!(clint.read(MTIME, 4), Ok(0xFFFF_FFFF));
+        assert_eq!(clint.read(MTIME + 4, 4), Ok(1));
+        // carry from the low half shows up in the high one
+        tick();
+        assert_eq!(clint.read(MTIME, 4), Ok(0));
+        assert_eq!(clint.read(MTIME + 4, 4), Ok(2));
+        clint.write(MTIME + 4, 4, 0).unwrap();
+        assert_eq!(clint.read(MTIME, 8), Ok(0));
+        assert_eq!(read_csr_raw(TIME), 0);
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        let mut clint = ClintDevice;
+        tick();
+        assert_eq!(mip() & MIP_MTIP, 0);
+        clint.write(MTIMECMP, 8, 3).unwrap();
+        tick();
+        assert_eq!(mip() & MIP_MTIP, 0);
+        tick();
+        assert_eq!(read_csr_raw(TIME), 3);
+        assert_ne!(mip() & MIP_MTIP, 0);
+        // moving mtimecmp forward clears it right away
+        clint.write(MTIMECMP, 8, 10).unwrap();
+        assert_eq!(mip() & MIP_MTIP, 0);
+        clint.write(MTIMECMP, 8, 1).unwrap();
+        assert_ne!(mip() & MIP_MTIP, 0);
+        // and so does moving mtime back
+        clint.write(MTIME, 8, 0).unwrap();
+        assert_eq!(mip() & MIP_MTIP, 0);
+    }
+
+    #[test]
+    fn software_interrupt_uses_bit_0() {
+        let mut clint = ClintDevice;
+        clint.write(MSIP, 4, 0xFFFF_FFFE).unwrap();
+        assert_eq!(clint.read(MSIP, 4), Ok(0));
+        assert_eq!(mip() & MIP_MSIP, 0);
+        clint.write(MSIP, 4, 3).unwrap();
+        assert_eq!(clint.read(MSIP, 4), Ok(1));
+        assert_ne!(mip() & MIP_MSIP, 0);
+        clint.write(MSIP, 4, 0).unwrap();
+        assert_eq!(mip() & MIP_MSIP, 0);
+    }
+
+    #[test]
+    fn misaligned_access_faults() {
+        let mut clint = ClintDevice;
+        assert_eq!(clint.read(MTIMECMP + 4, 8), Err(BusFault::Misaligned));
+        assert_eq!(clint.write(MSIP + 2, 4, 1), Err(BusFault::Misaligned));
+        // unmapped offsets read as zero and ignore writes
+        assert_eq!(clint.write(0x8000, 8, 1), Ok(()));
+        assert_eq!(clint.read(0x8000, 8), Ok(0));
+    }
+}
@@ -0,0 +1,389 @@
+// Zicsr
+// CSR values live in the CSRS thread local, except of the floating point ones which are views
+// of FCSR and supervisor ones which are views of their machine counterparts
+use crate::{
+    isa::Isa,
+    mmu::{satp_mode_supported, satp_written},
+    pmp, read_fcsr, set_fcsr, xlen, CSRS, PRIVILEGE,
+};
+
+// user floating point
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+// user vector
+pub const VSTART: u16 = 0x008;
+pub const VXSAT: u16 = 0x009;
+pub const VXRM: u16 = 0x00A;
+pub const VCSR: u16 = 0x00F;
+pub const VL: u16 = 0xC20;
+pub const VTYPE: u16 = 0xC21;
+pub const VLENB: u16 = 0xC22;
+
+// user counters
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+// upper halves of counters in RV32
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+// supervisor
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const SATP: u16 = 0x180;
+
+// machine
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+// physical memory protection
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPCFG15: u16 = 0x3AF;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const PMPADDR63: u16 = 0x3EF;
+// RV32 only
+pub const MSTATUSH: u16 = 0x310;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+// privilege levels
+pub const USER: u64 = 0b00;
+pub const SUPERVISOR: u64 = 0b01;
+pub const MACHINE: u64 = 0b11;
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+// UXL and SXL are hardwired to 64 bits, they don't exist in RV32
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_SXL: u64 = 0b11 << 34;
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+const MSTATUS_WRITE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+// part of mstatus visible through sstatus
+const SSTATUS_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_UXL
+    | MSTATUS_SD;
+
+// supervisor software, timer and external interrupts
+const S_INTERRUPTS: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+// all standard interrupts
+const INTERRUPTS: u64 = S_INTERRUPTS | (1 << 3) | (1 << 7) | (1 << 11);
+
+// CSR that is accessed through another one
+enum Backing {
+    // value stored under its own address
+    Plain,
+    // masked view of other CSR
+    View(u16),
+    // bits of other CSR starting at given bit
+    Bits(u16, u32),
+    // bits of FCSR starting at given bit
+    Fcsr(u32),
+    // value is constant
+    Const(u64),
+}
+
+struct CsrSpec {
+    backing: Backing,
+    read_mask: u64,
+    write_mask: u64,
+}
+
+fn spec(addr: u16) -> Option<CsrSpec> {
+    let (backing, read_mask, write_mask) = match addr {
+        FFLAGS => (Backing::Fcsr(0), 0x1F, 0x1F),
+        FRM => (Backing::Fcsr(5), 0x7, 0x7),
+        FCSR => (Backing::Fcsr(0), 0xFF, 0xFF),
+
+        VSTART => (Backing::Plain, u64::MAX, 0xFFFF),
+        VXSAT => (Backing::Bits(VCSR, 0), 0x1, 0x1),
+        VXRM => (Backing::Bits(VCSR, 1), 0x3, 0x3),
+        VCSR => (Backing::Plain, 0x7, 0x7),
+        VL | VTYPE | VLENB => (Backing::Plain, u64::MAX, 0),
+
+        CYCLE => (Backing::View(MCYCLE), u64::MAX, 0),
+        // kept up to date by CLINT
+        TIME => (Backing::Plain, u64::MAX, 0),
+        INSTRET => (Backing::View(MINSTRET), u64::MAX, 0),
+        CYCLEH if xlen!() == 32 => (Backing::Bits(MCYCLE, 32), u64::MAX, 0),
+        TIMEH if xlen!() == 32 => (Backing::Bits(TIME, 32), u64::MAX, 0),
+        INSTRETH if xlen!() == 32 => (Backing::Bits(MINSTRET, 32), u64::MAX, 0),
+
+        SSTATUS => (
+            Backing::View(MSTATUS),
+            SSTATUS_MASK,
+            MSTATUS_WRITE & SSTATUS_MASK,
+        ),
+        SIE => (Backing::View(MIE), S_INTERRUPTS, S_INTERRUPTS),
+        SIP => (Backing::View(MIP), S_INTERRUPTS, 1 << 1),
+        STVEC => (Backing::Plain, u64::MAX, !0b10),
+        SCOUNTEREN => (Backing::Plain, u64::MAX, 0b111),
+        SSCRATCH => (Backing::Plain, u64::MAX, u64::MAX),
+        SEPC => (Backing::Plain, u64::MAX, !0b1),
+        SCAUSE => (Backing::Plain, u64::MAX, u64::MAX),
+        STVAL => (Backing::Plain, u64::MAX, u64::MAX),
+        SATP => (Backing::Plain, u64::MAX, u64::MAX),
+
+        MVENDORID | MARCHID | MIMPID | MHARTID => (Backing::Const(0), u64::MAX, 0),
+        MISA => (Backing::Plain, u64::MAX, 0),
+        MSTATUS => (Backing::Plain, u64::MAX, MSTATUS_WRITE),
+        MEDELEG => (Backing::Plain, u64::MAX, 0xB3FF),
+        MIDELEG => (Backing::Plain, u64::MAX, S_INTERRUPTS),
+        MIE => (Backing::Plain, u64::MAX, INTERRUPTS),
+        MIP => (Backing::Plain, u64::MAX, S_INTERRUPTS),
+        MTVEC => (Backing::Plain, u64::MAX, !0b10),
+        MCOUNTEREN => (Backing::Plain, u64::MAX, 0b111),
+        MSCRATCH => (Backing::Plain, u64::MAX, u64::MAX),
+        MEPC => (Backing::Plain, u64::MAX, !0b1),
+        MCAUSE => (Backing::Plain, u64::MAX, u64::MAX),
+        MTVAL => (Backing::Plain, u64::MAX, u64::MAX),
+        // in RV32 the low half, writes leave mcycleh and minstreth alone
+        MCYCLE if xlen!() == 32 => (Backing::Bits(MCYCLE, 0), 0xFFFFFFFF, 0xFFFFFFFF),
+        MINSTRET if xlen!() == 32 => (Backing::Bits(MINSTRET, 0), 0xFFFFFFFF, 0xFFFFFFFF),
+        MCYCLE => (Backing::Plain, u64::MAX, u64::MAX),
+        MINSTRET => (Backing::Plain, u64::MAX, u64::MAX),
+        MCYCLEH if xlen!() == 32 => (Backing::Bits(MCYCLE, 32), u64::MAX, 0xFFFFFFFF),
+        MINSTRETH if xlen!() == 32 => (Backing::Bits(MINSTRET, 32), u64::MAX, 0xFFFFFFFF),
+        MSTATUSH if xlen!() == 32 => (Backing::Const(0), u64::MAX, 0),
+        // odd pmpcfg registers don't exist in RV64
+        PMPCFG0..=PMPCFG15 if xlen!() == 32 || addr.is_multiple_of(2) => {
+            (Backing::Plain, u64::MAX, u64::MAX)
+        }
+        // pmpaddr of entries that aren't implemented
+        PMPADDR0..=PMPADDR63 if pmp::addr_entry(addr) >= pmp::entries() => {
+            (Backing::Const(0), u64::MAX, 0)
+        }
+        // pmpaddr holds bits 55:2 of address in RV64 and bits 33:2 in RV32
+        PMPADDR0..=PMPADDR63 if xlen!() == 32 => (Backing::Plain, u64::MAX, 0xFFFFFFFF),
+        PMPADDR0..=PMPADDR63 => (Backing::Plain, u64::MAX, (1 << 54) - 1),
+
+        _ => return None,
+    };
+    Some(CsrSpec {
+        backing,
+        read_mask,
+        write_mask,
+    })
+}
+
+#[inline(always)]
+pub fn current_privilege() -> u64 {
+    PRIVILEGE.with(|x| *x.borrow())
+}
+
+#[inline(always)]
+pub fn set_privilege(privilege: u64) {
+    PRIVILEGE.with(|x| *x.borrow_mut() = privilege);
+}
+
+// bits 9:8 of CSR address hold the lowest privilege level that can access it
+fn accessible(addr: u16) -> bool {
+    let privilege = current_privilege();
+    if (addr as u64 >> 8) & 0b11 > privilege {
+        return false;
+    }
+    match addr {
+        // user counters are enabled by mcounteren for S and additionally by scounteren for U
+        CYCLE..=INSTRET | CYCLEH..=INSTRETH => {
+            let bit = 1 << (addr & 0x1F);
+            (privilege == MACHINE || read_csr_raw(MCOUNTEREN) & bit != 0)
+                && (privilege != USER || read_csr_raw(SCOUNTEREN) & bit != 0)
+        }
+        // mstatus.TVM traps satp accesses in S mode
+        SATP => privilege == MACHINE || read_csr_raw(MSTATUS) & MSTATUS_TVM == 0,
+        _ => true,
+    }
+}
+
+// bits 11:10 of CSR address set to 0b11 mark it as read-only
+fn read_only(addr: u16) -> bool {
+    addr >> 10 & 0b11 == 0b11
+}
+
+// raw value of CSR stored in CSRS, without any checks
+#[inline(always)]
+pub fn read_csr_raw(addr: u16) -> u64 {
+    CSRS.with(|x| x.borrow()[addr as usize])
+}
+
+#[inline(always)]
+pub fn set_csr_raw(addr: u16, val: u64) {
+    CSRS.with(|x| x.borrow_mut()[addr as usize] = val);
+}
+
+fn read_backing(spec: &CsrSpec, addr: u16) -> u64 {
+    match spec.backing {
+        Backing::Plain => read_csr_raw(addr),
+        Backing::View(target) => read_csr_raw(target),
+        Backing::Bits(target, shift) => read_csr_raw(target) >> shift,
+        Backing::Fcsr(shift) => (read_fcsr!() >> shift) as u64,
+        Backing::Const(val) => val,
+    }
+}
+
+fn write_backing(spec: &CsrSpec, addr: u16, val: u64) {
+    let old = read_backing(spec, addr);
+    let new = (old & !spec.write_mask) | (val & spec.write_mask);
+    match spec.backing {
+        Backing::Plain => set_csr_raw(addr, new),
+        Backing::View(target) => set_csr_raw(target, new),
+        Backing::Bits(target, shift) => {
+            let mask = spec.write_mask << shift;
+            set_csr_raw(
+                target,
+                (read_csr_raw(target) & !mask) | (new << shift & mask),
+            );
+        }
+        Backing::Fcsr(shift) => {
+            let mask = (spec.write_mask as u32) << shift;
+            set_fcsr!((read_fcsr!() & !mask) | ((new as u32) << shift & mask));
+        }
+        Backing::Const(_) => {}
+    }
+}
+
+// reads CSR with privilege check, None means illegal instruction
+pub fn read_csr(addr: u16) -> Option<u64> {
+    let spec = spec(addr)?;
+    if !accessible(addr) {
+        return None;
+    }
+    Some(read_backing(&spec, addr) & spec.read_mask)
+}
+
+// writes CSR with privilege and read-only checks, false means illegal instruction
+pub fn write_csr(addr: u16, val: u64) -> bool {
+    let spec = match spec(addr) {
+        Some(spec) => spec,
+        None => return false,
+    };
+    if !accessible(addr) || read_only(addr) {
+        return false;
+    }
+    // in RV32 CSRs are 32 bit wide
+    let mut val = if xlen!() == 32 { val & 0xFFFFFFFF } else { val };
+    // satp is WARL, unsupported translation modes leave it unchanged
+    if addr == SATP && !satp_mode_supported(val) {
+        return true;
+    }
+    // locked PMP entries ignore writes
+    match addr {
+        PMPCFG0..=PMPCFG15 => val = pmp::cfg_write_value(addr, val),
+        PMPADDR0..=PMPADDR63 if pmp::addr_locked(addr) => return true,
+        _ => {}
+    }
+    // MPP is WARL, reserved level 0b10 keeps previous value
+    if addr == MSTATUS && val & MSTATUS_MPP == 0b10 << 11 {
+        val = (val & !MSTATUS_MPP) | (read_csr_raw(MSTATUS) & MSTATUS_MPP);
+    }
+    let old = read_csr_raw(addr);
+    write_backing(&spec, addr, val);
+    if addr == SATP {
+        satp_written(old, read_csr_raw(SATP));
+    }
+    true
+}
+
+// sets reset values of CSRs
+pub fn init_csrs(isa: &Isa) {
+    set_privilege(MACHINE);
+    set_csr_raw(MISA, isa.misa());
+    if isa.xlen == 64 {
+        set_csr_raw(MSTATUS, (0b10 << 32) | (0b10 << 34));
+    }
+    set_csr_raw(VLENB, isa.vlen as u64 / 8);
+    // vector unit starts unconfigured
+    set_csr_raw(VTYPE, 1 << (isa.xlen - 1));
+}
+
+// counts retired instruction
+#[inline(always)]
+pub fn tick() {
+    CSRS.with(|x| {
+        let mut csrs = x.borrow_mut();
+        csrs[MCYCLE as usize] = csrs[MCYCLE as usize].wrapping_add(1);
+        csrs[MINSTRET as usize] = csrs[MINSTRET as usize].wrapping_add(1);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{isa::parse_isa, ISA};
+
+    fn rv32() {
+        ISA.with(|x| *x.borrow_mut() = parse_isa("rv32gc").unwrap());
+    }
+
+    #[test]
+    fn rv32_counter_halves() {
+        rv32();
+        assert!(write_csr(MCYCLEH, 1));
+        assert!(write_csr(MCYCLE, 5));
+        assert_eq!(read_csr(MCYCLEH), Some(1));
+        assert_eq!(read_csr(MCYCLE), Some(5));
+        assert_eq!(read_csr_raw(MCYCLE), 1 << 32 | 5);
+        // low half overflowing on a tick carries into the high half
+        assert!(write_csr(MINSTRET, 0xFFFF_FFFF));
+        assert!(write_csr(MINSTRETH, 2));
+        tick();
+        assert_eq!(read_csr(MINSTRET), Some(0));
+        assert_eq!(read_csr(MINSTRETH), Some(3));
+        assert_eq!(read_csr(INSTRETH), Some(3));
+    }
+}
@@ -1,90 +1,221 @@
-use std::{
-    mem::transmute,
-    ops::{Deref, DerefMut},
-};
+use crate::bus::{BusFault, Device};
+use std::collections::HashMap;
 
 pub const DRAM_SIZE: usize = 64 * 1024 * 1024;
+// memory is allocated in pages of this size
+const PAGE_SIZE: usize = 4096;
+
+// sparse memory, a page is allocated on its first write and untouched pages read as zero
 pub struct Dram {
-    vec: Vec<u8>,
+    size: usize,
+    pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
 }
 
 impl Dram {
-    pub fn new_dram() -> Self {
+    pub fn new_dram(size: usize) -> Self {
         Self {
-            vec: vec![0u8; DRAM_SIZE],
+            size,
+            pages: HashMap::new(),
         }
     }
 
-    pub fn set_u8(&mut self, addr: usize, val: u8) {
-        self.vec[addr] = val;
+    // bytes of host memory backing allocated pages
+    pub fn resident(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
     }
 
-    pub fn set_u16(&mut self, addr: usize, val: u16) {
-        let (v1, v2) = unsafe { transmute(val) };
-        self.vec[addr] = v1;
-        self.vec[addr + 1] = v2;
+    // len bytes at addr have to be inside of dram
+    fn check(&self, addr: usize, len: usize) -> Result<(), BusFault> {
+        if addr.checked_add(len).is_some_and(|end| end <= self.size) {
+            Ok(())
+        } else {
+            Err(BusFault::AccessFault)
+        }
     }
 
-    pub fn set_u32(&mut self, addr: usize, val: u32) {
-        let (v1, v2, v3, v4) = unsafe { transmute(val) };
-        self.vec[addr] = v1;
-        self.vec[addr + 1] = v2;
-        self.vec[addr + 2] = v3;
-        self.vec[addr + 3] = v4;
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), BusFault> {
+        self.check(addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = (addr + done) % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(buf.len() - done);
+            match self.pages.get(&((addr + done) / PAGE_SIZE)) {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[offset..offset + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        Ok(())
     }
 
-    pub fn set_u64(&mut self, addr: usize, val: u64) {
-        let a: [u8; 8] = unsafe { transmute(val) };
-        for i in 0..8 {
-            self.vec[addr + i] = a[i];
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), BusFault> {
+        self.check(addr, bytes.len())?;
+        let mut done = 0;
+        while done < bytes.len() {
+            let offset = (addr + done) % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(bytes.len() - done);
+            let page = self
+                .pages
+                .entry((addr + done) / PAGE_SIZE)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            page[offset..offset + n].copy_from_slice(&bytes[done..done + n]);
+            done += n;
         }
+        Ok(())
     }
 
-    pub fn get_u8(&mut self, addr: usize) -> u8 {
-        self.vec[addr]
+    // copies program data to memory
+    pub fn load(&mut self, addr: usize, bytes: &[u8]) -> Result<(), BusFault> {
+        self.write_bytes(addr, bytes)
     }
 
-    pub fn get_u16(&mut self, addr: usize) -> u16 {
-        unsafe { transmute([self.vec[addr], self.vec[addr + 1]]) }
+    // zeroes len bytes at addr, whole pages are freed instead of written
+    pub fn zero(&mut self, addr: usize, len: usize) -> Result<(), BusFault> {
+        self.check(addr, len)?;
+        let end = addr + len;
+        let mut at = addr;
+        while at < end {
+            let offset = at % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(end - at);
+            if n == PAGE_SIZE {
+                self.pages.remove(&(at / PAGE_SIZE));
+            } else if let Some(page) = self.pages.get_mut(&(at / PAGE_SIZE)) {
+                page[offset..offset + n].fill(0);
+            }
+            at += n;
+        }
+        Ok(())
     }
 
-    pub fn get_u32(&mut self, addr: usize) -> u32 {
-        unsafe {
-            transmute([
-                self.vec[addr],
-                self.vec[addr + 1],
-                self.vec[addr + 2],
-                self.vec[addr + 3],
-            ])
-        }
+    pub fn set_u8(&mut self, addr: usize, val: u8) -> Result<(), BusFault> {
+        self.write_bytes(addr, &[val])
     }
 
-    pub fn get_u64(&mut self, addr: usize) -> u64 {
-        unsafe {
-            transmute([
-                self.vec[addr],
-                self.vec[addr + 1],
-                self.vec[addr + 2],
-                self.vec[addr + 3],
-                self.vec[addr + 4],
-                self.vec[addr + 5],
-                self.vec[addr + 6],
-                self.vec[addr + 7],
-            ])
-        }
+    pub fn set_u16(&mut self, addr: usize, val: u16) -> Result<(), BusFault> {
+        self.write_bytes(addr, &val.to_le_bytes())
+    }
+
+    pub fn set_u32(&mut self, addr: usize, val: u32) -> Result<(), BusFault> {
+        self.write_bytes(addr, &val.to_le_bytes())
+    }
+
+    pub fn set_u64(&mut self, addr: usize, val: u64) -> Result<(), BusFault> {
+        self.write_bytes(addr, &val.to_le_bytes())
+    }
+
+    pub fn get_u8(&mut self, addr: usize) -> Result<u8, BusFault> {
+        let mut buf = [0; 1];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn get_u16(&mut self, addr: usize) -> Result<u16, BusFault> {
+        let mut buf = [0; 2];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn get_u32(&mut self, addr: usize) -> Result<u32, BusFault> {
+        let mut buf = [0; 4];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn get_u64(&mut self, addr: usize) -> Result<u64, BusFault> {
+        let mut buf = [0; 8];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
     }
 }
 
-impl Deref for Dram {
-    type Target = Vec<u8>;
+// DRAM region of the bus, accesses outside of its size fault
+impl Device for Dram {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        let addr = offset as usize;
+        Ok(match size {
+            1 => self.get_u8(addr)? as u64,
+            2 => self.get_u16(addr)? as u64,
+            4 => self.get_u32(addr)? as u64,
+            _ => self.get_u64(addr)?,
+        })
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.vec
+    fn write(&mut self, offset: u64, size: u64, val: u64) -> Result<(), BusFault> {
+        let addr = offset as usize;
+        match size {
+            1 => self.set_u8(addr, val as u8),
+            2 => self.set_u16(addr, val as u16),
+            4 => self.set_u32(addr, val as u32),
+            _ => self.set_u64(addr, val),
+        }
+    }
+
+    fn resident(&self) -> usize {
+        Dram::resident(self)
     }
 }
 
-impl DerefMut for Dram {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.vec
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_outside_faults() {
+        let mut dram = Dram::new_dram(0x2000);
+        assert_eq!(dram.set_u32(0x1FFC, 7), Ok(()));
+        assert_eq!(dram.get_u32(0x1FFC), Ok(7));
+        // straddles the end
+        assert_eq!(dram.set_u64(0x1FFC, 7), Err(BusFault::AccessFault));
+        assert_eq!(dram.get_u16(0x1FFF), Err(BusFault::AccessFault));
+        assert_eq!(dram.get_u8(0x2000), Err(BusFault::AccessFault));
+        assert_eq!(dram.get_u8(usize::MAX), Err(BusFault::AccessFault));
+        assert_eq!(dram.load(0x1FFF, &[1, 2]), Err(BusFault::AccessFault));
+        assert_eq!(dram.zero(0x1000, 0x1001), Err(BusFault::AccessFault));
+        assert_eq!(
+            Device::read(&mut dram, 0x1FFC, 8),
+            Err(BusFault::AccessFault)
+        );
+        assert_eq!(
+            Device::write(&mut dram, 0x2000, 1, 0),
+            Err(BusFault::AccessFault)
+        );
+    }
+
+    #[test]
+    fn pages_allocated_on_write() {
+        let mut dram = Dram::new_dram(DRAM_SIZE);
+        assert_eq!(dram.resident(), 0);
+        // reads of untouched memory don't allocate
+        assert_eq!(dram.get_u64(0x10_0000), Ok(0));
+        assert_eq!(dram.resident(), 0);
+        dram.set_u8(0x10_0000, 1).unwrap();
+        assert_eq!(dram.resident(), PAGE_SIZE);
+        // write crossing a page boundary allocates both pages
+        dram.set_u64(3 * PAGE_SIZE - 4, u64::MAX).unwrap();
+        assert_eq!(dram.resident(), 3 * PAGE_SIZE);
+        assert_eq!(dram.get_u64(3 * PAGE_SIZE - 4), Ok(u64::MAX));
+        assert_eq!(dram.get_u32(3 * PAGE_SIZE), Ok(u32::MAX));
+        assert_eq!(dram.get_u32(3 * PAGE_SIZE + 4), Ok(0));
+        // zero bytes are stored like any other
+        dram.set_u8(DRAM_SIZE - 1, 0).unwrap();
+        assert_eq!(dram.resident(), 4 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn zero_frees_whole_pages() {
+        let mut dram = Dram::new_dram(DRAM_SIZE);
+        dram.load(PAGE_SIZE - 2, &[0xAA; PAGE_SIZE + 4]).unwrap();
+        assert_eq!(dram.resident(), 3 * PAGE_SIZE);
+        // .bss from the middle of the first page to the middle of the third
+        dram.zero(PAGE_SIZE - 1, PAGE_SIZE + 2).unwrap();
+        assert_eq!(dram.resident(), 2 * PAGE_SIZE);
+        assert_eq!(dram.get_u8(PAGE_SIZE - 2), Ok(0xAA));
+        assert_eq!(dram.get_u8(PAGE_SIZE - 1), Ok(0));
+        assert_eq!(dram.get_u64(PAGE_SIZE), Ok(0));
+        assert_eq!(dram.get_u8(2 * PAGE_SIZE), Ok(0));
+        assert_eq!(dram.get_u8(2 * PAGE_SIZE + 1), Ok(0xAA));
+        // zeroing untouched memory allocates nothing
+        dram.zero(0x10_0000, 16 * PAGE_SIZE + 3).unwrap();
+        assert_eq!(dram.resident(), 2 * PAGE_SIZE);
     }
 }
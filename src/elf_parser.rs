@@ -1,31 +1,58 @@
 #![allow(dead_code)]
-#![allow(unused_unsafe)]
 
 use std::rc::Rc;
 
-use crate::{error::EmulatorError, fast_transmute};
+use crate::error::EmulatorError;
 
-#[repr(u8)]
+// OS ABI with ABI version
 #[derive(Debug, Clone)]
 pub enum ABI {
-    SystemV(u8) = 0x00,
-    HpUx(u8) = 0x01,
-    NetBSD(u8) = 0x02,
-    Linux(u8) = 0x03,
-    GnuHurd(u8) = 0x04,
-    Solaris(u8) = 0x06,
-    AixMonterey(u8) = 0x07,
-    IRIX(u8) = 0x08,
-    FreeBSD(u8) = 0x09,
-    Tru64(u8) = 0x0A,
-    NovellModesto(u8) = 0x0B,
-    OpenBSD(u8) = 0x0C,
-    OpenVMS(u8) = 0x0D,
-    NonStopKernel(u8) = 0x0E,
-    AROS(u8) = 0x0F,
-    FenixOS(u8) = 0x10,
-    NuxiCloudAbi(u8) = 0x11,
-    StratusTechnologiesOpenVos(u8) = 0x12,
+    SystemV(u8),
+    HpUx(u8),
+    NetBSD(u8),
+    Linux(u8),
+    GnuHurd(u8),
+    Solaris(u8),
+    AixMonterey(u8),
+    IRIX(u8),
+    FreeBSD(u8),
+    Tru64(u8),
+    NovellModesto(u8),
+    OpenBSD(u8),
+    OpenVMS(u8),
+    NonStopKernel(u8),
+    AROS(u8),
+    FenixOS(u8),
+    NuxiCloudAbi(u8),
+    StratusTechnologiesOpenVos(u8),
+    // unknown OS ABI
+    Other(u8),
+}
+
+impl ABI {
+    fn new(abi: u8, version: u8) -> Self {
+        match abi {
+            0x00 => ABI::SystemV(version),
+            0x01 => ABI::HpUx(version),
+            0x02 => ABI::NetBSD(version),
+            0x03 => ABI::Linux(version),
+            0x04 => ABI::GnuHurd(version),
+            0x06 => ABI::Solaris(version),
+            0x07 => ABI::AixMonterey(version),
+            0x08 => ABI::IRIX(version),
+            0x09 => ABI::FreeBSD(version),
+            0x0A => ABI::Tru64(version),
+            0x0B => ABI::NovellModesto(version),
+            0x0C => ABI::OpenBSD(version),
+            0x0D => ABI::OpenVMS(version),
+            0x0E => ABI::NonStopKernel(version),
+            0x0F => ABI::AROS(version),
+            0x10 => ABI::FenixOS(version),
+            0x11 => ABI::NuxiCloudAbi(version),
+            0x12 => ABI::StratusTechnologiesOpenVos(version),
+            other => ABI::Other(other),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -40,20 +67,45 @@ pub enum Endian {
     Big,
 }
 
-#[repr(u16)]
 #[derive(Debug, Clone)]
 pub enum ObjType {
-    EtNone = 0x00,
-    EtRel = 0x01,
-    EtExec = 0x02,
-    EtDyn = 0x03,
-    EtCore = 0x04,
-    EtLoos = 0xFE00,
-    EtHios = 0xFEFF,
-    EtLoproc = 0xFF00,
-    EtHiproc = 0xFFFF,
+    EtNone,
+    EtRel,
+    EtExec,
+    EtDyn,
+    EtCore,
+    EtLoos,
+    EtHios,
+    EtLoproc,
+    EtHiproc,
+    Other(u16),
 }
 
+impl From<u16> for ObjType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x00 => ObjType::EtNone,
+            0x01 => ObjType::EtRel,
+            0x02 => ObjType::EtExec,
+            0x03 => ObjType::EtDyn,
+            0x04 => ObjType::EtCore,
+            0xFE00 => ObjType::EtLoos,
+            0xFEFF => ObjType::EtHios,
+            0xFF00 => ObjType::EtLoproc,
+            0xFFFF => ObjType::EtHiproc,
+            other => ObjType::Other(other),
+        }
+    }
+}
+
+// e_machine of RISC-V
+const EM_RISCV: u16 = 0xF3;
+
+// sizes of headers and table entries, ELF32 and ELF64
+const EHDR_SIZE: [usize; 2] = [52, 64];
+const PHDR_SIZE: [usize; 2] = [32, 56];
+const SHDR_SIZE: [usize; 2] = [40, 64];
+
 #[derive(Debug, Clone)]
 pub struct ELF {
     magic: u32,
@@ -72,71 +124,179 @@ pub struct ELF {
     section_header_names: u16,
 }
 
-pub fn elf_parser(data: &[u8]) -> ELF {
-    let mut bin_arc = BinArc::X32;
-    let mut endian = Endian::Little;
+impl ELF {
+    // register width the binary was compiled for
+    pub fn xlen(&self) -> u32 {
+        match self.bin_arc {
+            BinArc::X32 => 32,
+            BinArc::X64 => 64,
+        }
+    }
 
-    let t_magic = &data[0..4];
-    let magic = fast_transmute!(<0, u32>, t_magic);
-    let ident = data[4];
-    if ident == 2 {
-        bin_arc = BinArc::X64;
+    pub fn entry_point(&self) -> u64 {
+        self.entry_point
     }
 
-    let ident = data[5];
-    if ident == 2 {
-        endian = Endian::Big;
+    pub fn is_big_endian(&self) -> bool {
+        matches!(self.endian, Endian::Big)
     }
+}
+
+// bounds checked read of raw field bytes
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], EmulatorError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(EmulatorError::ElfTruncated(offset))
+}
 
-    let set_to_one = data[6];
-    if set_to_one != 1 {
-        panic!("that is supposed to be set to 1");
+// fields after e_ident are in byte order of the file
+impl Endian {
+    fn read_u16(&self, data: &[u8], offset: usize) -> Result<u16, EmulatorError> {
+        let bytes = bytes(data, offset)?;
+        Ok(match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        })
     }
 
-    let abi = fast_transmute!(<7, ABI @ u16>, data);
-    let _padding = &data[9..16];
+    fn read_u32(&self, data: &[u8], offset: usize) -> Result<u32, EmulatorError> {
+        let bytes = bytes(data, offset)?;
+        Ok(match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        })
+    }
 
-    let obj_type = fast_transmute!(<16, ObjType @ u16>, data);
+    fn read_u64(&self, data: &[u8], offset: usize) -> Result<u64, EmulatorError> {
+        let bytes = bytes(data, offset)?;
+        Ok(match self {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        })
+    }
+}
+
+// checks that table of entries fits in the file, returns its start
+fn table(
+    data: &[u8],
+    name: &str,
+    start: u64,
+    entry_size: usize,
+    entries: usize,
+) -> Result<usize, EmulatorError> {
+    // offset of empty table is meaningless
+    if entries == 0 {
+        return Ok(0);
+    }
+    let end = entry_size
+        .checked_mul(entries)
+        .and_then(|size| (start as usize).checked_add(size));
+    match end {
+        Some(end) if start <= data.len() as u64 && end <= data.len() => Ok(start as usize),
+        _ => Err(EmulatorError::ElfOutOfRange(format!(
+            "{} with {} entries of {} bytes at {:#x}",
+            name, entries, entry_size, start
+        ))),
+    }
+}
+
+pub fn elf_parser(data: &[u8]) -> Result<ELF, EmulatorError> {
+    let ident: [u8; 16] = bytes(data, 0)?;
+    let magic = u32::from_le_bytes(bytes(data, 0)?);
+    if ident[..4] != *b"\x7fELF" {
+        return Err(EmulatorError::ElfMagic(magic));
+    }
+
+    let bin_arc = match ident[4] {
+        1 => BinArc::X32,
+        2 => BinArc::X64,
+        other => return Err(EmulatorError::ElfClass(other)),
+    };
+
+    let endian = match ident[5] {
+        1 => Endian::Little,
+        2 => Endian::Big,
+        other => return Err(EmulatorError::ElfEncoding(other)),
+    };
+
+    let version = ident[6];
+    if version != 1 {
+        return Err(EmulatorError::ElfVersion(version as u32));
+    }
+
+    let abi = ABI::new(ident[7], ident[8]);
+    let _padding = &ident[9..16];
+
+    let class = if bin_arc == BinArc::X64 { 1 } else { 0 };
+    if data.len() < EHDR_SIZE[class] {
+        return Err(EmulatorError::ElfTruncated(data.len()));
+    }
+
+    let obj_type = ObjType::from(endian.read_u16(data, 16)?);
 
     // 0xF3 means RISC-V
-    let arc = fast_transmute!(<18, u16>, data);
-    if arc != 0xF3 && arc != 0x00 {
-        panic!("this ELF is not for RISC-V architecture: {:x}", arc)
+    let arc = endian.read_u16(data, 18)?;
+    if arc != EM_RISCV && arc != 0x00 {
+        return Err(EmulatorError::ElfMachine(arc));
     } else if arc == 0x00 {
         println!(
             "\x1b[93mWARNING\x1b[0m: no guarantee that this ELF is made for RISC-V architecture"
         )
     }
-    let _e_version = fast_transmute!(<20, u32>, data);
+    let e_version = endian.read_u32(data, 20)?;
+    if e_version != 1 {
+        return Err(EmulatorError::ElfVersion(e_version));
+    }
 
     let entry_point;
     let program_header_address;
     let section_header_address;
     let rp; //reference point
-    unsafe {
-        if bin_arc == BinArc::X64 {
-            entry_point = fast_transmute!(<24, u64>, data);
-            program_header_address = fast_transmute!(<32, u64>, data);
-            section_header_address = fast_transmute!(<40, u64>, data);
+    if bin_arc == BinArc::X64 {
+        entry_point = endian.read_u64(data, 24)?;
+        program_header_address = endian.read_u64(data, 32)?;
+        section_header_address = endian.read_u64(data, 40)?;
 
-            rp = 48;
-        } else {
-            entry_point = fast_transmute!(<24, u32>, data) as u64;
-            program_header_address = fast_transmute!(<28, u32>, data) as u64;
-            section_header_address = fast_transmute!(<32, u32>, data) as u64;
+        rp = 48;
+    } else {
+        entry_point = endian.read_u32(data, 24)? as u64;
+        program_header_address = endian.read_u32(data, 28)? as u64;
+        section_header_address = endian.read_u32(data, 32)? as u64;
 
-            rp = 36;
-        }
+        rp = 36;
+    }
+
+    let _flags = endian.read_u32(data, rp)?;
+    let elf_header_size = endian.read_u16(data, rp + 4)?;
+    let program_header_size = endian.read_u16(data, rp + 6)?;
+    let program_header_entries = endian.read_u16(data, rp + 8)?;
+    let section_header_size = endian.read_u16(data, rp + 10)?;
+    let section_header_entries = endian.read_u16(data, rp + 12)?;
+    let section_header_names = endian.read_u16(data, rp + 14)?;
+
+    // entries can't be smaller than the fields read from them
+    if program_header_entries != 0 && (program_header_size as usize) < PHDR_SIZE[class] {
+        return Err(EmulatorError::ElfOutOfRange(format!(
+            "program header size {}",
+            program_header_size
+        )));
+    }
+    if section_header_entries != 0 && (section_header_size as usize) < SHDR_SIZE[class] {
+        return Err(EmulatorError::ElfOutOfRange(format!(
+            "section header size {}",
+            section_header_size
+        )));
+    }
+    if section_header_entries != 0 && section_header_names >= section_header_entries {
+        return Err(EmulatorError::ElfOutOfRange(format!(
+            "section name table index {}",
+            section_header_names
+        )));
     }
 
-    let _unknown = fast_transmute!(<rp, u32>, data);
-    let elf_header_size = fast_transmute!(<rp+4, u16>, data);
-    let program_header_size = fast_transmute!(<rp+6, u16>, data);
-    let program_header_entries = fast_transmute!(<rp+8, u16>, data);
-    let section_header_size = fast_transmute!(<rp+10, u16>, data);
-    let section_header_entries = fast_transmute!(<rp+12, u16>, data);
-    let section_header_names = fast_transmute!(<rp+14, u16>, data);
-    ELF {
+    Ok(ELF {
         magic,
         bin_arc,
         endian,
@@ -151,28 +311,48 @@ pub fn elf_parser(data: &[u8]) -> ELF {
         section_header_size,
         section_header_entries,
         section_header_names,
-    }
+    })
 }
 
-#[repr(u32)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProgramHeaderType {
-    PtNull = 0x00000000,
-    PtLoad = 0x00000001,
-    PtDynamic = 0x00000002,
-    PtInterp = 0x00000003,
-    PtNote = 0x00000004,
-    PtShlib = 0x00000005,
-    PtPhdr = 0x00000006,
-    PtTls = 0x00000007,
-    PtLoos = 0x60000000,
-    PtHios = 0x6FFFFFFF,
-    PtLoproc = 0x70000000,
-    PtHiproc = 0x7FFFFFFF,
+    PtNull,
+    PtLoad,
+    PtDynamic,
+    PtInterp,
+    PtNote,
+    PtShlib,
+    PtPhdr,
+    PtTls,
+    PtLoos,
+    PtHios,
+    PtLoproc,
+    PtHiproc,
+    Other(u32),
+}
+
+impl From<u32> for ProgramHeaderType {
+    fn from(value: u32) -> Self {
+        match value {
+            0x00000000 => ProgramHeaderType::PtNull,
+            0x00000001 => ProgramHeaderType::PtLoad,
+            0x00000002 => ProgramHeaderType::PtDynamic,
+            0x00000003 => ProgramHeaderType::PtInterp,
+            0x00000004 => ProgramHeaderType::PtNote,
+            0x00000005 => ProgramHeaderType::PtShlib,
+            0x00000006 => ProgramHeaderType::PtPhdr,
+            0x00000007 => ProgramHeaderType::PtTls,
+            0x60000000 => ProgramHeaderType::PtLoos,
+            0x6FFFFFFF => ProgramHeaderType::PtHios,
+            0x70000000 => ProgramHeaderType::PtLoproc,
+            0x7FFFFFFF => ProgramHeaderType::PtHiproc,
+            other => ProgramHeaderType::Other(other),
+        }
+    }
 }
 
 #[repr(u32)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProgramHeaderFlags {
     PfX = 0x1,
     PfW = 0x2,
@@ -193,30 +373,37 @@ pub struct ProgramHeader {
     pub alignment: u64,
 }
 
-pub fn program_header_parser(data: &[u8], elf: &ELF) -> Vec<ProgramHeader> {
-    let start = elf.program_header_address as usize;
+pub fn program_header_parser(data: &[u8], elf: &ELF) -> Result<Vec<ProgramHeader>, EmulatorError> {
     let bin_arc = &elf.bin_arc;
+    let endian = &elf.endian;
     let size = elf.program_header_size as usize;
     let entries = elf.program_header_entries as usize;
+    let start = table(
+        data,
+        "program header table",
+        elf.program_header_address,
+        size,
+        entries,
+    )?;
 
     let mut v = vec![];
 
     for i in 0..entries {
         let of = (i * size) + start;
-        let p_type = fast_transmute!(<of, ProgramHeaderType @ u32>, data);
+        let p_type = ProgramHeaderType::from(endian.read_u32(data, of)?);
 
         let p_flags = {
             let mut flags = vec![];
             let temp = if *bin_arc == BinArc::X64 {
-                fast_transmute!(<of+4, u32>, data)
+                endian.read_u32(data, of + 4)?
             } else {
-                fast_transmute!(<of+24, u32>, data)
+                endian.read_u32(data, of + 24)?
             };
             if temp & 0x1 == 0x1 {
                 flags.push(ProgramHeaderFlags::PfX);
             }
             if temp & 0x2 == 0x2 {
-                flags.push(ProgramHeaderFlags::PfX);
+                flags.push(ProgramHeaderFlags::PfW);
             }
             if temp & 0x4 == 0x4 {
                 flags.push(ProgramHeaderFlags::PfR);
@@ -231,23 +418,21 @@ pub fn program_header_parser(data: &[u8], elf: &ELF) -> Vec<ProgramHeader> {
         let mem_size;
         let alignment;
 
-        unsafe {
-            if *bin_arc == BinArc::X64 {
-                segment_offset = fast_transmute!(<of+8, u64>, data);
-                virtual_address = fast_transmute!(<of+16, u64>, data);
-                physical_address = fast_transmute!(<of+24, u64>, data);
-                segment_size = fast_transmute!(<of+32, u64>, data);
-                mem_size = fast_transmute!(<of+40, u64>, data);
-                alignment = fast_transmute!(<of+48, u64>, data);
-            } else {
-                segment_offset = fast_transmute!(<of+4, u32>, data) as u64;
-                virtual_address = fast_transmute!(<of+8, u32>, data) as u64;
-                physical_address = fast_transmute!(<of+12, u32>, data) as u64;
-                segment_size = fast_transmute!(<of+16, u32>, data) as u64;
-                mem_size = fast_transmute!(<of+20, u32>, data) as u64;
-                alignment = fast_transmute!(<of+28, u32>, data) as u64;
-            }
-        };
+        if *bin_arc == BinArc::X64 {
+            segment_offset = endian.read_u64(data, of + 8)?;
+            virtual_address = endian.read_u64(data, of + 16)?;
+            physical_address = endian.read_u64(data, of + 24)?;
+            segment_size = endian.read_u64(data, of + 32)?;
+            mem_size = endian.read_u64(data, of + 40)?;
+            alignment = endian.read_u64(data, of + 48)?;
+        } else {
+            segment_offset = endian.read_u32(data, of + 4)? as u64;
+            virtual_address = endian.read_u32(data, of + 8)? as u64;
+            physical_address = endian.read_u32(data, of + 12)? as u64;
+            segment_size = endian.read_u32(data, of + 16)? as u64;
+            mem_size = endian.read_u32(data, of + 20)? as u64;
+            alignment = endian.read_u32(data, of + 28)? as u64;
+        }
 
         v.push(ProgramHeader {
             p_type,
@@ -260,31 +445,58 @@ pub fn program_header_parser(data: &[u8], elf: &ELF) -> Vec<ProgramHeader> {
             alignment,
         });
     }
-    v
+    Ok(v)
 }
 
-#[repr(u32)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SectionHeaderType {
-    ShtNull = 0x0,
-    ShtProgbits = 0x1,
-    ShtSymtab = 0x2,
-    ShtStrtab = 0x3,
-    ShtRela = 0x4,
-    ShtHash = 0x5,
-    ShtDynamic = 0x6,
-    ShtNote = 0x7,
-    ShtNobits = 0x8,
-    ShtRel = 0x9,
-    ShtShlib = 0x0A,
-    ShtDynsym = 0x0B,
-    ShtInitArray = 0x0E,
-    ShtFiniArray = 0x0F,
-    ShtPreinitArray = 0x10,
-    ShtGroup = 0x11,
-    ShtSymtabShndx = 0x12,
-    ShtNum = 0x13,
-    ShtLoos = 0x60000000,
+    ShtNull,
+    ShtProgbits,
+    ShtSymtab,
+    ShtStrtab,
+    ShtRela,
+    ShtHash,
+    ShtDynamic,
+    ShtNote,
+    ShtNobits,
+    ShtRel,
+    ShtShlib,
+    ShtDynsym,
+    ShtInitArray,
+    ShtFiniArray,
+    ShtPreinitArray,
+    ShtGroup,
+    ShtSymtabShndx,
+    ShtNum,
+    ShtLoos,
+    Other(u32),
+}
+
+impl From<u32> for SectionHeaderType {
+    fn from(value: u32) -> Self {
+        match value {
+            0x0 => SectionHeaderType::ShtNull,
+            0x1 => SectionHeaderType::ShtProgbits,
+            0x2 => SectionHeaderType::ShtSymtab,
+            0x3 => SectionHeaderType::ShtStrtab,
+            0x4 => SectionHeaderType::ShtRela,
+            0x5 => SectionHeaderType::ShtHash,
+            0x6 => SectionHeaderType::ShtDynamic,
+            0x7 => SectionHeaderType::ShtNote,
+            0x8 => SectionHeaderType::ShtNobits,
+            0x9 => SectionHeaderType::ShtRel,
+            0x0A => SectionHeaderType::ShtShlib,
+            0x0B => SectionHeaderType::ShtDynsym,
+            0x0E => SectionHeaderType::ShtInitArray,
+            0x0F => SectionHeaderType::ShtFiniArray,
+            0x10 => SectionHeaderType::ShtPreinitArray,
+            0x11 => SectionHeaderType::ShtGroup,
+            0x12 => SectionHeaderType::ShtSymtabShndx,
+            0x13 => SectionHeaderType::ShtNum,
+            0x60000000 => SectionHeaderType::ShtLoos,
+            other => SectionHeaderType::Other(other),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -302,6 +514,23 @@ pub struct SectionHeader {
     pub entry_size: u64,
 }
 
+impl SectionHeader {
+    // contents of the section in the file, empty for sections without any
+    pub fn contents<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], EmulatorError> {
+        if self.section_type == SectionHeaderType::ShtNobits {
+            return Ok(&[]);
+        }
+        self.section_offset
+            .checked_add(self.section_size)
+            .filter(|end| *end <= data.len() as u64)
+            .map(|end| &data[self.section_offset as usize..end as usize])
+            .ok_or(EmulatorError::ElfOutOfRange(format!(
+                "section of {:#x} bytes at {:#x}",
+                self.section_size, self.section_offset
+            )))
+    }
+}
+
 #[derive(Debug)]
 pub struct SectionHeadersList {
     pub headers: Vec<SectionHeader>,
@@ -312,21 +541,35 @@ impl SectionHeadersList {
     pub fn len(&self) -> usize {
         self.headers.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
 }
 
-pub fn raw_section_header_parser(data: &[u8], elf: &ELF) -> SectionHeadersList {
-    let start = elf.section_header_address as usize;
+pub fn raw_section_header_parser(
+    data: &[u8],
+    elf: &ELF,
+) -> Result<SectionHeadersList, EmulatorError> {
     let bin_arc = &elf.bin_arc;
+    let endian = &elf.endian;
     let size = elf.section_header_size as usize;
     let entries = elf.section_header_entries as usize;
+    let start = table(
+        data,
+        "section header table",
+        elf.section_header_address,
+        size,
+        entries,
+    )?;
 
     let mut v = vec![];
 
     for i in 0..entries {
         let of = (i * size) + start;
 
-        let name = fast_transmute!(<of, u32>, data);
-        let section_type = fast_transmute!(<of + 4, SectionHeaderType @ u32>, data);
+        let name = endian.read_u32(data, of)?;
+        let section_type = SectionHeaderType::from(endian.read_u32(data, of + 4)?);
 
         let flags;
         let section_address;
@@ -337,26 +580,24 @@ pub fn raw_section_header_parser(data: &[u8], elf: &ELF) -> SectionHeadersList {
         let alignment;
         let entry_size;
 
-        unsafe {
-            if *bin_arc == BinArc::X64 {
-                flags = fast_transmute!(<of+8, u64>, data);
-                section_address = fast_transmute!(<of+16, u64>, data);
-                section_offset = fast_transmute!(<of+24, u64>, data);
-                section_size = fast_transmute!(<of+32, u64>, data);
-                link = fast_transmute!(<of+40, u32>, data);
-                info = fast_transmute!(<of+44, u32>, data);
-                alignment = fast_transmute!(<of+48, u64>, data);
-                entry_size = fast_transmute!(<of+56, u64>, data);
-            } else {
-                flags = fast_transmute!(<of+8, u32>, data) as u64;
-                section_address = fast_transmute!(<of+12, u32>, data) as u64;
-                section_offset = fast_transmute!(<of+16, u32>, data) as u64;
-                section_size = fast_transmute!(<of+20, u32>, data) as u64;
-                link = fast_transmute!(<of+24, u32>, data);
-                info = fast_transmute!(<of+28, u32>, data);
-                alignment = fast_transmute!(<of+32, u32>, data) as u64;
-                entry_size = fast_transmute!(<of+36, u32>, data) as u64;
-            }
+        if *bin_arc == BinArc::X64 {
+            flags = endian.read_u64(data, of + 8)?;
+            section_address = endian.read_u64(data, of + 16)?;
+            section_offset = endian.read_u64(data, of + 24)?;
+            section_size = endian.read_u64(data, of + 32)?;
+            link = endian.read_u32(data, of + 40)?;
+            info = endian.read_u32(data, of + 44)?;
+            alignment = endian.read_u64(data, of + 48)?;
+            entry_size = endian.read_u64(data, of + 56)?;
+        } else {
+            flags = endian.read_u32(data, of + 8)? as u64;
+            section_address = endian.read_u32(data, of + 12)? as u64;
+            section_offset = endian.read_u32(data, of + 16)? as u64;
+            section_size = endian.read_u32(data, of + 20)? as u64;
+            link = endian.read_u32(data, of + 24)?;
+            info = endian.read_u32(data, of + 28)?;
+            alignment = endian.read_u32(data, of + 32)? as u64;
+            entry_size = endian.read_u32(data, of + 36)? as u64;
         }
 
         v.push(SectionHeader {
@@ -373,31 +614,39 @@ pub fn raw_section_header_parser(data: &[u8], elf: &ELF) -> SectionHeadersList {
             name_str: None,
         })
     }
-    SectionHeadersList {
+    Ok(SectionHeadersList {
         headers: v,
         list: vec![],
-    }
+    })
+}
+
+// null terminated string at offset in string table
+fn string_at(strings: &[u8], offset: u32) -> Result<Rc<str>, EmulatorError> {
+    let bytes = strings
+        .get(offset as usize..)
+        .and_then(|s| s.split(|b| *b == b'\0').next())
+        .ok_or(EmulatorError::ElfOutOfRange(format!(
+            "string at {:#x}",
+            offset
+        )))?;
+    Ok(String::from_utf8(bytes.to_vec())?.as_str().into())
 }
 
 impl SectionHeadersList {
-    pub fn fill_names(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+    // names are read from the section name string table given by e_shstrndx
+    pub fn fill_names(&mut self, data: &[u8], elf: &ELF) -> Result<(), EmulatorError> {
+        if self.headers.is_empty() {
+            return Ok(());
+        }
         let strtab = self
             .headers
-            .iter()
-            .find(|x| x.section_type == SectionHeaderType::ShtStrtab)
-            .ok_or(EmulatorError::StrTabError)?
-            .clone();
+            .get(elf.section_header_names as usize)
+            .filter(|x| x.section_type == SectionHeaderType::ShtStrtab)
+            .ok_or(EmulatorError::StrTabError)?;
+        let strings = strtab.contents(data)?;
 
         for e in &mut self.headers {
-            let name_start = strtab.section_offset + e.name as u64 + strtab.section_size;
-            let mut name_vec = vec![];
-            for i in name_start.. {
-                if data[i as usize] == b'\0' {
-                    break;
-                }
-                name_vec.push(data[i as usize])
-            }
-            let name: Rc<str> = String::from_utf8(name_vec)?.as_str().into();
+            let name = string_at(strings, e.name)?;
             e.name_str = Some(name.clone());
             self.list.push(name.clone())
         }
@@ -414,20 +663,16 @@ impl SectionHeadersList {
                     false
                 }
             })
-            .clone()
             .ok_or(EmulatorError::NoTextSection)
     }
     pub fn find_data_section(&self) -> Option<&SectionHeader> {
-        self.headers
-            .iter()
-            .find(|x| {
-                if let Some(name) = &x.name_str {
-                    *name == ".data".into()
-                } else {
-                    false
-                }
-            })
-            .clone()
+        self.headers.iter().find(|x| {
+            if let Some(name) = &x.name_str {
+                *name == ".data".into()
+            } else {
+                false
+            }
+        })
     }
 }
 
@@ -454,8 +699,369 @@ pub fn extract_prog_bits<'a>(
         return Err(EmulatorError::WrongHeaderProvieded);
     }
 
-    let lower = text.section_offset as usize;
-    let upper = (text.section_offset + text.section_size) as usize;
+    text.contents(data)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolType {
+    SttNotype,
+    SttObject,
+    SttFunc,
+    SttSection,
+    SttFile,
+    SttCommon,
+    SttTls,
+    Other(u8),
+}
+
+impl From<u8> for SymbolType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolType::SttNotype,
+            1 => SymbolType::SttObject,
+            2 => SymbolType::SttFunc,
+            3 => SymbolType::SttSection,
+            4 => SymbolType::SttFile,
+            5 => SymbolType::SttCommon,
+            6 => SymbolType::SttTls,
+            other => SymbolType::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolBinding {
+    StbLocal,
+    StbGlobal,
+    StbWeak,
+    Other(u8),
+}
+
+impl From<u8> for SymbolBinding {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SymbolBinding::StbLocal,
+            1 => SymbolBinding::StbGlobal,
+            2 => SymbolBinding::StbWeak,
+            other => SymbolBinding::Other(other),
+        }
+    }
+}
 
-    Ok(&data[lower..upper])
+// index of section the symbol is defined in, 0 is undefined and values from 0xff00 are reserved
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_LORESERVE: u16 = 0xFF00;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: Rc<str>,
+    pub value: u64,
+    pub size: u64,
+    pub sym_type: SymbolType,
+    pub binding: SymbolBinding,
+    pub section: u16,
+}
+
+// sizes of symbol table entries, ELF32 and ELF64
+const SYM_SIZE: [usize; 2] = [16, 24];
+
+// reads symbols of every .symtab and .dynsym, names come from string table given by sh_link
+pub fn symbol_parser(
+    data: &[u8],
+    elf: &ELF,
+    sections: &SectionHeadersList,
+) -> Result<Vec<Symbol>, EmulatorError> {
+    let bin_arc = &elf.bin_arc;
+    let endian = &elf.endian;
+    let class = if *bin_arc == BinArc::X64 { 1 } else { 0 };
+
+    let mut v = vec![];
+
+    for table in sections.headers.iter().filter(|x| {
+        x.section_type == SectionHeaderType::ShtSymtab
+            || x.section_type == SectionHeaderType::ShtDynsym
+    }) {
+        let size = match table.entry_size as usize {
+            0 => SYM_SIZE[class],
+            size if size >= SYM_SIZE[class] => size,
+            size => {
+                return Err(EmulatorError::ElfOutOfRange(format!(
+                    "symbol size {}",
+                    size
+                )))
+            }
+        };
+        let strtab = sections
+            .headers
+            .get(table.link as usize)
+            .filter(|x| x.section_type == SectionHeaderType::ShtStrtab)
+            .ok_or(EmulatorError::StrTabError)?;
+        let strings = strtab.contents(data)?;
+        let entries = table.contents(data)?;
+
+        // first entry is always the null symbol
+        for of in (size..entries.len()).step_by(size) {
+            let name = endian.read_u32(entries, of)?;
+            let info;
+            let section;
+            let value;
+            let sym_size;
+
+            if *bin_arc == BinArc::X64 {
+                info = bytes::<1>(entries, of + 4)?[0];
+                section = endian.read_u16(entries, of + 6)?;
+                value = endian.read_u64(entries, of + 8)?;
+                sym_size = endian.read_u64(entries, of + 16)?;
+            } else {
+                value = endian.read_u32(entries, of + 4)? as u64;
+                sym_size = endian.read_u32(entries, of + 8)? as u64;
+                info = bytes::<1>(entries, of + 12)?[0];
+                section = endian.read_u16(entries, of + 14)?;
+            }
+
+            v.push(Symbol {
+                name: string_at(strings, name)?,
+                value,
+                size: sym_size,
+                sym_type: SymbolType::from(info & 0xF),
+                binding: SymbolBinding::from(info >> 4),
+                section,
+            });
+        }
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dram::Dram, loader::load_segments, memmap::MemoryMap};
+
+    const ENTRY: u64 = 0x8000_0000;
+    // offset of the only program header and of the segment contents
+    const PHDR: usize = 64;
+    const CODE: usize = PHDR + 56;
+
+    // RV64 executable with one PT_LOAD segment of 8 bytes at ENTRY and no sections
+    fn elf64() -> Vec<u8> {
+        let mut data = vec![0; CODE + 8];
+        data[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        let mut put =
+            |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(16, &2u16.to_le_bytes());
+        put(18, &EM_RISCV.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(24, &ENTRY.to_le_bytes());
+        put(32, &(PHDR as u64).to_le_bytes());
+        put(52, &64u16.to_le_bytes());
+        put(54, &56u16.to_le_bytes());
+        put(56, &1u16.to_le_bytes());
+        put(58, &64u16.to_le_bytes());
+
+        put(PHDR, &1u32.to_le_bytes());
+        put(PHDR + 4, &0x5u32.to_le_bytes());
+        put(PHDR + 8, &(CODE as u64).to_le_bytes());
+        put(PHDR + 16, &ENTRY.to_le_bytes());
+        put(PHDR + 24, &ENTRY.to_le_bytes());
+        put(PHDR + 32, &8u64.to_le_bytes());
+        put(PHDR + 40, &8u64.to_le_bytes());
+        put(PHDR + 48, &0x1000u64.to_le_bytes());
+        // addi a0, zero, 1 ; ret
+        put(CODE, &[0x13, 0x05, 0x10, 0x00, 0x67, 0x80, 0x00, 0x00]);
+        data
+    }
+
+    fn load(data: &[u8]) -> Result<Dram, EmulatorError> {
+        let elf = elf_parser(data)?;
+        let headers = program_header_parser(data, &elf)?;
+        let map = MemoryMap::default();
+        let mut dram = Dram::new_dram(map.ram.size as usize);
+        load_segments(data, &headers, &map, &mut dram, None)?;
+        Ok(dram)
+    }
+
+    #[test]
+    fn parses_header_and_segment() {
+        let data = elf64();
+        let elf = elf_parser(&data).unwrap();
+        assert_eq!(elf.xlen(), 64);
+        assert_eq!(elf.entry_point(), ENTRY);
+        assert!(!elf.is_big_endian());
+
+        let headers = program_header_parser(&data, &elf).unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].p_type, ProgramHeaderType::PtLoad);
+        assert_eq!(
+            headers[0].p_flags,
+            [ProgramHeaderFlags::PfX, ProgramHeaderFlags::PfR]
+        );
+        assert_eq!(headers[0].segment_offset, CODE as u64);
+        assert_eq!(headers[0].mem_size, 8);
+
+        let mut dram = load(&data).unwrap();
+        assert_eq!(dram.get_u32(0), Ok(0x0010_0513));
+    }
+
+    #[test]
+    fn truncated_ident() {
+        let data = elf64();
+        let err = elf_parser(&data[..10]).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfTruncated(0)), "{:?}", err);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = elf64();
+        let err = elf_parser(&data[..40]).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfTruncated(40)), "{:?}", err);
+    }
+
+    #[test]
+    fn truncated_program_headers() {
+        let data = elf64();
+        let elf = elf_parser(&data).unwrap();
+        let err = program_header_parser(&data[..PHDR + 20], &elf).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfOutOfRange(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = elf64();
+        data[1] = b'X';
+        let err = elf_parser(&data).unwrap_err();
+        assert!(
+            matches!(err, EmulatorError::ElfMagic(0x464C_587F)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn bad_class_and_encoding() {
+        let mut data = elf64();
+        data[4] = 3;
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfClass(3)), "{:?}", err);
+
+        let mut data = elf64();
+        data[5] = 0;
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfEncoding(0)), "{:?}", err);
+    }
+
+    #[test]
+    fn wrong_machine() {
+        let mut data = elf64();
+        // x86-64
+        data[18..20].copy_from_slice(&0x3Eu16.to_le_bytes());
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfMachine(0x3E)), "{:?}", err);
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut data = elf64();
+        data[20..24].copy_from_slice(&2u32.to_le_bytes());
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfVersion(2)), "{:?}", err);
+    }
+
+    #[test]
+    fn program_headers_out_of_range() {
+        let mut data = elf64();
+        data[32..40].copy_from_slice(&0x1000u64.to_le_bytes());
+        let elf = elf_parser(&data).unwrap();
+        let err = program_header_parser(&data, &elf).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfOutOfRange(_)), "{:?}", err);
+
+        // offset wrapping around when entries are added to it
+        data[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        let elf = elf_parser(&data).unwrap();
+        let err = program_header_parser(&data, &elf).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfOutOfRange(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn program_header_too_small() {
+        let mut data = elf64();
+        data[54..56].copy_from_slice(&32u16.to_le_bytes());
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfOutOfRange(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn section_name_index_out_of_range() {
+        let mut data = elf64();
+        // two sections with names in the third
+        data[60..62].copy_from_slice(&2u16.to_le_bytes());
+        data[62..64].copy_from_slice(&2u16.to_le_bytes());
+        let err = elf_parser(&data).unwrap_err();
+        assert!(matches!(err, EmulatorError::ElfOutOfRange(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn segment_contents_out_of_range() {
+        let mut data = elf64();
+        data[PHDR + 8..PHDR + 16].copy_from_slice(&0x1000u64.to_le_bytes());
+        assert!(matches!(load(&data), Err(EmulatorError::BadSegment(_))));
+    }
+
+    #[test]
+    fn segment_memory_size_outside_of_ram() {
+        let mut data = elf64();
+        // p_memsz far past the end of RAM, nothing is allocated for it
+        data[PHDR + 40..PHDR + 48].copy_from_slice(&0x1_0000_0000_0000u64.to_le_bytes());
+        assert!(matches!(load(&data), Err(EmulatorError::BadSegment(_))));
+
+        // end of the segment overflowing u64
+        data[PHDR + 40..PHDR + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(load(&data), Err(EmulatorError::BadSegment(_))));
+    }
+
+    #[test]
+    fn big_endian_headers() {
+        let mut data = elf64();
+        data[5] = 2;
+        // offset and width of every field elf64 sets
+        let fields = [
+            (16, 2),
+            (18, 2),
+            (20, 4),
+            (24, 8),
+            (32, 8),
+            (52, 2),
+            (54, 2),
+            (56, 2),
+            (58, 2),
+            (PHDR, 4),
+            (PHDR + 4, 4),
+            (PHDR + 8, 8),
+            (PHDR + 16, 8),
+            (PHDR + 24, 8),
+            (PHDR + 32, 8),
+            (PHDR + 40, 8),
+            (PHDR + 48, 8),
+        ];
+        for (offset, width) in fields {
+            data[offset..offset + width].reverse();
+        }
+        let elf = elf_parser(&data).unwrap();
+        assert!(elf.is_big_endian());
+        assert_eq!(elf.xlen(), 64);
+        assert_eq!(elf.entry_point(), ENTRY);
+
+        let headers = program_header_parser(&data, &elf).unwrap();
+        assert_eq!(headers.len(), 1);
+        let header = &headers[0];
+        assert_eq!(header.p_type, ProgramHeaderType::PtLoad);
+        assert_eq!(
+            header.p_flags,
+            [ProgramHeaderFlags::PfX, ProgramHeaderFlags::PfR]
+        );
+        assert_eq!(header.segment_offset, CODE as u64);
+        assert_eq!(header.virtual_address, ENTRY);
+        assert_eq!(header.segment_size, 8);
+        assert_eq!(header.mem_size, 8);
+        assert_eq!(header.alignment, 0x1000);
+    }
 }
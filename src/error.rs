@@ -7,6 +7,27 @@ pub enum EmulatorError {
     StrTabError,
     WrongHeaderProvieded,
     NoTextSection,
+    IsaString(String),
+    TlbSize(String),
+    PmpEntries(String),
+    ClintArgument(String),
+    PlicArgument(String),
+    UartArgument(String),
+    BusRegion(String),
+    MemoryMap(String),
+    BadSegment(String),
+    // ELF file ends before the field at this offset
+    ElfTruncated(usize),
+    ElfMagic(u32),
+    ElfClass(u8),
+    ElfEncoding(u8),
+    ElfVersion(u32),
+    ElfMachine(u16),
+    ElfOutOfRange(String),
+    // big endian data mode (mstatus.MBE/SBE/UBE) is not implemented
+    BigEndianElf,
+    // --break names neither symbol nor hex address
+    UnknownSymbol(String),
 }
 
 impl From<std::io::Error> for EmulatorError {
@@ -17,6 +38,6 @@ impl From<std::io::Error> for EmulatorError {
 
 impl From<FromUtf8Error> for EmulatorError {
     fn from(value: FromUtf8Error) -> Self {
-        return EmulatorError::FromUtf8(value);
+        EmulatorError::FromUtf8(value)
     }
 }
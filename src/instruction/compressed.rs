@@ -0,0 +1,516 @@
+// C extension
+// every compressed instruction is expanded into its 32 bit equivalent, which is then executed
+// by the regular decoder with instruction length of 2
+use crate::{trap::Exception, xlen};
+
+// registers encoded on 3 bits (rd', rs1', rs2') start at x8
+#[inline(always)]
+fn creg(bits: u32) -> u32 {
+    8 + (bits & 0x7)
+}
+
+#[inline(always)]
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+#[inline(always)]
+fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm as u32) & 0xFFF) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+#[inline(always)]
+fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5) & 0x7F) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1F) << 7 | opcode
+}
+
+#[inline(always)]
+fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12) & 0x1) << 31
+        | ((imm >> 5) & 0x3F) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | ((imm >> 1) & 0xF) << 8
+        | ((imm >> 11) & 0x1) << 7
+        | opcode
+}
+
+#[inline(always)]
+fn u_type(imm: i32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xFFFFF000) | rd << 7 | opcode
+}
+
+#[inline(always)]
+fn j_type(imm: i32, rd: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 20) & 0x1) << 31
+        | ((imm >> 1) & 0x3FF) << 21
+        | ((imm >> 11) & 0x1) << 20
+        | ((imm >> 12) & 0xFF) << 12
+        | rd << 7
+        | opcode
+}
+
+// sign extends value of `bits` width
+#[inline(always)]
+fn sext(val: u32, bits: u32) -> i32 {
+    ((val << (32 - bits)) as i32) >> (32 - bits)
+}
+
+// 6 bit immediate of c.addi, c.li, c.andi, ...
+#[inline(always)]
+fn ci_imm(op: u32) -> i32 {
+    sext(((op >> 7) & 0x20) | ((op >> 2) & 0x1F), 6)
+}
+
+// offset of c.j and c.jal
+#[inline(always)]
+fn cj_imm(op: u32) -> i32 {
+    sext(
+        ((op >> 1) & 0x800)
+            | ((op >> 7) & 0x10)
+            | ((op >> 1) & 0x300)
+            | ((op << 2) & 0x400)
+            | ((op >> 1) & 0x40)
+            | ((op << 1) & 0x80)
+            | ((op >> 2) & 0xE)
+            | ((op << 3) & 0x20),
+        12,
+    )
+}
+
+// offsets of c.ld/c.sd/c.fld/c.fsd
+#[inline(always)]
+fn cl_d_offset(op: u32) -> i32 {
+    (((op >> 7) & 0x38) | ((op << 1) & 0xC0)) as i32
+}
+
+// offsets of c.lw/c.sw
+#[inline(always)]
+fn cl_w_offset(op: u32) -> i32 {
+    (((op >> 7) & 0x38) | ((op >> 4) & 0x4) | ((op << 1) & 0x40)) as i32
+}
+
+#[inline(always)]
+fn illegal(op: u32) -> Exception {
+    Exception::IllegalInstruction(op)
+}
+
+pub fn expand_compressed(op: u16) -> Result<u32, Exception> {
+    let op = op as u32;
+    let funct3 = op >> 13 & 0x7;
+    let rv32 = xlen!() == 32;
+    let expanded = match (op & 0x3, funct3) {
+        // quadrant 0
+        // C.addi4spn
+        (0b00, 0b000) => {
+            let imm =
+                ((op >> 7) & 0x30) | ((op >> 1) & 0x3C0) | ((op >> 4) & 0x4) | ((op >> 2) & 0x8);
+            if imm == 0 {
+                return Err(illegal(op));
+            }
+            i_type(imm as i32, 2, 0b000, creg(op >> 2), 0b0010011)
+        }
+        // C.fld
+        (0b00, 0b001) => i_type(
+            cl_d_offset(op),
+            creg(op >> 7),
+            0b011,
+            creg(op >> 2),
+            0b0000111,
+        ),
+        // C.lw
+        (0b00, 0b010) => i_type(
+            cl_w_offset(op),
+            creg(op >> 7),
+            0b010,
+            creg(op >> 2),
+            0b0000011,
+        ),
+        // C.flw RV32
+        (0b00, 0b011) if rv32 => i_type(
+            cl_w_offset(op),
+            creg(op >> 7),
+            0b010,
+            creg(op >> 2),
+            0b0000111,
+        ),
+        // C.ld
+        (0b00, 0b011) => i_type(
+            cl_d_offset(op),
+            creg(op >> 7),
+            0b011,
+            creg(op >> 2),
+            0b0000011,
+        ),
+        // C.fsd
+        (0b00, 0b101) => s_type(
+            cl_d_offset(op),
+            creg(op >> 2),
+            creg(op >> 7),
+            0b011,
+            0b0100111,
+        ),
+        // C.sw
+        (0b00, 0b110) => s_type(
+            cl_w_offset(op),
+            creg(op >> 2),
+            creg(op >> 7),
+            0b010,
+            0b0100011,
+        ),
+        // C.fsw RV32
+        (0b00, 0b111) if rv32 => s_type(
+            cl_w_offset(op),
+            creg(op >> 2),
+            creg(op >> 7),
+            0b010,
+            0b0100111,
+        ),
+        // C.sd
+        (0b00, 0b111) => s_type(
+            cl_d_offset(op),
+            creg(op >> 2),
+            creg(op >> 7),
+            0b011,
+            0b0100011,
+        ),
+
+        // quadrant 1
+        // C.addi, C.nop
+        (0b01, 0b000) => {
+            let rd = op >> 7 & 0x1F;
+            i_type(ci_imm(op), rd, 0b000, rd, 0b0010011)
+        }
+        // C.jal RV32, same offset encoding as C.j
+        (0b01, 0b001) if rv32 => j_type(cj_imm(op), 1, 0b1101111),
+        // C.addiw
+        (0b01, 0b001) => {
+            let rd = op >> 7 & 0x1F;
+            if rd == 0 {
+                return Err(illegal(op));
+            }
+            i_type(ci_imm(op), rd, 0b000, rd, 0b0011011)
+        }
+        // C.li
+        (0b01, 0b010) => i_type(ci_imm(op), 0, 0b000, op >> 7 & 0x1F, 0b0010011),
+        // C.addi16sp, C.lui
+        (0b01, 0b011) => {
+            let rd = op >> 7 & 0x1F;
+            if rd == 2 {
+                let imm = sext(
+                    ((op >> 3) & 0x200)
+                        | ((op >> 2) & 0x10)
+                        | ((op << 1) & 0x40)
+                        | ((op << 4) & 0x180)
+                        | ((op << 3) & 0x20),
+                    10,
+                );
+                if imm == 0 {
+                    return Err(illegal(op));
+                }
+                i_type(imm, 2, 0b000, 2, 0b0010011)
+            } else {
+                let imm = ci_imm(op) << 12;
+                if imm == 0 {
+                    return Err(illegal(op));
+                }
+                u_type(imm, rd, 0b0110111)
+            }
+        }
+        // arithmetic on rd'
+        (0b01, 0b100) => {
+            let rd = creg(op >> 7);
+            let shamt = ((op >> 7) & 0x20) | ((op >> 2) & 0x1F);
+            // shift amount can't exceed 31 in RV32
+            if rv32 && op >> 11 & 0x1 == 0 && shamt & 0x20 != 0 {
+                return Err(illegal(op));
+            }
+            match op >> 10 & 0x3 {
+                // C.srli
+                0b00 => i_type(shamt as i32, rd, 0b101, rd, 0b0010011),
+                // C.srai
+                0b01 => i_type((0b010000 << 6 | shamt) as i32, rd, 0b101, rd, 0b0010011),
+                // C.andi
+                0b10 => i_type(ci_imm(op), rd, 0b111, rd, 0b0010011),
+                _ => {
+                    let rs2 = creg(op >> 2);
+                    match (op >> 12 & 0x1, op >> 5 & 0x3) {
+                        // C.sub
+                        (0, 0b00) => r_type(0b0100000, rs2, rd, 0b000, rd, 0b0110011),
+                        // C.xor
+                        (0, 0b01) => r_type(0b0000000, rs2, rd, 0b100, rd, 0b0110011),
+                        // C.or
+                        (0, 0b10) => r_type(0b0000000, rs2, rd, 0b110, rd, 0b0110011),
+                        // C.and
+                        (0, 0b11) => r_type(0b0000000, rs2, rd, 0b111, rd, 0b0110011),
+                        // C.subw
+                        (1, 0b00) if !rv32 => r_type(0b0100000, rs2, rd, 0b000, rd, 0b0111011),
+                        // C.addw
+                        (1, 0b01) if !rv32 => r_type(0b0000000, rs2, rd, 0b000, rd, 0b0111011),
+                        // error?
+                        _ => return Err(illegal(op)),
+                    }
+                }
+            }
+        }
+        // C.j
+        (0b01, 0b101) => j_type(cj_imm(op), 0, 0b1101111),
+        // C.beqz, C.bnez
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let imm = sext(
+                ((op >> 4) & 0x100)
+                    | ((op >> 7) & 0x18)
+                    | ((op << 1) & 0xC0)
+                    | ((op >> 2) & 0x6)
+                    | ((op << 3) & 0x20),
+                9,
+            );
+            b_type(imm, 0, creg(op >> 7), funct3 & 0x1, 0b1100011)
+        }
+
+        // quadrant 2
+        // C.slli
+        (0b10, 0b000) => {
+            let rd = op >> 7 & 0x1F;
+            let shamt = ((op >> 7) & 0x20) | ((op >> 2) & 0x1F);
+            if rv32 && shamt & 0x20 != 0 {
+                return Err(illegal(op));
+            }
+            i_type(shamt as i32, rd, 0b001, rd, 0b0010011)
+        }
+        // C.fldsp
+        (0b10, 0b001) => {
+            let imm = ((op >> 7) & 0x20) | ((op >> 2) & 0x18) | ((op << 4) & 0x1C0);
+            i_type(imm as i32, 2, 0b011, op >> 7 & 0x1F, 0b0000111)
+        }
+        // C.lwsp
+        (0b10, 0b010) => {
+            let rd = op >> 7 & 0x1F;
+            if rd == 0 {
+                return Err(illegal(op));
+            }
+            let imm = ((op >> 7) & 0x20) | ((op >> 2) & 0x1C) | ((op << 4) & 0xC0);
+            i_type(imm as i32, 2, 0b010, rd, 0b0000011)
+        }
+        // C.flwsp RV32
+        (0b10, 0b011) if rv32 => {
+            let imm = ((op >> 7) & 0x20) | ((op >> 2) & 0x1C) | ((op << 4) & 0xC0);
+            i_type(imm as i32, 2, 0b010, op >> 7 & 0x1F, 0b0000111)
+        }
+        // C.ldsp
+        (0b10, 0b011) => {
+            let rd = op >> 7 & 0x1F;
+            if rd == 0 {
+                return Err(illegal(op));
+            }
+            let imm = ((op >> 7) & 0x20) | ((op >> 2) & 0x18) | ((op << 4) & 0x1C0);
+            i_type(imm as i32, 2, 0b011, rd, 0b0000011)
+        }
+        (0b10, 0b100) => {
+            let rs1 = op >> 7 & 0x1F;
+            let rs2 = op >> 2 & 0x1F;
+            match (op >> 12 & 0x1, rs1, rs2) {
+                // C.jr
+                (0, 0, 0) => return Err(illegal(op)),
+                (0, _, 0) => i_type(0, rs1, 0b000, 0, 0b1100111),
+                // C.mv
+                (0, _, _) => r_type(0b0000000, rs2, 0, 0b000, rs1, 0b0110011),
+                // C.ebreak
+                (1, 0, 0) => 0b00000000000100000000000001110011,
+                // C.jalr
+                (1, _, 0) => i_type(0, rs1, 0b000, 1, 0b1100111),
+                // C.add
+                _ => r_type(0b0000000, rs2, rs1, 0b000, rs1, 0b0110011),
+            }
+        }
+        // C.fsdsp
+        (0b10, 0b101) => {
+            let imm = ((op >> 7) & 0x38) | ((op >> 1) & 0x1C0);
+            s_type(imm as i32, op >> 2 & 0x1F, 2, 0b011, 0b0100111)
+        }
+        // C.swsp
+        (0b10, 0b110) => {
+            let imm = ((op >> 7) & 0x3C) | ((op >> 1) & 0xC0);
+            s_type(imm as i32, op >> 2 & 0x1F, 2, 0b010, 0b0100011)
+        }
+        // C.fswsp RV32
+        (0b10, 0b111) if rv32 => {
+            let imm = ((op >> 7) & 0x3C) | ((op >> 1) & 0xC0);
+            s_type(imm as i32, op >> 2 & 0x1F, 2, 0b010, 0b0100111)
+        }
+        // C.sdsp
+        (0b10, 0b111) => {
+            let imm = ((op >> 7) & 0x38) | ((op >> 1) & 0x1C0);
+            s_type(imm as i32, op >> 2 & 0x1F, 2, 0b011, 0b0100011)
+        }
+
+        // error?
+        _ => return Err(illegal(op)),
+    };
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{isa::parse_isa, ISA};
+
+    fn set_isa(isa: &str) {
+        ISA.with(|x| *x.borrow_mut() = parse_isa(isa).unwrap());
+    }
+
+    fn check(cases: &[(u16, u32)]) {
+        for &(op, expanded) in cases {
+            assert_eq!(expand_compressed(op), Ok(expanded), "{:#06x}", op);
+        }
+    }
+
+    // encodings from llvm-mc, compressed next to the 32 bit instruction
+    #[test]
+    fn expand_rv64() {
+        set_isa("rv64gc");
+        check(&[
+            // c.addi4spn s0, sp, 1020
+            (0x1FE0, 0x3FC10413),
+            // c.fld fa5, 248(a0)
+            (0x3D7C, 0x0F853787),
+            // c.lw a2, 124(a5)
+            (0x5FF0, 0x07C7A603),
+            // c.ld s1, 8(a3)
+            (0x6684, 0x0086B483),
+            // c.fsd fs0, 0(s1)
+            (0xA080, 0x0084B027),
+            // c.sw a0, 64(a1)
+            (0xC1A8, 0x04A5A023),
+            // c.sd a4, 248(a5)
+            (0xFFF8, 0x0EE7BC23),
+            // c.nop
+            (0x0001, 0x00000013),
+            // c.addi a0, -32
+            (0x1501, 0xFE050513),
+            // c.addiw t1, 31
+            (0x237D, 0x01F3031B),
+            // c.li ra, -1
+            (0x50FD, 0xFFF00093),
+            // c.addi16sp sp, -512
+            (0x7101, 0xE0010113),
+            // c.addi16sp sp, 496
+            (0x617D, 0x1F010113),
+            // c.lui a5, 0xfffe0
+            (0x7781, 0xFFFE07B7),
+            // c.lui t0, 1
+            (0x6285, 0x000012B7),
+            // c.srli s0, 63
+            (0x907D, 0x03F45413),
+            // c.srai a1, 1
+            (0x8585, 0x4015D593),
+            // c.andi a2, -1
+            (0x9A7D, 0xFFF67613),
+            // c.sub s1, a5
+            (0x8C9D, 0x40F484B3),
+            // c.xor a0, a1
+            (0x8D2D, 0x00B54533),
+            // c.or a2, a3
+            (0x8E55, 0x00D66633),
+            // c.and a4, s0
+            (0x8F61, 0x00877733),
+            // c.subw a0, a5
+            (0x9D1D, 0x40F5053B),
+            // c.addw s1, s0
+            (0x9CA1, 0x008484BB),
+            // c.j -2048
+            (0xB001, 0x801FF06F),
+            // c.j 2046
+            (0xAFFD, 0x7FE0006F),
+            // c.beqz a0, -256
+            (0xD101, 0xF00500E3),
+            // c.bnez s1, 254
+            (0xECFD, 0x0E049F63),
+            // c.slli t2, 63
+            (0x13FE, 0x03F39393),
+            // c.fldsp fs1, 504(sp)
+            (0x34FE, 0x1F813487),
+            // c.lwsp ra, 252(sp)
+            (0x50FE, 0x0FC12083),
+            // c.ldsp s11, 504(sp)
+            (0x7DFE, 0x1F813D83),
+            // c.jr ra
+            (0x8082, 0x00008067),
+            // c.mv a0, t6
+            (0x857E, 0x01F00533),
+            // c.ebreak
+            (0x9002, 0x00100073),
+            // c.jalr t0
+            (0x9282, 0x000280E7),
+            // c.add sp, a0
+            (0x912A, 0x00A10133),
+            // c.fsdsp fs11, 504(sp)
+            (0xBFEE, 0x1FB13C27),
+            // c.swsp s0, 252(sp)
+            (0xDFA2, 0x0E812E23),
+            // c.sdsp t6, 0(sp)
+            (0xE07E, 0x01F13023),
+        ]);
+    }
+
+    #[test]
+    fn expand_rv32() {
+        set_isa("rv32gc");
+        check(&[
+            // c.jal -2
+            (0x3FFD, 0xFFFFF0EF),
+            // c.jal 1024
+            (0x2101, 0x400000EF),
+            // c.flw fa0, 124(a1)
+            (0x7DE8, 0x07C5A507),
+            // c.fsw fs1, 4(s0)
+            (0xE044, 0x00942227),
+            // c.flwsp ft0, 252(sp)
+            (0x707E, 0x0FC12007),
+            // c.fswsp ft11, 0(sp)
+            (0xE07E, 0x01F12027),
+            // c.srli a0, 31
+            (0x817D, 0x01F55513),
+        ]);
+    }
+
+    #[test]
+    fn reserved_rv64() {
+        set_isa("rv64gc");
+        for op in [
+            // all zeros
+            0x0000, // c.addi4spn with zero immediate
+            0x0004, // c.addiw with rd = x0
+            0x2001, // c.addi16sp with zero immediate
+            0x6101, // c.lui with zero immediate
+            0x6281, // c.lwsp and c.ldsp with rd = x0
+            0x4002, 0x6002, // c.jr with rs1 = x0
+            0x8002,
+        ] {
+            assert_eq!(
+                expand_compressed(op),
+                Err(illegal(op as u32)),
+                "{:#06x}",
+                op
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_rv32() {
+        set_isa("rv32gc");
+        // c.srli and c.slli with shamt[5] set
+        for op in [0x9101, 0x1502] {
+            assert_eq!(
+                expand_compressed(op),
+                Err(illegal(op as u32)),
+                "{:#06x}",
+                op
+            );
+        }
+    }
+}
@@ -0,0 +1,950 @@
+#![allow(unused_unsafe)]
+// F and D extensions
+// single precision values are kept NaN-boxed in the 64 bit f registers, rounding modes other
+// than round-to-nearest-even are emulated by computing the exact error of the host operation
+// and moving the result to its neighbour when the mode requires it
+use std::{cmp::Ordering, num::FpCategory};
+
+use crate::{trap::Exception, *};
+
+// fflags bits
+pub const NV: u32 = 0x10;
+pub const DZ: u32 = 0x08;
+pub const OF: u32 = 0x04;
+pub const UF: u32 = 0x02;
+pub const NX: u32 = 0x01;
+
+// rounding modes
+pub const RNE: u32 = 0b000;
+pub const RTZ: u32 = 0b001;
+pub const RDN: u32 = 0b010;
+pub const RUP: u32 = 0b011;
+pub const RMM: u32 = 0b100;
+pub const DYN: u32 = 0b111;
+
+const CANONICAL_NAN_32: u32 = 0x7fc00000;
+const CANONICAL_NAN_64: u64 = 0x7ff8000000000000;
+
+// fmt field values
+const S: u32 = 0b00;
+const D: u32 = 0b01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FpOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sqrt,
+    Fma,
+}
+
+#[inline(always)]
+pub fn box_f32(v: f32) -> u64 {
+    0xFFFFFFFF00000000 | v.to_bits() as u64
+}
+
+#[inline(always)]
+pub fn unbox_f32(v: u64) -> f32 {
+    if v >> 32 == 0xFFFFFFFF {
+        f32::from_bits(v as u32)
+    } else {
+        f32::from_bits(CANONICAL_NAN_32)
+    }
+}
+
+#[inline(always)]
+fn is_snan_f32(v: f32) -> bool {
+    v.is_nan() && v.to_bits() & 0x00400000 == 0
+}
+
+#[inline(always)]
+fn is_snan_f64(v: f64) -> bool {
+    v.is_nan() && v.to_bits() & 0x0008000000000000 == 0
+}
+
+#[inline(always)]
+fn raise(flags: u32) {
+    if flags != 0 {
+        set_fcsr!(read_fcsr!() | flags);
+    }
+}
+
+fn rounding_mode(raw: u32) -> Result<u32, Exception> {
+    let rm = raw >> 12 & 0x7;
+    let rm = if rm == DYN {
+        read_fcsr!() >> 5 & 0x7
+    } else {
+        rm
+    };
+    if rm > RMM {
+        return Err(Exception::IllegalInstruction(raw));
+    }
+    Ok(rm)
+}
+
+// reads f register as a value of the given format widened to f64, second value tells if it
+// was a signaling NaN before widening
+fn read_operand(fmt: u32, reg: u32) -> (f64, bool) {
+    if fmt == S {
+        let v = unbox_f32(read_freg!(reg));
+        (v as f64, is_snan_f32(v))
+    } else {
+        let v = f64::from_bits(read_freg!(reg));
+        (v, is_snan_f64(v))
+    }
+}
+
+// writes value that is already exact in the given format
+fn write_result(fmt: u32, reg: u32, v: f64) {
+    if fmt == S {
+        if v.is_nan() {
+            set_freg!(reg, box_f32(f32::from_bits(CANONICAL_NAN_32)));
+        } else {
+            set_freg!(reg, box_f32(v as f32));
+        }
+    } else if v.is_nan() {
+        set_freg!(reg, CANONICAL_NAN_64);
+    } else {
+        set_freg!(reg, v.to_bits());
+    }
+}
+
+#[inline(always)]
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let bb = s - a;
+    let e = (a - (s - bb)) + (b - bb);
+    (s, e)
+}
+
+// tells if rounding should move from round-to-nearest-even result `r` to `other`, the
+// neighbour on the side of the exact value, `err` is the distance from `r` to the exact value
+fn take_neighbour(rm: u32, r: f64, other: f64, err: f64, r_even: bool) -> bool {
+    let half = (other - r).abs() / 2.0;
+    take_neighbour_cmp(rm, r, other, err.abs().total_cmp(&half), r_even)
+}
+
+// same with the distance to the exact value already compared to half of the distance to `other`
+fn take_neighbour_cmp(rm: u32, r: f64, other: f64, err: Ordering, r_even: bool) -> bool {
+    match rm {
+        RNE => err == Ordering::Greater || (err == Ordering::Equal && !r_even),
+        RTZ => other.abs() < r.abs(),
+        RDN => other < r,
+        RUP => other > r,
+        RMM => err == Ordering::Greater || (err == Ordering::Equal && other.abs() > r.abs()),
+        _ => unreachable!(),
+    }
+}
+
+// result of overflow: either infinity or the largest finite value, depending on the direction
+fn overflow_to_max(negative: bool, rm: u32) -> bool {
+    matches!((rm, negative), (RTZ, _) | (RDN, false) | (RUP, true))
+}
+
+// rounds exact value `hi + lo` to single precision
+fn round_f32(hi: f64, lo: f64, rm: u32) -> (f64, u32) {
+    if hi.is_nan() || hi.is_infinite() {
+        return (hi, 0);
+    }
+    let r = hi as f32;
+    let mut flags = 0;
+    let res = if r.is_infinite() {
+        flags |= OF | NX;
+        if overflow_to_max(r.is_sign_negative(), rm) {
+            f32::MAX.copysign(r)
+        } else {
+            r
+        }
+    } else {
+        let err = (hi - r as f64) + lo;
+        if err == 0.0 {
+            r
+        } else {
+            flags |= NX;
+            let other = if err > 0.0 {
+                r.next_up()
+            } else {
+                r.next_down()
+            };
+            let res = if take_neighbour(rm, r as f64, other as f64, err, r.to_bits() & 1 == 0) {
+                other
+            } else {
+                r
+            };
+            if res.is_infinite() {
+                flags |= OF;
+            }
+            res
+        }
+    };
+    if flags & NX != 0 && res.abs() < f32::MIN_POSITIVE {
+        flags |= UF;
+    }
+    (res as f64, flags)
+}
+
+// rounds exact value `hi + lo` to double precision, `hi` has to be the round-to-nearest-even
+// result of finite operands
+fn round_f64(hi: f64, lo: f64, rm: u32) -> (f64, u32) {
+    if hi.is_nan() {
+        return (hi, 0);
+    }
+    let mut flags = 0;
+    let res = if hi.is_infinite() {
+        flags |= OF | NX;
+        if overflow_to_max(hi.is_sign_negative(), rm) {
+            f64::MAX.copysign(hi)
+        } else {
+            hi
+        }
+    } else if lo == 0.0 {
+        hi
+    } else {
+        flags |= NX;
+        let other = if lo > 0.0 {
+            hi.next_up()
+        } else {
+            hi.next_down()
+        };
+        let res = if take_neighbour(rm, hi, other, lo, hi.to_bits() & 1 == 0) {
+            other
+        } else {
+            hi
+        };
+        if res.is_infinite() {
+            flags |= OF;
+        }
+        res
+    };
+    if flags & NX != 0 && res.abs() < f64::MIN_POSITIVE {
+        flags |= UF;
+    }
+    (res, flags)
+}
+
+fn round_to(fmt: u32, hi: f64, lo: f64, rm: u32) -> (f64, u32) {
+    if fmt == S {
+        round_f32(hi, lo, rm)
+    } else {
+        round_f64(hi, lo, rm)
+    }
+}
+
+// round-to-nearest-even result of the operation and the error to the exact result
+fn exact(op: FpOp, a: f64, b: f64, c: f64) -> (f64, f64) {
+    match op {
+        FpOp::Add => two_sum(a, b),
+        FpOp::Sub => two_sum(a, -b),
+        FpOp::Mul => {
+            let p = a * b;
+            (p, a.mul_add(b, -p))
+        }
+        FpOp::Div => {
+            let q = a / b;
+            let rem = (-q).mul_add(b, a);
+            (q, rem / b)
+        }
+        FpOp::Sqrt => {
+            let q = a.sqrt();
+            let rem = (-q).mul_add(q, a);
+            (q, rem / (2.0 * q))
+        }
+        FpOp::Fma => {
+            let r = a.mul_add(b, c);
+            let p = a * b;
+            let pl = a.mul_add(b, -p);
+            let (s, e) = two_sum(p, c);
+            (r, (s - r) + (e + pl))
+        }
+    }
+}
+
+fn hw(op: FpOp, a: f64, b: f64, c: f64) -> f64 {
+    match op {
+        FpOp::Add => a + b,
+        FpOp::Sub => a - b,
+        FpOp::Mul => a * b,
+        FpOp::Div => a / b,
+        FpOp::Sqrt => a.sqrt(),
+        FpOp::Fma => a.mul_add(b, c),
+    }
+}
+
+// double precision results below this can have error of the host operation underflow
+const TINY_F64: f64 = f64::from_bits((1023 - 969) << 52);
+
+// terms of fma further apart than 2^APART only contribute the sign of the smaller one
+const APART: i32 = 900;
+
+// v * 2^exp in steps that stay representable, only the last step rounds
+fn pow2(mut v: f64, mut exp: i32) -> f64 {
+    while exp != 0 {
+        let step = exp.clamp(-1000, 1000);
+        v *= f64::from_bits(((1023 + step) as u64) << 52);
+        exp -= step;
+    }
+    v
+}
+
+// floor(log2(|v|)) of finite non-zero value
+fn exponent(v: f64) -> i32 {
+    let bits = v.to_bits() & !(1 << 63);
+    match bits >> 52 {
+        0 => -1011 - bits.leading_zeros() as i32,
+        e => e as i32 - 1023,
+    }
+}
+
+// true when error term of the double precision operation can underflow
+fn near_subnormal(op: FpOp, a: f64, b: f64, hi: f64) -> bool {
+    match op {
+        FpOp::Add | FpOp::Sub => false,
+        FpOp::Sqrt => a != 0.0 && a < TINY_F64,
+        _ if a == 0.0 || b == 0.0 => false,
+        FpOp::Mul => hi.abs() < TINY_F64,
+        // remainder is smaller than the dividend
+        FpOp::Div => hi.abs() < TINY_F64 || a.abs() < TINY_F64,
+        // so is error of the product
+        _ => hi.abs() < TINY_F64 || (a * b).abs() < TINY_F64,
+    }
+}
+
+// operation on operands scaled to normal range, exact result is (hi + lo) * 2^k
+fn exact_scaled(op: FpOp, a: f64, b: f64, c: f64) -> (f64, f64, i32) {
+    if op == FpOp::Sqrt {
+        // even exponent keeps the square root exact to scale back
+        let e = exponent(a) & !1;
+        let (hi, lo) = exact(op, pow2(a, -e), 0.0, 0.0);
+        return (hi, lo, e / 2);
+    }
+    let (ea, eb) = (exponent(a), exponent(b));
+    let (a, b) = (pow2(a, -ea), pow2(b, -eb));
+    match op {
+        FpOp::Mul => {
+            let (hi, lo) = exact(op, a, b, 0.0);
+            (hi, lo, ea + eb)
+        }
+        FpOp::Div => {
+            let (hi, lo) = exact(op, a, b, 0.0);
+            (hi, lo, ea - eb)
+        }
+        _ => {
+            // larger of the product and addend is scaled to 1
+            let ep = ea + eb;
+            let ec = if c == 0.0 {
+                ep - APART - 1
+            } else {
+                exponent(c)
+            };
+            let k = ep.max(ec);
+            let sticky = pow2(1.0, -APART);
+            if ep < k - APART {
+                return (pow2(c, -k), sticky.copysign(a * b), k);
+            }
+            let c_scaled = if ec < k - APART { 0.0 } else { pow2(c, -k) };
+            let (hi, lo) = exact(op, a, pow2(b, ep - k), c_scaled);
+            if lo == 0.0 && c_scaled == 0.0 && c != 0.0 {
+                return (hi, sticky.copysign(c), k);
+            }
+            (hi, lo, k)
+        }
+    }
+}
+
+// rounds double precision result of operation whose error term can underflow on the host
+fn round_near_subnormal(op: FpOp, a: f64, b: f64, c: f64, rm: u32) -> (f64, u32) {
+    let (hi, lo, k) = exact_scaled(op, a, b, c);
+    if hi == 0.0 {
+        // exact cancellation of fma terms
+        return (if rm == RDN { -0.0 } else { 0.0 }, 0);
+    }
+    if exponent(hi) + k > -1022 {
+        let (res, flags) = round_f64(hi, lo, rm);
+        return (pow2(res, k), flags);
+    }
+
+    // count of subnormal steps of 2^-1074, below 2^53 and exact when it matters
+    let t = pow2(hi, 1074 + k);
+    let e = pow2(lo, 1074 + k);
+    let n = t.round_ties_even();
+    let dist = t - n;
+    if dist == 0.0 && e == 0.0 {
+        return (f64::from_bits(n.abs() as u64).copysign(hi), 0);
+    }
+    let other = n + if dist != 0.0 {
+        dist.signum()
+    } else {
+        e.signum()
+    };
+    let err = if dist.abs() < 0.5 || (e != 0.0 && (e > 0.0) != (dist > 0.0)) {
+        Ordering::Less
+    } else if e == 0.0 {
+        Ordering::Equal
+    } else {
+        Ordering::Greater
+    };
+    let n = if take_neighbour_cmp(rm, n, other, err, n % 2.0 == 0.0) {
+        other
+    } else {
+        n
+    };
+    // steps of 2^-1074 are the bit pattern up to 2^-1021
+    let res = f64::from_bits(n.abs() as u64).copysign(hi);
+    let flags = if res.abs() < f64::MIN_POSITIVE {
+        NX | UF
+    } else {
+        NX
+    };
+    (res, flags)
+}
+
+fn arith(op: FpOp, fmt: u32, rm: u32, ops: [(f64, bool); 3]) -> (f64, u32) {
+    let count = match op {
+        FpOp::Sqrt => 1,
+        FpOp::Fma => 3,
+        _ => 2,
+    };
+    let ops = &ops[..count];
+    let (a, b, c) = (
+        ops[0].0,
+        ops.get(1).map_or(0.0, |x| x.0),
+        ops.get(2).map_or(0.0, |x| x.0),
+    );
+
+    let mut flags = 0;
+    if ops.iter().any(|x| x.1) {
+        flags |= NV;
+    }
+    let r = hw(op, a, b, c);
+    let any_nan = ops.iter().any(|x| x.0.is_nan());
+
+    // infinities, NaNs and division by zero give exact results
+    if ops.iter().any(|x| !x.0.is_finite()) || r.is_nan() || (op == FpOp::Div && b == 0.0) {
+        if r.is_nan() && !any_nan {
+            flags |= NV;
+        }
+        if op == FpOp::Fma && ((a.is_infinite() && b == 0.0) || (a == 0.0 && b.is_infinite())) {
+            flags |= NV;
+        }
+        if op == FpOp::Div && b == 0.0 && a.is_finite() && a != 0.0 {
+            flags |= DZ;
+        }
+        return (r, flags);
+    }
+
+    if fmt == D && near_subnormal(op, a, b, r) {
+        let (res, f) = round_near_subnormal(op, a, b, c, rm);
+        return (res, flags | f);
+    }
+
+    let (hi, lo) = exact(op, a, b, c);
+    // exact zero sum is negative when rounding down unless both addends are +0
+    if hi == 0.0 && lo == 0.0 && rm == RDN {
+        let (x, y) = match op {
+            FpOp::Add => (a, b),
+            FpOp::Sub => (a, -b),
+            FpOp::Fma => (a * b, c),
+            _ => (hi, hi),
+        };
+        if x.is_sign_negative() || y.is_sign_negative() || x != 0.0 {
+            return (-0.0, flags);
+        }
+    }
+
+    let (res, f) = round_to(fmt, hi, lo, rm);
+    (res, flags | f)
+}
+
+fn round_to_int(v: f64, rm: u32) -> f64 {
+    match rm {
+        RNE => v.round_ties_even(),
+        RTZ => v.trunc(),
+        RDN => v.floor(),
+        RUP => v.ceil(),
+        RMM => v.round(),
+        _ => unreachable!(),
+    }
+}
+
+// Fcvt.w/wu/l/lu, returns value for x register
+fn fp_to_int(v: f64, kind: u32, rm: u32) -> (u64, u32) {
+    let (min, max_excl, min_res, max_res): (f64, f64, u64, u64) = match kind {
+        // W
+        0b00 => (
+            -2147483648.0,
+            2147483648.0,
+            i32::MIN as i64 as u64,
+            i32::MAX as u64,
+        ),
+        // WU
+        0b01 => (0.0, 4294967296.0, 0, u64::MAX),
+        // L
+        0b10 => (
+            -9223372036854775808.0,
+            9223372036854775808.0,
+            i64::MIN as u64,
+            i64::MAX as u64,
+        ),
+        // LU
+        0b11 => (0.0, 18446744073709551616.0, 0, u64::MAX),
+        _ => unreachable!(),
+    };
+
+    if v.is_nan() {
+        return (max_res, NV);
+    }
+    let r = round_to_int(v, rm);
+    if r < min {
+        return (min_res, NV);
+    }
+    if r >= max_excl {
+        return (max_res, NV);
+    }
+
+    let flags = if r != v { NX } else { 0 };
+    let res = match kind {
+        0b00 => r as i32 as i64 as u64,
+        0b01 => r as u32 as i32 as i64 as u64,
+        0b10 => r as i64 as u64,
+        _ => r as u64,
+    };
+    (res, flags)
+}
+
+// Fcvt.s/d.w/wu/l/lu
+fn int_to_fp(x: u64, kind: u32, fmt: u32, rm: u32) -> (f64, u32) {
+    let n: i128 = match kind {
+        0b00 => x as i32 as i128,
+        0b01 => x as u32 as i128,
+        0b10 => x as i64 as i128,
+        0b11 => x as i128,
+        _ => unreachable!(),
+    };
+    let hi = n as f64;
+    let lo = (n - hi as i128) as f64;
+    round_to(fmt, hi, lo, rm)
+}
+
+fn fclass(fmt: u32, bits: u64) -> u64 {
+    let (category, negative, snan) = if fmt == S {
+        let v = unbox_f32(bits);
+        (v.classify(), v.is_sign_negative(), is_snan_f32(v))
+    } else {
+        let v = f64::from_bits(bits);
+        (v.classify(), v.is_sign_negative(), is_snan_f64(v))
+    };
+    let bit = match (category, negative) {
+        (FpCategory::Infinite, true) => 0,
+        (FpCategory::Normal, true) => 1,
+        (FpCategory::Subnormal, true) => 2,
+        (FpCategory::Zero, true) => 3,
+        (FpCategory::Zero, false) => 4,
+        (FpCategory::Subnormal, false) => 5,
+        (FpCategory::Normal, false) => 6,
+        (FpCategory::Infinite, false) => 7,
+        (FpCategory::Nan, _) => {
+            if snan {
+                8
+            } else {
+                9
+            }
+        }
+    };
+    1 << bit
+}
+
+// Fmadd, Fmsub, Fnmsub, Fnmadd
+pub fn execute_fma(raw: u32) -> Result<(), Exception> {
+    let opcode = raw & 0x7F;
+    let fmt = raw >> 25 & 0x3;
+    if fmt > D || (fmt == D && !has_ext!(d)) {
+        return Err(Exception::IllegalInstruction(raw));
+    }
+    let rm = rounding_mode(raw)?;
+    let rd = rd!(raw);
+    let (a, a_snan) = read_operand(fmt, rs1!(raw));
+    let (b, b_snan) = read_operand(fmt, rs2!(raw));
+    let (c, c_snan) = read_operand(fmt, raw >> 27);
+
+    let (a, c) = match opcode {
+        // Fmadd.s/d
+        0b1000011 => (a, c),
+        // Fmsub.s/d
+        0b1000111 => (a, -c),
+        // Fnmsub.s/d
+        0b1001011 => (-a, c),
+        // Fnmadd.s/d
+        0b1001111 => (-a, -c),
+        // error?
+        _ => return Err(Exception::IllegalInstruction(raw)),
+    };
+
+    let (res, flags) = arith(FpOp::Fma, fmt, rm, [(a, a_snan), (b, b_snan), (c, c_snan)]);
+    write_result(fmt, rd, res);
+    raise(flags);
+    Ok(())
+}
+
+pub fn execute_op_fp(raw: u32) -> Result<(), Exception> {
+    let funct5 = raw >> 27 & 0x1F;
+    let fmt = raw >> 25 & 0x3;
+    let funct3 = raw >> 12 & 0x7;
+    let rd = rd!(raw);
+    let rs1 = rs1!(raw);
+    let rs2 = rs2!(raw);
+    if fmt > D || (fmt == D && !has_ext!(d)) {
+        return Err(Exception::IllegalInstruction(raw));
+    }
+
+    match funct5 {
+        // Fadd, Fsub, Fmul, Fdiv, Fsqrt
+        0b00000 | 0b00001 | 0b00010 | 0b00011 | 0b01011 => {
+            let op = match funct5 {
+                0b00000 => FpOp::Add,
+                0b00001 => FpOp::Sub,
+                0b00010 => FpOp::Mul,
+                0b00011 => FpOp::Div,
+                _ => FpOp::Sqrt,
+            };
+            let rm = rounding_mode(raw)?;
+            let (res, flags) = arith(
+                op,
+                fmt,
+                rm,
+                [read_operand(fmt, rs1), read_operand(fmt, rs2), (0.0, false)],
+            );
+            write_result(fmt, rd, res);
+            raise(flags);
+        }
+        // Fsgnj, Fsgnjn, Fsgnjx
+        0b00100 => {
+            let (a, b, sign) = if fmt == S {
+                (
+                    unbox_f32(read_freg!(rs1)).to_bits() as u64,
+                    unbox_f32(read_freg!(rs2)).to_bits() as u64,
+                    1u64 << 31,
+                )
+            } else {
+                (read_freg!(rs1), read_freg!(rs2), 1u64 << 63)
+            };
+            let s = match funct3 {
+                // Fsgnj
+                0b000 => b & sign,
+                // Fsgnjn
+                0b001 => !b & sign,
+                // Fsgnjx
+                0b010 => (a ^ b) & sign,
+                // error?
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            };
+            let res = (a & !sign) | s;
+            if fmt == S {
+                set_freg!(rd, box_f32(f32::from_bits(res as u32)));
+            } else {
+                set_freg!(rd, res);
+            }
+        }
+        // Fmin, Fmax
+        0b00101 => {
+            let (a, a_snan) = read_operand(fmt, rs1);
+            let (b, b_snan) = read_operand(fmt, rs2);
+            let res = match (a.is_nan(), b.is_nan()) {
+                (true, true) => f64::NAN,
+                (true, false) => b,
+                (false, true) => a,
+                _ if a == b => match funct3 {
+                    // -0.0 is smaller than +0.0
+                    0b000 => f64::from_bits(a.to_bits() | b.to_bits()),
+                    _ => f64::from_bits(a.to_bits() & b.to_bits()),
+                },
+                _ => match funct3 {
+                    // Fmin
+                    0b000 => a.min(b),
+                    // Fmax
+                    0b001 => a.max(b),
+                    // error?
+                    _ => return Err(Exception::IllegalInstruction(raw)),
+                },
+            };
+            write_result(fmt, rd, res);
+            raise(if a_snan || b_snan { NV } else { 0 });
+        }
+        // Fcvt.s.d, Fcvt.d.s
+        0b01000 => {
+            let src = rs2;
+            let (v, snan) = match (fmt, src) {
+                (S, 0b00001) if has_ext!(d) => read_operand(D, rs1),
+                (D, 0b00000) => read_operand(S, rs1),
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            };
+            let (res, flags) = if fmt == S {
+                round_f32(v, 0.0, rounding_mode(raw)?)
+            } else {
+                (v, 0)
+            };
+            write_result(fmt, rd, res);
+            raise(flags | if snan { NV } else { 0 });
+        }
+        // Feq, Flt, Fle
+        0b10100 => {
+            let (a, a_snan) = read_operand(fmt, rs1);
+            let (b, b_snan) = read_operand(fmt, rs2);
+            let any_nan = a.is_nan() || b.is_nan();
+            let (res, flags) = match funct3 {
+                // Feq is a quiet comparison
+                0b010 => (a == b, if a_snan || b_snan { NV } else { 0 }),
+                // Flt
+                0b001 => (a < b, if any_nan { NV } else { 0 }),
+                // Fle
+                0b000 => (a <= b, if any_nan { NV } else { 0 }),
+                // error?
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            };
+            set_reg!(rd, res);
+            raise(flags);
+        }
+        // Fcvt.w/wu/l/lu.s/d, l and lu are RV64 only
+        0b11000 if rs2 < 0b100 && (rs2 & 0b10 == 0 || xlen!() == 64) => {
+            let (v, _) = read_operand(fmt, rs1);
+            let (res, flags) = fp_to_int(v, rs2, rounding_mode(raw)?);
+            set_reg!(rd, res);
+            raise(flags);
+        }
+        // Fcvt.s/d.w/wu/l/lu
+        0b11010 if rs2 < 0b100 && (rs2 & 0b10 == 0 || xlen!() == 64) => {
+            let (res, flags) = int_to_fp(read_reg!(rs1), rs2, fmt, rounding_mode(raw)?);
+            write_result(fmt, rd, res);
+            raise(flags);
+        }
+        // Fmv.x.w, Fmv.x.d, Fclass
+        0b11100 => match funct3 {
+            // Fmv.x.w / Fmv.x.d, Fmv.x.d is RV64 only
+            0b000 if fmt == S || xlen!() == 64 => {
+                if fmt == S {
+                    set_reg!(rd, t_i32!(read_freg!(rs1) as u32));
+                } else {
+                    set_reg!(rd, read_freg!(rs1));
+                }
+            }
+            // Fclass
+            0b001 => {
+                set_reg!(rd, fclass(fmt, read_freg!(rs1)));
+            }
+            // error?
+            _ => return Err(Exception::IllegalInstruction(raw)),
+        },
+        // Fmv.w.x, Fmv.d.x, Fmv.d.x is RV64 only
+        0b11110 if fmt == S || xlen!() == 64 => {
+            if fmt == S {
+                set_freg!(rd, box_f32(f32::from_bits(read_reg!(rs1) as u32)));
+            } else {
+                set_freg!(rd, read_reg!(rs1));
+            }
+        }
+        // error?
+        _ => return Err(Exception::IllegalInstruction(raw)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // OP-FP instruction with fd = f1, fs1 = f2, fs2 = f3
+    fn op_fp(funct7: u32, rm: u32) -> u32 {
+        funct7 << 25 | 3 << 20 | 2 << 15 | rm << 12 | 1 << 7 | 0b1010011
+    }
+
+    const FADD_S: u32 = 0b0000000;
+    const FADD_D: u32 = 0b0000001;
+    const FMUL_D: u32 = 0b0001001;
+    const FDIV_S: u32 = 0b0001100;
+
+    fn add_f32(a: f32, b: f32, rm: u32) -> (f32, u32) {
+        let (res, flags) = arith(
+            FpOp::Add,
+            S,
+            rm,
+            [(a as f64, false), (b as f64, false), (0.0, false)],
+        );
+        (res as f32, flags)
+    }
+
+    fn add_f64(a: f64, b: f64, rm: u32) -> (f64, u32) {
+        arith(FpOp::Add, D, rm, [(a, false), (b, false), (0.0, false)])
+    }
+
+    fn mul_f64(a: f64, b: f64, rm: u32) -> (f64, u32) {
+        arith(FpOp::Mul, D, rm, [(a, false), (b, false), (0.0, false)])
+    }
+
+    #[test]
+    fn ties_f32() {
+        // 1 + 2^-24 is halfway between 1 and the next value
+        let half_ulp = f32::EPSILON / 2.0;
+        let up = 1.0f32.next_up();
+        assert_eq!(add_f32(1.0, half_ulp, RNE), (1.0, NX));
+        assert_eq!(add_f32(1.0, half_ulp, RMM), (up, NX));
+        assert_eq!(add_f32(1.0, half_ulp, RTZ), (1.0, NX));
+        assert_eq!(add_f32(1.0, half_ulp, RDN), (1.0, NX));
+        assert_eq!(add_f32(1.0, half_ulp, RUP), (up, NX));
+        // ties to even goes up from odd
+        assert_eq!(add_f32(up, half_ulp, RNE), (up.next_up(), NX));
+        // negative ties away from zero
+        assert_eq!(add_f32(-1.0, -half_ulp, RMM), (-up, NX));
+        assert_eq!(add_f32(-1.0, -half_ulp, RDN), (-up, NX));
+        assert_eq!(add_f32(-1.0, -half_ulp, RUP), (-1.0, NX));
+    }
+
+    #[test]
+    fn ties_f64() {
+        let half_ulp = f64::EPSILON / 2.0;
+        let up = 1.0f64.next_up();
+        assert_eq!(add_f64(1.0, half_ulp, RNE), (1.0, NX));
+        assert_eq!(add_f64(1.0, half_ulp, RMM), (up, NX));
+        assert_eq!(add_f64(1.0, half_ulp, RTZ), (1.0, NX));
+        assert_eq!(add_f64(1.0, half_ulp, RUP), (up, NX));
+        assert_eq!(add_f64(up, half_ulp, RNE), (up.next_up(), NX));
+        assert_eq!(add_f64(-1.0, -half_ulp, RTZ), (-1.0, NX));
+        assert_eq!(add_f64(-1.0, -half_ulp, RDN), (-up, NX));
+    }
+
+    #[test]
+    fn exact_zero_sum_sign() {
+        assert_eq!(add_f64(1.0, -1.0, RNE).0.to_bits(), 0.0f64.to_bits());
+        assert_eq!(add_f64(1.0, -1.0, RDN).0.to_bits(), (-0.0f64).to_bits());
+        assert_eq!(add_f64(0.0, 0.0, RDN).0.to_bits(), 0.0f64.to_bits());
+    }
+
+    #[test]
+    fn subnormal_f32() {
+        let min = f32::from_bits(1);
+        // half of the smallest subnormal is a tie between it and zero
+        let half = min as f64 / 2.0;
+        let (res, flags) = round_f32(half, 0.0, RNE);
+        assert_eq!((res, flags), (0.0, UF | NX));
+        assert_eq!(round_f32(half, 0.0, RUP), (min as f64, UF | NX));
+        assert_eq!(round_f32(half, 0.0, RMM), (min as f64, UF | NX));
+        assert_eq!(round_f32(-half, 0.0, RDN), (-min as f64, UF | NX));
+        // exact subnormal results don't underflow
+        assert_eq!(add_f32(min, min, RNE), (f32::from_bits(2), 0));
+    }
+
+    #[test]
+    fn subnormal_f64() {
+        let min = f64::from_bits(1);
+        assert_eq!(mul_f64(min, 0.5, RNE), (0.0, UF | NX));
+        assert_eq!(mul_f64(min, 0.5, RUP), (min, UF | NX));
+        assert_eq!(mul_f64(min, 0.75, RNE), (min, UF | NX));
+        assert_eq!(mul_f64(min, 0.75, RTZ), (0.0, UF | NX));
+        assert_eq!(mul_f64(min, 2.0, RNE), (f64::from_bits(2), 0));
+    }
+
+    #[test]
+    fn underflowing_error_terms_f64() {
+        let min = f64::from_bits(1);
+        // normal quotient of subnormal dividend with remainder below the smallest subnormal
+        let div = |a, b, rm| arith(FpOp::Div, D, rm, [(a, false), (b, false), (0.0, false)]);
+        let q = 3.0 * min / f64::from_bits(3 << 52 | 1);
+        assert_eq!(div(3.0 * min, f64::from_bits(3 << 52 | 1), RTZ).1, NX);
+        assert_eq!(div(3.0 * min, f64::from_bits(3 << 52 | 1), RNE), (q, NX));
+        // product far below addend still moves directed rounding
+        let fma = |a, b, c, rm| arith(FpOp::Fma, D, rm, [(a, false), (b, false), (c, false)]);
+        assert_eq!(fma(min, min, 1.0, RNE), (1.0, NX));
+        assert_eq!(fma(min, min, 1.0, RUP), (1.0f64.next_up(), NX));
+        assert_eq!(fma(-min, min, 1.0, RTZ), (1.0f64.next_down(), NX));
+        // exact cancellation
+        assert_eq!(fma(3.0, min, -(3.0 * min), RNE), (0.0, 0));
+        let (res, flags) = fma(3.0, min, -(3.0 * min), RDN);
+        assert_eq!((res.to_bits(), flags), ((-0.0f64).to_bits(), 0));
+        // square root of subnormal is normal and usually inexact
+        let sqrt = |a, rm| arith(FpOp::Sqrt, D, rm, [(a, false), (0.0, false), (0.0, false)]);
+        assert_eq!(sqrt(4.0 * min, RNE), (2.0 * 2f64.powi(-537), 0));
+        assert_eq!(sqrt(2.0 * min, RNE).1, NX);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(add_f32(f32::MAX, f32::MAX, RNE), (f32::INFINITY, OF | NX));
+        assert_eq!(add_f32(f32::MAX, f32::MAX, RTZ), (f32::MAX, OF | NX));
+        assert_eq!(add_f32(-f32::MAX, -f32::MAX, RUP), (-f32::MAX, OF | NX));
+        assert_eq!(mul_f64(f64::MAX, 2.0, RDN), (f64::MAX, OF | NX));
+        assert_eq!(mul_f64(f64::MAX, -2.0, RDN), (f64::NEG_INFINITY, OF | NX));
+    }
+
+    #[test]
+    fn unboxed_single_is_nan() {
+        // upper half not all ones
+        set_freg!(2, 0x0000_0000_3F80_0000u64);
+        set_freg!(3, box_f32(1.0));
+        set_fcsr!(0);
+        execute_op_fp(op_fp(FADD_S, RNE)).unwrap();
+        assert_eq!(read_freg!(1), 0xFFFF_FFFF_7FC0_0000);
+        // canonical NaN is quiet
+        assert_eq!(read_fcsr!(), 0);
+    }
+
+    #[test]
+    fn nan_payload_is_canonicalised() {
+        set_freg!(2, 0x7FF8_0000_0000_0123u64);
+        set_freg!(3, 1.0f64.to_bits());
+        set_fcsr!(0);
+        execute_op_fp(op_fp(FADD_D, RNE)).unwrap();
+        assert_eq!(read_freg!(1), CANONICAL_NAN_64);
+        assert_eq!(read_fcsr!(), 0);
+
+        // signaling NaN raises invalid
+        set_freg!(2, 0x7FF0_0000_0000_0001u64);
+        execute_op_fp(op_fp(FMUL_D, RNE)).unwrap();
+        assert_eq!(read_freg!(1), CANONICAL_NAN_64);
+        assert_eq!(read_fcsr!(), NV);
+    }
+
+    #[test]
+    fn single_nan_payload_is_canonicalised() {
+        set_freg!(2, box_f32(f32::from_bits(0x7F80_0001)));
+        set_freg!(3, box_f32(2.0));
+        set_fcsr!(0);
+        execute_op_fp(op_fp(FDIV_S, RNE)).unwrap();
+        assert_eq!(read_freg!(1), 0xFFFF_FFFF_7FC0_0000);
+        assert_eq!(read_fcsr!(), NV);
+    }
+
+    #[test]
+    fn invalid_operation_gives_canonical_nan() {
+        set_freg!(2, box_f32(0.0));
+        set_freg!(3, box_f32(0.0));
+        set_fcsr!(0);
+        execute_op_fp(op_fp(FDIV_S, RNE)).unwrap();
+        assert_eq!(read_freg!(1), 0xFFFF_FFFF_7FC0_0000);
+        assert_eq!(read_fcsr!(), NV);
+    }
+
+    #[test]
+    fn dynamic_rounding_mode() {
+        set_freg!(2, box_f32(1.0));
+        set_freg!(3, box_f32(f32::EPSILON / 2.0));
+        set_fcsr!(RUP << 5);
+        execute_op_fp(op_fp(FADD_S, DYN)).unwrap();
+        assert_eq!(read_freg!(1), box_f32(1.0f32.next_up()));
+        assert_eq!(read_fcsr!(), RUP << 5 | NX);
+
+        // reserved frm makes dynamic rounding illegal
+        set_fcsr!(0b101 << 5);
+        let raw = op_fp(FADD_S, DYN);
+        assert_eq!(execute_op_fp(raw), Err(Exception::IllegalInstruction(raw)));
+    }
+
+    #[test]
+    fn convert_nan_and_out_of_range() {
+        assert_eq!(fp_to_int(f64::NAN, 0b00, RNE), (i32::MAX as u64, NV));
+        assert_eq!(fp_to_int(-1.0, 0b01, RNE), (0, NV));
+        // rounds before the range check
+        assert_eq!(fp_to_int(-0.4, 0b01, RNE), (0, NX));
+        assert_eq!(fp_to_int(2.5, 0b10, RNE), (2, NX));
+        assert_eq!(fp_to_int(2.5, 0b10, RMM), (3, NX));
+        assert_eq!(fp_to_int(-2.5, 0b00, RDN), (-3i64 as u64, NX));
+    }
+}
@@ -7,26 +7,77 @@
 // opcode mask for type J:                          0b1111111
 use crate::*;
 
+use crate::bus::Bus;
+use crate::csr::{
+    current_privilege, read_csr, read_csr_raw, write_csr, MACHINE, MSTATUS, MSTATUS_TVM,
+    MSTATUS_TW, SUPERVISOR, USER,
+};
+use crate::mmu::{load, store, translate, Access};
+use crate::tlb;
+use crate::trap::{self, Exception};
+
+use super::{
+    compressed::expand_compressed,
+    float::{box_f32, execute_fma, execute_op_fp},
+    vector::{execute_vector, execute_vector_memory},
+};
+
+// returns raw instruction, upper half is zero for compressed instructions
+// both halves are translated separately, so 32 bit instruction can cross page boundary
 #[inline(always)]
-pub fn get_instructions(prog_bits: &[u8], pc: u32) -> u32 {
-    let pc = pc as usize;
-    fast_transmute!(<0, u32>, [prog_bits[pc+0], prog_bits[pc+1], prog_bits[pc+2], prog_bits[pc+3]])
+pub fn get_instructions(bus: &mut Bus, pc: u64) -> Result<u32, Exception> {
+    let low = load(bus, pc, 2, Access::Fetch)? as u32;
+    if low & 0b11 != 0b11 {
+        return Ok(low);
+    }
+    let high = load(bus, pc.wrapping_add(2), 2, Access::Fetch)? as u32;
+    Ok(low | high << 16)
+}
+
+// sets PC so it points at the target after increment at the end of instruction
+pub fn jump(target: u64, ilen: u32) -> Result<(), Exception> {
+    let target = wrap_xlen!(target);
+    // targets are 4 byte aligned, or 2 byte aligned with C extension
+    let align = if has_ext!(c) { 2 } else { 4 };
+    if target % align != 0 {
+        return Err(Exception::InstructionAddressMisaligned(target));
+    }
+    set_pc!(target.wrapping_sub(ilen as u64));
+    Ok(())
+}
+
+pub fn execute_32(op: u32, bus: &mut Bus) -> Result<(), Exception> {
+    // instructions with lowest bits other than 0b11 are compressed
+    let (raw, ilen) = if op & 0b11 != 0b11 {
+        if !has_ext!(c) {
+            return Err(Exception::IllegalInstruction(op));
+        }
+        (expand_compressed(op as u16)?, 2)
+    } else {
+        (op, 4)
+    };
+    // illegal instruction is reported with its original encoding
+    let op = if ilen == 2 { op & 0xFFFF } else { op };
+    execute(raw, ilen, bus).map_err(|e| match e {
+        Exception::IllegalInstruction(_) => Exception::IllegalInstruction(op),
+        e => e,
+    })
 }
 
-pub fn execute_32(op: u32, dram: &mut Dram) {
+fn execute(op: u32, ilen: u32, bus: &mut Bus) -> Result<(), Exception> {
     let raw = op;
     let instruction_type = op & 0x7F;
     match instruction_type {
         // u_type
         // Lui
         0b0110111 => {
-            set_reg!(rd!(raw), imm!(U, raw) << 12);
+            set_reg!(rd!(raw), t_i32!(imm!(U, raw) << 12));
         }
         // Auipc
         0b0010111 => {
             set_reg!(
                 rd!(raw),
-                (get_pc!() as i64).wrapping_add((imm!(U, raw) << 12) as i64)
+                (get_pc!() as i64).wrapping_add(t_i32!(imm!(U, raw) << 12) as i64)
             );
         }
         // j_type
@@ -38,81 +89,144 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                 | (raw as i32 & 0xFF000);
             let imm = (imm << 11) >> 11;
 
-            let temp_pc = get_pc!() as i32;
             let rd = rd!(raw);
-            set_reg!(rd, get_pc!() + 4);
-            set_pc!(temp_pc.wrapping_add(imm).wrapping_sub(4));
+            let link = get_pc!().wrapping_add(ilen as u64);
+            jump((get_pc!() as i64).wrapping_add(imm as i64) as u64, ilen)?;
+            set_reg!(rd, link);
         }
         // Jalr
         0b1100111 => {
             let imm = imm!(I, raw);
             let rs1 = read_reg!(rs1!(raw)) as i64;
-            let mut imm = imm as i32;
-            imm = (imm << 20) >> 20;
 
             let rd = rd!(raw);
-            set_reg!(rd, get_pc!() + 4);
-            set_pc!(rs1.wrapping_add(imm as i64).wrapping_sub(4));
+            let link = get_pc!().wrapping_add(ilen as u64);
+            // lowest bit of target is cleared
+            jump(rs1.wrapping_add(imm as i64) as u64 & !1, ilen)?;
+            set_reg!(rd, link);
         }
         // i_type RV32I+RV64I
         0b0010011 => {
             let funct = op >> 12 & 0x7;
             match funct {
                 0b101 | 0b001 => {
-                    let s_funct = op >> 24 & 0x3f;
-                    match (s_funct, funct) {
+                    let funct6 = op >> 26 & 0x3f;
+                    let shamt = (imm!(I, raw) & 0x3f) as u32;
+                    let rs = rs1!(raw);
+                    let rd = rd!(raw);
+                    // shift amount can't exceed 31 in RV32
+                    if xlen!() == 32 && shamt & 0x20 != 0 {
+                        return Err(Exception::IllegalInstruction(raw));
+                    }
+                    match (funct6, funct) {
                         // Slli
                         (0b000000, 0b001) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = rs1!(raw);
-                            let rd = rd!(raw);
                             set_reg!(rd, read_reg!(rs) << shamt);
                         }
                         // Srli
                         (0b000000, 0b101) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = rs1!(raw);
-                            let rd = rd!(raw);
-                            set_reg!(rd, read_reg!(rs) >> shamt);
+                            set_reg!(rd, wrap_xlen!(read_reg!(rs)) >> shamt);
                         }
                         // Srai
                         (0b010000, 0b101) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = rs1!(raw);
-                            let rd = rd!(raw);
-                            set_reg!(rd, t_i64!(read_reg!(rs)) << shamt);
+                            set_reg!(rd, t_i64!(read_reg!(rs)) >> shamt);
+                        }
+                        // Clz, Ctz, Cpop, Sext.b, Sext.h Zbb
+                        (0b011000, 0b001) if has_ext!(zbb) => {
+                            let rs = wrap_xlen!(read_reg!(rs));
+                            match shamt {
+                                // Clz
+                                0b000000 => {
+                                    set_reg!(rd, rs.leading_zeros() - (64 - xlen!()));
+                                }
+                                // Ctz
+                                0b000001 => {
+                                    set_reg!(rd, rs.trailing_zeros().min(xlen!()));
+                                }
+                                // Cpop
+                                0b000010 => {
+                                    set_reg!(rd, rs.count_ones());
+                                }
+                                // Sext.b
+                                0b000100 => {
+                                    set_reg!(rd, rs as u8 as i8);
+                                }
+                                // Sext.h
+                                0b000101 => {
+                                    set_reg!(rd, rs as u16 as i16);
+                                }
+                                // error?
+                                _ => return Err(Exception::IllegalInstruction(raw)),
+                            }
+                        }
+                        // Rori Zbb
+                        (0b011000, 0b101) if has_ext!(zbb) => {
+                            if xlen!() == 32 {
+                                set_reg!(rd, (read_reg!(rs) as u32).rotate_right(shamt));
+                            } else {
+                                set_reg!(rd, read_reg!(rs).rotate_right(shamt));
+                            }
+                        }
+                        // Orc.b Zbb
+                        (0b001010, 0b101) if has_ext!(zbb) && shamt == 0b000111 => {
+                            let mut res = 0u64;
+                            for byte in 0..8 {
+                                if read_reg!(rs) >> (byte * 8) & 0xFF != 0 {
+                                    res |= 0xFF << (byte * 8);
+                                }
+                            }
+                            set_reg!(rd, res);
+                        }
+                        // Rev8 Zbb, encoding depends on XLEN
+                        (0b011010, 0b101) if has_ext!(zbb) && shamt == xlen!() - 8 => {
+                            if xlen!() == 32 {
+                                set_reg!(rd, (read_reg!(rs) as u32).swap_bytes());
+                            } else {
+                                set_reg!(rd, read_reg!(rs).swap_bytes());
+                            }
+                        }
+                        // Bclri Zbs
+                        (0b010010, 0b001) if has_ext!(zbs) => {
+                            set_reg!(rd, read_reg!(rs) & !(1u64 << shamt));
+                        }
+                        // Bexti Zbs
+                        (0b010010, 0b101) if has_ext!(zbs) => {
+                            set_reg!(rd, read_reg!(rs) >> shamt & 1);
+                        }
+                        // Binvi Zbs
+                        (0b011010, 0b001) if has_ext!(zbs) => {
+                            set_reg!(rd, read_reg!(rs) ^ (1u64 << shamt));
+                        }
+                        // Bseti Zbs
+                        (0b001010, 0b001) if has_ext!(zbs) => {
+                            set_reg!(rd, read_reg!(rs) | (1u64 << shamt));
                         }
 
                         // error?
-                        _ => panic!("unknown instruction occured in i_type branch"),
+                        _ => return Err(Exception::IllegalInstruction(raw)),
                     }
                 }
                 // Addi
                 0b000 => {
                     let rd = rd!(raw);
                     let rs = t_i64!(read_reg!(rs1!(raw)));
-                    let mut imm = imm!(I, raw) as i32;
-                    imm = (imm << 21) >> 21;
+                    let imm = imm!(I, raw);
                     set_reg!(rd, rs.wrapping_add(imm as i64));
                 }
                 // Slti
                 0b010 => {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
-                    let mut imm = imm!(I, raw) as i32;
-                    imm = (imm << 21) >> 21;
-                    if (rs as i64) < imm as i64 {
-                        set_reg!(rd, 1);
-                    }
+                    let imm = imm!(I, raw);
+                    set_reg!(rd, (rs as i64) < imm as i64);
                 }
                 // Sltiu
                 0b011 => {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
+                    // immediate is sign extended first, then compared as unsigned
                     let imm = imm!(I, raw);
-                    if (rs as u64) < imm as u64 {
-                        set_reg!(rd, 1);
-                    }
+                    set_reg!(rd, rs < imm as u64);
                 }
                 // Xori
                 0b100 => {
@@ -134,53 +248,72 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                 }
 
                 // error?
-                _ => panic!("unknown instruction occured in i_type branch"),
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
         }
         // i_type RV64I
-        0b0011011 => {
+        0b0011011 if xlen!() == 64 => {
             let funct = op >> 12 & 0x7;
 
             match funct {
                 0b101 | 0b001 => {
-                    let s_funct = op >> 24 & 0x3f;
-                    match (s_funct, funct) {
+                    let funct7 = op >> 25 & 0x7f;
+                    let shamt = (imm!(I, raw) & 0x1f) as u32;
+                    let rs = (read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32;
+                    let rd = rd!(raw);
+                    match (funct7, funct) {
                         // Slliw
-                        (0b000000, 0b001) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
-                            let rd = rd!(raw);
-                            set_reg!(rd, rs.wrapping_shl(shamt));
+                        (0b0000000, 0b001) => {
+                            set_reg!(rd, t_i32!(rs << shamt));
                         }
                         // Srliw
-                        (0b000000, 0b101) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
-                            let rd = rd!(raw);
-                            set_reg!(rd, rs.wrapping_shr(shamt));
+                        (0b0000000, 0b101) => {
+                            set_reg!(rd, t_i32!(rs >> shamt));
                         }
                         // Sraiw
-                        (0b010000, 0b101) => {
-                            let shamt = imm!(I, raw) & 0x1f;
-                            let rs = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
-                            let rd = rd!(raw);
-                            set_reg!(rd, rs.wrapping_shr(shamt));
+                        (0b0100000, 0b101) => {
+                            set_reg!(rd, t_i32!(rs) >> shamt);
+                        }
+                        // Slli.uw Zba, shift amount has 6 bits
+                        (0b0000100 | 0b0000101, 0b001) if has_ext!(zba) => {
+                            let shamt = imm!(I, raw) & 0x3f;
+                            set_reg!(rd, (rs as u64) << shamt);
+                        }
+                        // Clzw, Ctzw, Cpopw Zbb
+                        (0b0110000, 0b001) if has_ext!(zbb) => match shamt {
+                            // Clzw
+                            0b00000 => {
+                                set_reg!(rd, rs.leading_zeros());
+                            }
+                            // Ctzw
+                            0b00001 => {
+                                set_reg!(rd, rs.trailing_zeros());
+                            }
+                            // Cpopw
+                            0b00010 => {
+                                set_reg!(rd, rs.count_ones());
+                            }
+                            // error?
+                            _ => return Err(Exception::IllegalInstruction(raw)),
+                        },
+                        // Roriw Zbb
+                        (0b0110000, 0b101) if has_ext!(zbb) => {
+                            set_reg!(rd, t_i32!(rs.rotate_right(shamt)));
                         }
 
                         // error?
-                        _ => panic!("unknown instruction occured in i_type branch"),
+                        _ => return Err(Exception::IllegalInstruction(raw)),
                     }
                 }
                 // Addiw
                 0b000 => {
                     let rd = rd!(raw);
                     let rs = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
-                    let mut imm = imm!(I, raw) as i32;
-                    imm = (imm << 21) >> 21;
+                    let imm = imm!(I, raw);
                     set_reg!(rd, rs.wrapping_add(imm));
                 }
                 // error?
-                _ => panic!("unknown instruction occured in i_type RV64I branch"),
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
         }
         // r_type RV32I
@@ -207,7 +340,9 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs1 = rs1!(raw);
                     let rs2 = rs2!(raw);
-                    set_reg!(rd, read_reg!(rs1) << read_reg!(rs2));
+                    // only lower log2(XLEN) bits of rs2 are used
+                    let shamt = read_reg!(rs2) & (xlen!() as u64 - 1);
+                    set_reg!(rd, read_reg!(rs1) << shamt);
                 }
                 // Slt
                 (0b0000000, 0b010) => {
@@ -235,14 +370,16 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs1 = rs1!(raw);
                     let rs2 = rs2!(raw);
-                    set_reg!(rd, read_reg!(rs1) >> read_reg!(rs2));
+                    let shamt = read_reg!(rs2) & (xlen!() as u64 - 1);
+                    set_reg!(rd, wrap_xlen!(read_reg!(rs1)) >> shamt);
                 }
                 // Sra
                 (0b0100000, 0b101) => {
                     let rd = rd!(raw);
                     let rs1 = rs1!(raw);
                     let rs2 = rs2!(raw);
-                    set_reg!(rd, (read_reg!(rs1) as i64) >> (read_reg!(rs2) as i64));
+                    let shamt = read_reg!(rs2) & (xlen!() as u64 - 1);
+                    set_reg!(rd, (read_reg!(rs1) as i64) >> shamt);
                 }
                 // Or
                 (0b0000000, 0b110) => {
@@ -258,33 +395,205 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rs2 = rs2!(raw);
                     set_reg!(rd, read_reg!(rs1) & read_reg!(rs2));
                 }
+                // Sh1add, Sh2add, Sh3add Zba
+                (0b0010000, 0b010 | 0b100 | 0b110) if has_ext!(zba) => {
+                    let rd = rd!(raw);
+                    let rs1 = read_reg!(rs1!(raw));
+                    let rs2 = read_reg!(rs2!(raw));
+                    set_reg!(rd, rs2.wrapping_add(rs1 << (funct3 >> 1)));
+                }
+                // Andn Zbb
+                (0b0100000, 0b111) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)) & !read_reg!(rs2!(raw)));
+                }
+                // Orn Zbb
+                (0b0100000, 0b110) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)) | !read_reg!(rs2!(raw)));
+                }
+                // Xnor Zbb
+                (0b0100000, 0b100) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, !(read_reg!(rs1!(raw)) ^ read_reg!(rs2!(raw))));
+                }
+                // Min Zbb
+                (0b0000101, 0b100) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i64!(read_reg!(rs1!(raw)));
+                    let rs2 = t_i64!(read_reg!(rs2!(raw)));
+                    set_reg!(rd, rs1.min(rs2));
+                }
+                // Minu Zbb
+                (0b0000101, 0b101) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)).min(read_reg!(rs2!(raw))));
+                }
+                // Max Zbb
+                (0b0000101, 0b110) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i64!(read_reg!(rs1!(raw)));
+                    let rs2 = t_i64!(read_reg!(rs2!(raw)));
+                    set_reg!(rd, rs1.max(rs2));
+                }
+                // Maxu Zbb
+                (0b0000101, 0b111) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)).max(read_reg!(rs2!(raw))));
+                }
+                // Rol Zbb
+                (0b0110000, 0b001) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let shamt = read_reg!(rs2!(raw)) as u32 & (xlen!() - 1);
+                    if xlen!() == 32 {
+                        set_reg!(rd, (read_reg!(rs1!(raw)) as u32).rotate_left(shamt));
+                    } else {
+                        set_reg!(rd, read_reg!(rs1!(raw)).rotate_left(shamt));
+                    }
+                }
+                // Ror Zbb
+                (0b0110000, 0b101) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let shamt = read_reg!(rs2!(raw)) as u32 & (xlen!() - 1);
+                    if xlen!() == 32 {
+                        set_reg!(rd, (read_reg!(rs1!(raw)) as u32).rotate_right(shamt));
+                    } else {
+                        set_reg!(rd, read_reg!(rs1!(raw)).rotate_right(shamt));
+                    }
+                }
+                // Zext.h Zbb, RV64 encodes it in r_type RV64I
+                (0b0000100, 0b100) if has_ext!(zbb) && xlen!() == 32 && rs2!(raw) == 0 => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)) & 0xFFFF);
+                }
+                // Clmul, Clmulr, Clmulh Zbc
+                (0b0000101, 0b001..=0b011) if has_ext!(zbc) => {
+                    let rd = rd!(raw);
+                    let xlen = xlen!();
+                    let rs1 = wrap_xlen!(read_reg!(rs1!(raw))) as u128;
+                    let rs2 = wrap_xlen!(read_reg!(rs2!(raw)));
+                    let mut product = 0u128;
+                    for i in 0..xlen {
+                        if rs2 >> i & 1 == 1 {
+                            product ^= rs1 << i;
+                        }
+                    }
+                    match funct3 {
+                        // Clmul
+                        0b001 => {
+                            set_reg!(rd, product as u64);
+                        }
+                        // Clmulr
+                        0b010 => {
+                            set_reg!(rd, (product >> (xlen - 1)) as u64);
+                        }
+                        // Clmulh
+                        _ => {
+                            set_reg!(rd, (product >> xlen) as u64);
+                        }
+                    }
+                }
+                // Bclr Zbs
+                (0b0100100, 0b001) if has_ext!(zbs) => {
+                    let rd = rd!(raw);
+                    let index = read_reg!(rs2!(raw)) & (xlen!() as u64 - 1);
+                    set_reg!(rd, read_reg!(rs1!(raw)) & !(1u64 << index));
+                }
+                // Bext Zbs
+                (0b0100100, 0b101) if has_ext!(zbs) => {
+                    let rd = rd!(raw);
+                    let index = read_reg!(rs2!(raw)) & (xlen!() as u64 - 1);
+                    set_reg!(rd, read_reg!(rs1!(raw)) >> index & 1);
+                }
+                // Binv Zbs
+                (0b0110100, 0b001) if has_ext!(zbs) => {
+                    let rd = rd!(raw);
+                    let index = read_reg!(rs2!(raw)) & (xlen!() as u64 - 1);
+                    set_reg!(rd, read_reg!(rs1!(raw)) ^ (1u64 << index));
+                }
+                // Bset Zbs
+                (0b0010100, 0b001) if has_ext!(zbs) => {
+                    let rd = rd!(raw);
+                    let index = read_reg!(rs2!(raw)) & (xlen!() as u64 - 1);
+                    set_reg!(rd, read_reg!(rs1!(raw)) | (1u64 << index));
+                }
                 // Mul RV32M+RV64M
-                (0000001, 0b000) => {
+                (0b0000001, 0b000) if has_ext!(m) => {
                     let rd = rd!(raw);
                     let rs1 = t_i64!(read_reg!(rs1!(raw)));
                     let rs2 = t_i64!(read_reg!(rs2!(raw)));
                     set_reg!(rd, rs1.wrapping_mul(rs2));
                 }
+                // Mulh RV32M+RV64M
+                (0b0000001, 0b001) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i64!(read_reg!(rs1!(raw))) as i128;
+                    let rs2 = t_i64!(read_reg!(rs2!(raw))) as i128;
+                    set_reg!(rd, (rs1.wrapping_mul(rs2) >> xlen!()) as i64);
+                }
+                // Mulhsu RV32M+RV64M
+                (0b0000001, 0b010) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i64!(read_reg!(rs1!(raw))) as i128;
+                    let rs2 = wrap_xlen!(read_reg!(rs2!(raw))) as i128;
+                    set_reg!(rd, (rs1.wrapping_mul(rs2) >> xlen!()) as i64);
+                }
+                // Mulhu RV32M+RV64M
+                (0b0000001, 0b011) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = wrap_xlen!(read_reg!(rs1!(raw))) as u128;
+                    let rs2 = wrap_xlen!(read_reg!(rs2!(raw))) as u128;
+                    set_reg!(rd, (rs1.wrapping_mul(rs2) >> xlen!()) as u64);
+                }
                 // Div  RV32M+RV64M
-                (0000001, 0b100) => {
+                (0b0000001, 0b100) if has_ext!(m) => {
                     let rd = rd!(raw);
                     let rs1: i64 = t_i64!(read_reg!(rs1!(raw)));
                     let rs2: i64 = t_i64!(read_reg!(rs2!(raw)));
-                    set_reg!(rd, rs1.wrapping_div(rs2));
+                    // division by zero gives -1, overflow (MIN / -1) gives MIN
+                    if rs2 == 0 {
+                        set_reg!(rd, -1);
+                    } else {
+                        set_reg!(rd, rs1.wrapping_div(rs2));
+                    }
+                }
+                // Divu  RV32M+RV64M
+                (0b0000001, 0b101) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = wrap_xlen!(read_reg!(rs1!(raw)));
+                    let rs2 = wrap_xlen!(read_reg!(rs2!(raw)));
+                    // division by zero gives all bits set
+                    set_reg!(rd, rs1.checked_div(rs2).unwrap_or(u64::MAX));
                 }
                 // Rem  RV32M+RV64M
-                (0000001, 0b110) => {
+                (0b0000001, 0b110) if has_ext!(m) => {
                     let rd = rd!(raw);
                     let rs1: i64 = t_i64!(read_reg!(rs1!(raw)));
                     let rs2: i64 = t_i64!(read_reg!(rs2!(raw)));
-                    set_reg!(rd, rs1.wrapping_rem(rs2));
+                    // remainder of division by zero is the dividend, overflow gives 0
+                    if rs2 == 0 {
+                        set_reg!(rd, rs1);
+                    } else {
+                        set_reg!(rd, rs1.wrapping_rem(rs2));
+                    }
+                }
+                // Remu  RV32M+RV64M
+                (0b0000001, 0b111) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = wrap_xlen!(read_reg!(rs1!(raw)));
+                    let rs2 = wrap_xlen!(read_reg!(rs2!(raw)));
+                    if rs2 == 0 {
+                        set_reg!(rd, rs1);
+                    } else {
+                        set_reg!(rd, rs1 % rs2);
+                    }
                 }
                 // error?
-                _ => panic!("unknown instruction"),
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
         }
         // r_type RV64I
-        0b0111011 => {
+        0b0111011 if xlen!() == 64 => {
             let funct3 = op >> 12 & 0x7;
             let funct7 = op >> 25 & 0x7F;
             match (funct7, funct3) {
@@ -300,31 +609,234 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
                     let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
-                    set_reg!(rd, read_reg!(rs1).wrapping_sub(read_reg!(rs2)));
+                    set_reg!(rd, rs1.wrapping_sub(rs2));
                 }
                 // Sllw
                 (0b0000000, 0b001) => {
                     let rd = rd!(raw);
                     let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
                     let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
-                    set_reg!(rd, read_reg!(rs1) << read_reg!(rs2));
+                    set_reg!(rd, t_i32!((rs1 as u32) << (rs2 & 0x1f)));
                 }
-
                 // Srlw
                 (0b0000000, 0b101) => {
                     let rd = rd!(raw);
                     let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
                     let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
-                    set_reg!(rd, read_reg!(rs1) >> read_reg!(rs2));
+                    set_reg!(rd, t_i32!((rs1 as u32) >> (rs2 & 0x1f)));
                 }
                 // Sraw
                 (0b0100000, 0b101) => {
                     let rd = rd!(raw);
                     let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
                     let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
-                    set_reg!(rd, (read_reg!(rs1) as i64) >> (read_reg!(rs2) as i64));
+                    set_reg!(rd, rs1 >> (rs2 & 0x1f));
+                }
+                // Add.uw Zba
+                (0b0000100, 0b000) if has_ext!(zba) => {
+                    let rd = rd!(raw);
+                    let rs1 = read_reg!(rs1!(raw)) & 0xFFFFFFFF;
+                    set_reg!(rd, read_reg!(rs2!(raw)).wrapping_add(rs1));
+                }
+                // Sh1add.uw, Sh2add.uw, Sh3add.uw Zba
+                (0b0010000, 0b010 | 0b100 | 0b110) if has_ext!(zba) => {
+                    let rd = rd!(raw);
+                    let rs1 = read_reg!(rs1!(raw)) & 0xFFFFFFFF;
+                    let rs2 = read_reg!(rs2!(raw));
+                    set_reg!(rd, rs2.wrapping_add(rs1 << (funct3 >> 1)));
+                }
+                // Zext.h Zbb
+                (0b0000100, 0b100) if has_ext!(zbb) && rs2!(raw) == 0 => {
+                    let rd = rd!(raw);
+                    set_reg!(rd, read_reg!(rs1!(raw)) & 0xFFFF);
+                }
+                // Rolw Zbb
+                (0b0110000, 0b001) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let rs1 = (read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32;
+                    let shamt = (read_reg!(rs2!(raw)) & 0x1f) as u32;
+                    set_reg!(rd, t_i32!(rs1.rotate_left(shamt)));
+                }
+                // Rorw Zbb
+                (0b0110000, 0b101) if has_ext!(zbb) => {
+                    let rd = rd!(raw);
+                    let rs1 = (read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32;
+                    let shamt = (read_reg!(rs2!(raw)) & 0x1f) as u32;
+                    set_reg!(rd, t_i32!(rs1.rotate_right(shamt)));
+                }
+                // Mulw RV64M
+                (0b0000001, 0b000) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
+                    let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
+                    set_reg!(rd, rs1.wrapping_mul(rs2));
+                }
+                // Divw RV64M
+                (0b0000001, 0b100) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
+                    let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
+                    if rs2 == 0 {
+                        set_reg!(rd, -1);
+                    } else {
+                        set_reg!(rd, rs1.wrapping_div(rs2));
+                    }
+                }
+                // Divuw RV64M
+                (0b0000001, 0b101) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = (read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32;
+                    let rs2 = (read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32;
+                    // 32 bit result is sign extended, so division by zero gives all bits set
+                    set_reg!(rd, t_i32!(rs1.checked_div(rs2).unwrap_or(u32::MAX)));
+                }
+                // Remw RV64M
+                (0b0000001, 0b110) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = t_i32!((read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32);
+                    let rs2 = t_i32!((read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32);
+                    if rs2 == 0 {
+                        set_reg!(rd, rs1);
+                    } else {
+                        set_reg!(rd, rs1.wrapping_rem(rs2));
+                    }
+                }
+                // Remuw RV64M
+                (0b0000001, 0b111) if has_ext!(m) => {
+                    let rd = rd!(raw);
+                    let rs1 = (read_reg!(rs1!(raw)) & 0xFFFFFFFF) as u32;
+                    let rs2 = (read_reg!(rs2!(raw)) & 0xFFFFFFFF) as u32;
+                    if rs2 == 0 {
+                        set_reg!(rd, t_i32!(rs1));
+                    } else {
+                        set_reg!(rd, t_i32!(rs1 % rs2));
+                    }
                 }
-                _ => panic!("unknown instruction"),
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            }
+        }
+        // atomics RV32A+RV64A
+        0b0101111 if has_ext!(a) => {
+            let funct3 = op >> 12 & 0x7;
+            let funct5 = op >> 27 & 0x1F;
+            let rd = rd!(raw);
+            let addr = wrap_xlen!(read_reg!(rs1!(raw)));
+            let rs2 = read_reg!(rs2!(raw));
+            match funct3 {
+                // word
+                0b010 => {
+                    if addr % 4 != 0 {
+                        return Err(misaligned_atomic(funct5, addr));
+                    }
+                    // reservations are kept on physical addresses
+                    let access = atomic_access(funct5);
+                    let vaddr = addr;
+                    let fault = |e| access.bus_fault(e, vaddr);
+                    let addr = translate(bus, addr, 4, access)? as u64;
+                    match funct5 {
+                        // Lr.w
+                        0b00010 => {
+                            let data = t_i32!(bus.get_u32(addr as usize).map_err(fault)?);
+                            set_reservation!(addr);
+                            set_reg!(rd, data);
+                        }
+                        // Sc.w
+                        0b00011 => {
+                            if take_reservation!() == Some(addr) {
+                                bus.set_u32(addr as usize, (rs2 & 0xFFFFFFFF) as u32)
+                                    .map_err(fault)?;
+                                set_reg!(rd, 0);
+                            } else {
+                                set_reg!(rd, 1);
+                            }
+                        }
+                        _ => {
+                            let old = t_i32!(bus.get_u32(addr as usize).map_err(fault)?);
+                            let src = t_i32!((rs2 & 0xFFFFFFFF) as u32);
+                            let new = match funct5 {
+                                // Amoswap.w
+                                0b00001 => src,
+                                // Amoadd.w
+                                0b00000 => old.wrapping_add(src),
+                                // Amoxor.w
+                                0b00100 => old ^ src,
+                                // Amoand.w
+                                0b01100 => old & src,
+                                // Amoor.w
+                                0b01000 => old | src,
+                                // Amomin.w
+                                0b10000 => old.min(src),
+                                // Amomax.w
+                                0b10100 => old.max(src),
+                                // Amominu.w
+                                0b11000 => t_i32!((old as u32).min(src as u32)),
+                                // Amomaxu.w
+                                0b11100 => t_i32!((old as u32).max(src as u32)),
+                                // error?
+                                _ => return Err(Exception::IllegalInstruction(raw)),
+                            };
+                            bus.set_u32(addr as usize, new as u32).map_err(fault)?;
+                            set_reg!(rd, old);
+                        }
+                    }
+                }
+                // double word RV64A
+                0b011 if xlen!() == 64 => {
+                    if addr % 8 != 0 {
+                        return Err(misaligned_atomic(funct5, addr));
+                    }
+                    // reservations are kept on physical addresses
+                    let access = atomic_access(funct5);
+                    let vaddr = addr;
+                    let fault = |e| access.bus_fault(e, vaddr);
+                    let addr = translate(bus, addr, 8, access)? as u64;
+                    match funct5 {
+                        // Lr.d
+                        0b00010 => {
+                            let data = bus.get_u64(addr as usize).map_err(fault)?;
+                            set_reservation!(addr);
+                            set_reg!(rd, data);
+                        }
+                        // Sc.d
+                        0b00011 => {
+                            if take_reservation!() == Some(addr) {
+                                bus.set_u64(addr as usize, rs2).map_err(fault)?;
+                                set_reg!(rd, 0);
+                            } else {
+                                set_reg!(rd, 1);
+                            }
+                        }
+                        _ => {
+                            let old = bus.get_u64(addr as usize).map_err(fault)?;
+                            let new = match funct5 {
+                                // Amoswap.d
+                                0b00001 => rs2,
+                                // Amoadd.d
+                                0b00000 => old.wrapping_add(rs2),
+                                // Amoxor.d
+                                0b00100 => old ^ rs2,
+                                // Amoand.d
+                                0b01100 => old & rs2,
+                                // Amoor.d
+                                0b01000 => old | rs2,
+                                // Amomin.d
+                                0b10000 => t_u64!(t_i64!(old).min(t_i64!(rs2))),
+                                // Amomax.d
+                                0b10100 => t_u64!(t_i64!(old).max(t_i64!(rs2))),
+                                // Amominu.d
+                                0b11000 => old.min(rs2),
+                                // Amomaxu.d
+                                0b11100 => old.max(rs2),
+                                // error?
+                                _ => return Err(Exception::IllegalInstruction(raw)),
+                            };
+                            bus.set_u64(addr as usize, new).map_err(fault)?;
+                            set_reg!(rd, old);
+                        }
+                    }
+                }
+                // error?
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
         }
         // b_type
@@ -333,33 +845,31 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
             match funct3 {
                 // Beq
                 0b000 => {
-                    branch!(raw, ==);
+                    branch!(raw, ==, ilen);
                 }
                 // Bne
                 0b001 => {
-                    branch!(raw, !=);
+                    branch!(raw, !=, ilen);
                 }
                 // Blt
                 0b100 => {
-                    branch!(raw, <, int);
+                    branch!(raw, <, ilen, int);
                 }
                 // Bge
                 0b101 => {
-                    branch!(raw, >=, int);
+                    branch!(raw, >=, ilen, int);
                 }
                 // Bltu
                 0b110 => {
-                    branch!(raw, <);
+                    branch!(raw, <, ilen);
                 }
                 // Bgeu
                 0b111 => {
-                    branch!(raw, >=);
+                    branch!(raw, >=, ilen);
                 }
 
                 // error?
-                _ => {
-                    panic!("unknown instruction")
-                }
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
         }
         // fence
@@ -371,26 +881,89 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
         0b00000000000000000001000000001111 => {
             println!("fence.i");
         }
-        //calls
-        // Ecall
-        0b00000000000000000000000001110011 => match (read_reg!(A0), read_reg!(A7)) {
-            (1, 64) => {
-                let addr = read_reg!(A1) as usize;
-                let len = read_reg!(A2) as usize;
-                let s = String::from_utf8_lossy(&dram[addr..addr + len]);
-                if DEBUG {
-                    println!("{:x}", addr);
-                    println!("ecall: print\n{:?}", s);
-                } else {
-                    print!("{}", s);
+        // system
+        0b1110011 => {
+            let funct3 = op >> 12 & 0x7;
+            match funct3 {
+                //calls
+                0b000 => match op {
+                    // Ecall
+                    0b00000000000000000000000001110011 => {
+                        return Err(Exception::EnvironmentCall(current_privilege()));
+                    }
+                    // Ebreak
+                    0b00000000000100000000000001110011 => {
+                        return Err(Exception::Breakpoint(get_pc!()));
+                    }
+                    // Mret
+                    0b00110000001000000000000001110011 => {
+                        let target = trap::mret().ok_or(Exception::IllegalInstruction(raw))?;
+                        jump(target, ilen)?;
+                    }
+                    // Sret
+                    0b00010000001000000000000001110011 => {
+                        let target = trap::sret().ok_or(Exception::IllegalInstruction(raw))?;
+                        jump(target, ilen)?;
+                    }
+                    // Wfi, there is nothing to wait for so it's a nop
+                    0b00010000010100000000000001110011 => {
+                        let privilege = current_privilege();
+                        let tw = read_csr_raw(MSTATUS) & MSTATUS_TW != 0;
+                        if privilege == USER || (tw && privilege < MACHINE) {
+                            return Err(Exception::IllegalInstruction(raw));
+                        }
+                    }
+                    // Sfence.vma
+                    _ if op >> 25 == 0b0001001 && rd!(raw) == 0 => {
+                        let privilege = current_privilege();
+                        let tvm = read_csr_raw(MSTATUS) & MSTATUS_TVM != 0;
+                        if privilege == USER || (tvm && privilege == SUPERVISOR) {
+                            return Err(Exception::IllegalInstruction(raw));
+                        }
+                        // x0 in rs1 or rs2 means all addresses or all ASIDs
+                        let (rs1, rs2) = (rs1!(raw), rs2!(raw));
+                        let vaddr = (rs1 != 0).then(|| wrap_xlen!(read_reg!(rs1)));
+                        let asid = (rs2 != 0).then(|| read_reg!(rs2));
+                        tlb::flush(vaddr, asid);
+                    }
+                    // error?
+                    _ => return Err(Exception::IllegalInstruction(raw)),
+                },
+                // Csrrw, Csrrs, Csrrc, Csrrwi, Csrrsi, Csrrci
+                0b001 | 0b010 | 0b011 | 0b101 | 0b110 | 0b111 => {
+                    let addr = (op >> 20) as u16;
+                    let rd = rd!(raw);
+                    let rs1 = rs1!(raw);
+                    // immediate forms use rs1 field as 5 bit unsigned value
+                    let src = if funct3 & 0b100 != 0 {
+                        rs1 as u64
+                    } else {
+                        read_reg!(rs1)
+                    };
+                    // Csrrw with rd == x0 doesn't read the CSR
+                    let old = if funct3 & 0b11 == 0b01 && rd == 0 {
+                        0
+                    } else {
+                        read_csr(addr).ok_or(Exception::IllegalInstruction(raw))?
+                    };
+                    // Csrrs and Csrrc with rs1 == x0 don't write the CSR
+                    let new = match funct3 & 0b11 {
+                        0b01 => Some(src),
+                        0b10 if rs1 != 0 => Some(old | src),
+                        0b11 if rs1 != 0 => Some(old & !src),
+                        _ => None,
+                    };
+                    if let Some(new) = new {
+                        if !write_csr(addr, new) {
+                            return Err(Exception::IllegalInstruction(raw));
+                        }
+                    }
+                    set_reg!(rd, old);
                 }
+                // error?
+                _ => return Err(Exception::IllegalInstruction(raw)),
             }
-            _ => {
-                panic!("unknown syscall");
-            }
-        },
-        // Ebreak
-        0b00000000000100000000000001110011 => {}
+        }
         // loads
         0b0000011 => {
             let funct3 = op >> 12 & 0x7;
@@ -400,10 +973,8 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let data = unsafe {
-                        transmute::<u8, i8>(dram.get_u8(rs.wrapping_add(imm as u64) as usize))
-                            as i64
-                    };
+                    let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+                    let data = load(bus, addr, 1, Access::Load)? as u8 as i8;
                     set_reg!(rd, data);
                 }
                 // Lh
@@ -411,9 +982,8 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let data = unsafe {
-                        transmute::<u16, i16>(dram.get_u16(rs.wrapping_add(imm as u64) as usize))
-                    };
+                    let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+                    let data = load(bus, addr, 2, Access::Load)? as u16 as i16;
                     set_reg!(rd, data);
                 }
                 // Lw
@@ -421,27 +991,26 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let data = unsafe {
-                        transmute::<u32, i32>(dram.get_u32(rs.wrapping_add(imm as u64) as usize))
-                    };
+                    let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+                    let data = load(bus, addr, 4, Access::Load)? as u32 as i32;
                     set_reg!(rd, data);
                 }
-                // Lwu
-                0b110 => {
+                // Lwu RV64I
+                0b110 if xlen!() == 64 => {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let data = dram.get_u32(rs.wrapping_add(imm as u64) as usize);
+                    let addr = rs.wrapping_add(imm as u64);
+                    let data = load(bus, addr, 4, Access::Load)? as u32;
                     set_reg!(rd, data);
                 }
-                // Ld
-                0b011 => {
+                // Ld RV64I
+                0b011 if xlen!() == 64 => {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let data = unsafe {
-                        transmute::<u64, i64>(dram.get_u64(rs.wrapping_add(imm as u64) as usize))
-                    };
+                    let addr = rs.wrapping_add(imm as u64);
+                    let data = load(bus, addr, 8, Access::Load)? as i64;
                     set_reg!(rd, data);
                 }
                 // Lbu
@@ -449,20 +1018,20 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let addr = (rs.wrapping_add(imm as u64)) as usize;
-                    set_reg!(rd, dram.get_u8(addr));
+                    let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+                    set_reg!(rd, load(bus, addr, 1, Access::Load)? as u8);
                 }
                 // Lhu
                 0b101 => {
                     let rd = rd!(raw);
                     let rs = read_reg!(rs1!(raw));
                     let imm = imm!(I, raw);
-                    let addr = (rs.wrapping_add(imm as u64)) as usize;
-                    set_reg!(rd, dram.get_u16(addr));
+                    let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+                    set_reg!(rd, load(bus, addr, 2, Access::Load)? as u16);
                 }
                 // error?
                 _ => {
-                    panic!("unknown instruction: {:x}", op);
+                    return Err(Exception::IllegalInstruction(raw));
                 }
             }
         }
@@ -475,48 +1044,494 @@ pub fn execute_32(op: u32, dram: &mut Dram) {
                     let rs1 = read_reg!(rs1!(raw));
                     let rs2 = read_reg!(rs2!(raw));
                     let imm = imm!(S, raw);
-                    let addr = (rs1 + imm as u64) as usize;
+                    let addr = wrap_xlen!(rs1.wrapping_add(imm as u64));
                     let val = (rs2 & 0xFF) as u8;
-                    dram.set_u8(addr, val);
+                    store(bus, addr, 1, val as u64)?;
                 }
                 // Sh
                 0b001 => {
                     let rs1 = read_reg!(rs1!(raw));
                     let rs2 = read_reg!(rs2!(raw));
                     let imm = imm!(S, raw);
-                    let addr = (rs1 + imm as u64) as usize;
+                    let addr = wrap_xlen!(rs1.wrapping_add(imm as u64));
                     let val = (rs2 & 0xFFFF) as u16;
-                    dram.set_u16(addr, val);
+                    store(bus, addr, 2, val as u64)?;
                 }
                 // Sw
                 0b010 => {
                     let rs1 = read_reg!(rs1!(raw));
                     let rs2 = read_reg!(rs2!(raw));
                     let imm = imm!(S, raw);
-                    let addr = (rs1 + imm as u64) as usize;
+                    let addr = wrap_xlen!(rs1.wrapping_add(imm as u64));
                     let val = (rs2 & 0xFFFFFFFF) as u32;
-                    dram.set_u32(addr, val);
+                    store(bus, addr, 4, val as u64)?;
                 }
-                // Sd
-                0b011 => {
+                // Sd RV64I
+                0b011 if xlen!() == 64 => {
                     let rs1 = read_reg!(rs1!(raw));
                     let rs2 = read_reg!(rs2!(raw));
-                    let imm = imm!(S, raw) as u64;
-                    let addr = (rs1 + imm) as usize;
-                    dram.set_u64(addr, rs2);
+                    let imm = imm!(S, raw);
+                    let addr = wrap_xlen!(rs1.wrapping_add(imm as u64));
+                    store(bus, addr, 8, rs2)?;
                 }
 
                 // error?
                 _ => {
-                    panic!("unknown instruction: {:x}", op);
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+            }
+        }
+        // floating point loads RV32F+RV32D, vector loads RVV
+        0b0000111 if has_ext!(f) || has_ext!(v) => {
+            let funct3 = op >> 12 & 0x7;
+            let rd = rd!(raw);
+            let rs = read_reg!(rs1!(raw));
+            // 12 bit sign extended offset
+            let imm = raw as i32 >> 20;
+            let addr = wrap_xlen!(rs.wrapping_add(imm as u64));
+            match funct3 {
+                // Flw
+                0b010 if has_ext!(f) => {
+                    let data = load(bus, addr, 4, Access::Load)? as u32;
+                    set_freg!(rd, box_f32(f32::from_bits(data)));
+                }
+                // Fld
+                0b011 if has_ext!(d) => {
+                    let data = load(bus, addr, 8, Access::Load)?;
+                    set_freg!(rd, data);
+                }
+                // Vle, Vlse, Vluxei, Vloxei, Vlseg, Vlm, Vl<nf>r
+                0b000 | 0b101 | 0b110 | 0b111 if has_ext!(v) => {
+                    execute_vector_memory(raw, bus, false)?;
+                }
+                // error?
+                _ => {
+                    return Err(Exception::IllegalInstruction(raw));
                 }
             }
         }
+        // floating point stores RV32F+RV32D, vector stores RVV
+        0b0100111 if has_ext!(f) || has_ext!(v) => {
+            let funct3 = op >> 12 & 0x7;
+            let rs1 = read_reg!(rs1!(raw));
+            let rs2 = read_freg!(rs2!(raw));
+            let imm = imm!(S, raw);
+            let addr = wrap_xlen!(rs1.wrapping_add(imm as u64));
+            match funct3 {
+                // Fsw
+                0b010 if has_ext!(f) => {
+                    store(bus, addr, 4, rs2)?;
+                }
+                // Fsd
+                0b011 if has_ext!(d) => {
+                    store(bus, addr, 8, rs2)?;
+                }
+                // Vse, Vsse, Vsuxei, Vsoxei, Vsseg, Vsm, Vs<nf>r
+                0b000 | 0b101 | 0b110 | 0b111 if has_ext!(v) => {
+                    execute_vector_memory(raw, bus, true)?;
+                }
+                // error?
+                _ => {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+            }
+        }
+        // fused multiply-add RV32F+RV32D
+        0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 if has_ext!(f) => {
+            execute_fma(raw)?;
+        }
+        // vector operations and configuration RVV
+        0b1010111 if has_ext!(v) => {
+            execute_vector(raw)?;
+        }
+        // floating point operations RV32F+RV32D+RV64F+RV64D
+        0b1010011 if has_ext!(f) => {
+            execute_op_fp(raw)?;
+        }
         // error?
         _ => {
-            panic!("unknown instruction: {:x}", op);
+            return Err(Exception::IllegalInstruction(raw));
         }
     }
 
-    inc_pc!(4);
+    inc_pc!(ilen);
+    Ok(())
+}
+
+// LR reports misaligned address as load, SC and AMOs as store
+fn misaligned_atomic(funct5: u32, addr: u64) -> Exception {
+    if funct5 == 0b00010 {
+        Exception::LoadAddressMisaligned(addr)
+    } else {
+        Exception::StoreAddressMisaligned(addr)
+    }
+}
+
+// LR is translated as load, SC and AMOs as store
+fn atomic_access(funct5: u32) -> Access {
+    if funct5 == 0b00010 {
+        Access::Load
+    } else {
+        Access::Store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{csr::init_csrs, dram::Dram, isa::parse_isa};
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const RAM_SIZE: u64 = 0x10000;
+
+    // div a0, a1, a2
+    const DIV: u32 = 0x02c5c533;
+    // divu a0, a1, a2
+    const DIVU: u32 = 0x02c5d533;
+    // rem a0, a1, a2
+    const REM: u32 = 0x02c5e533;
+    // remu a0, a1, a2
+    const REMU: u32 = 0x02c5f533;
+    // divw a0, a1, a2
+    const DIVW: u32 = 0x02c5c53b;
+    // divuw a0, a1, a2
+    const DIVUW: u32 = 0x02c5d53b;
+    // remw a0, a1, a2
+    const REMW: u32 = 0x02c5e53b;
+    // remuw a0, a1, a2
+    const REMUW: u32 = 0x02c5f53b;
+    // mulh a0, a1, a2
+    const MULH: u32 = 0x02c59533;
+    // mulhsu a0, a1, a2
+    const MULHSU: u32 = 0x02c5a533;
+    // mulhu a0, a1, a2
+    const MULHU: u32 = 0x02c5b533;
+    // mulw a0, a1, a2
+    const MULW: u32 = 0x02c5853b;
+
+    // lr.w a0, (a1)
+    const LR_W: u32 = 0x1005a52f;
+    // sc.w a3, a2, (a1)
+    const SC_W: u32 = 0x18c5a6af;
+    // lr.d a0, (a1)
+    const LR_D: u32 = 0x1005b52f;
+    // sc.d a3, a2, (a1)
+    const SC_D: u32 = 0x18c5b6af;
+    // amoadd.w a0, a2, (a1)
+    const AMOADD_W: u32 = 0x00c5a52f;
+    // amomin.w a0, a2, (a1)
+    const AMOMIN_W: u32 = 0x80c5a52f;
+    // amomaxu.d a0, a2, (a1)
+    const AMOMAXU_D: u32 = 0xe0c5b52f;
+    // amoswap.d a0, a2, (a1)
+    const AMOSWAP_D: u32 = 0x08c5b52f;
+
+    // clz a0, a1
+    const CLZ: u32 = 0x60059513;
+    // clzw a0, a1
+    const CLZW: u32 = 0x6005951b;
+    // ctz a0, a1
+    const CTZ: u32 = 0x60159513;
+    // cpop a0, a1
+    const CPOP: u32 = 0x60259513;
+    // rev8 a0, a1
+    const REV8: u32 = 0x6b85d513;
+    // orc.b a0, a1
+    const ORC_B: u32 = 0x2875d513;
+    // clmul a0, a1, a2
+    const CLMUL: u32 = 0x0ac59533;
+    // clmulh a0, a1, a2
+    const CLMULH: u32 = 0x0ac5b533;
+    // clmulr a0, a1, a2
+    const CLMULR: u32 = 0x0ac5a533;
+    // sh1add.uw a0, a1, a2
+    const SH1ADD_UW: u32 = 0x20c5a53b;
+    // add.uw a0, a1, a2
+    const ADD_UW: u32 = 0x08c5853b;
+    // slli.uw a0, a1, 3
+    const SLLI_UW: u32 = 0x0835951b;
+    // rori a0, a1, 4
+    const RORI: u32 = 0x6045d513;
+    // roriw a0, a1, 4
+    const RORIW: u32 = 0x6045d51b;
+    // bseti a0, a1, 63
+    const BSETI: u32 = 0x2bf59513;
+    // bext a0, a1, a2
+    const BEXT: u32 = 0x48c5d533;
+    // sext.b a0, a1
+    const SEXT_B: u32 = 0x60459513;
+    // zext.h a0, a1
+    const ZEXT_H: u32 = 0x0805c53b;
+    // min a0, a1, a2
+    const MIN: u32 = 0x0ac5c533;
+    // maxu a0, a1, a2
+    const MAXU: u32 = 0x0ac5f533;
+    // andn a0, a1, a2
+    const ANDN: u32 = 0x40c5f533;
+
+    // M mode hart with DRAM at RAM_BASE
+    fn setup(isa: &str) -> Bus {
+        let isa = parse_isa(isa).unwrap();
+        init_csrs(&isa);
+        ISA.with(|x| *x.borrow_mut() = isa);
+        let mut bus = Bus::new();
+        bus.register(
+            "dram",
+            RAM_BASE,
+            RAM_SIZE,
+            Box::new(Dram::new_dram(RAM_SIZE as usize)),
+        )
+        .unwrap();
+        bus
+    }
+
+    // a0 after executing raw with a1 and a2 set to the operands
+    fn op(bus: &mut Bus, raw: u32, a: u64, b: u64) -> u64 {
+        set_reg!(A1, a);
+        set_reg!(A2, b);
+        execute_32(raw, bus).unwrap();
+        read_reg!(A0)
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut bus = setup("rv64gc");
+        let cases = [
+            // quotient has all bits set, remainder is the dividend
+            (DIV, 7, u64::MAX),
+            (DIVU, 7, u64::MAX),
+            (REM, 7, 7),
+            (REMU, 7, 7),
+            (DIVW, 7, u64::MAX),
+            (DIVUW, 7, u64::MAX),
+            // word results are sign extended from bit 31 of the dividend
+            (REMW, 0x1_8000_0000, 0xFFFF_FFFF_8000_0000),
+            (REMUW, 0x1_0000_0005, 5),
+        ];
+        for (raw, a, res) in cases {
+            assert_eq!(op(&mut bus, raw, a, 0), res, "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn division_overflow() {
+        let mut bus = setup("rv64gc");
+        let min = i64::MIN as u64;
+        assert_eq!(op(&mut bus, DIV, min, u64::MAX), min);
+        assert_eq!(op(&mut bus, REM, min, u64::MAX), 0);
+        let min_w = i32::MIN as i64 as u64;
+        assert_eq!(op(&mut bus, DIVW, min_w, u64::MAX), min_w);
+        assert_eq!(op(&mut bus, REMW, min_w, u64::MAX), 0);
+        // unsigned division of the same operands doesn't overflow
+        assert_eq!(op(&mut bus, DIVU, min, u64::MAX), 0);
+        assert_eq!(op(&mut bus, REMU, min, u64::MAX), min);
+        // signed division rounds towards zero
+        assert_eq!(op(&mut bus, DIV, -7i64 as u64, 2), -3i64 as u64);
+        assert_eq!(op(&mut bus, REM, -7i64 as u64, 2), -1i64 as u64);
+    }
+
+    #[test]
+    fn multiply_high() {
+        let mut bus = setup("rv64gc");
+        let min = i64::MIN as u64;
+        assert_eq!(op(&mut bus, MULH, u64::MAX, u64::MAX), 0);
+        assert_eq!(op(&mut bus, MULH, min, min), 1 << 62);
+        assert_eq!(op(&mut bus, MULHSU, u64::MAX, u64::MAX), u64::MAX);
+        assert_eq!(op(&mut bus, MULHU, u64::MAX, u64::MAX), u64::MAX - 1);
+        assert_eq!(op(&mut bus, MULW, 0x7FFF_FFFF, 2), 0xFFFF_FFFF_FFFF_FFFE);
+    }
+
+    #[test]
+    fn rv32_division() {
+        let mut bus = setup("rv32gc");
+        // registers hold sign extended values
+        let min = i32::MIN as i64 as u64;
+        assert_eq!(op(&mut bus, DIV, min, u64::MAX), min);
+        assert_eq!(op(&mut bus, REM, min, u64::MAX), 0);
+        assert_eq!(op(&mut bus, DIVU, 7, 0), u64::MAX);
+        assert_eq!(
+            op(&mut bus, MULHU, u64::MAX, u64::MAX),
+            0xFFFF_FFFF_FFFF_FFFE
+        );
+        // word instructions exist only in RV64
+        assert_eq!(
+            execute_32(DIVW, &mut bus),
+            Err(Exception::IllegalInstruction(DIVW))
+        );
+    }
+
+    // executes atomic with a1 = addr and a2 = src, returns a0 and a3
+    fn atomic(bus: &mut Bus, raw: u32, addr: u64, src: u64) -> (u64, u64) {
+        set_reg!(A0, 0);
+        set_reg!(A3, 0xDEAD);
+        op(bus, raw, addr, src);
+        (read_reg!(A0), read_reg!(A3))
+    }
+
+    #[test]
+    fn lr_sc() {
+        let mut bus = setup("rv64gc");
+        bus.set_u32(RAM_BASE as usize, 0x8000_0001).unwrap();
+        // loaded word is sign extended
+        assert_eq!(atomic(&mut bus, LR_W, RAM_BASE, 0).0, 0xFFFF_FFFF_8000_0001);
+        assert_eq!(atomic(&mut bus, SC_W, RAM_BASE, 5).1, 0);
+        assert_eq!(bus.get_u32(RAM_BASE as usize), Ok(5));
+        // reservation is used up by the first sc
+        assert_eq!(atomic(&mut bus, SC_W, RAM_BASE, 6).1, 1);
+        assert_eq!(bus.get_u32(RAM_BASE as usize), Ok(5));
+
+        let addr = RAM_BASE + 8;
+        atomic(&mut bus, LR_D, addr, 0);
+        assert_eq!(atomic(&mut bus, SC_D, addr, u64::MAX).1, 0);
+        assert_eq!(bus.get_u64(addr as usize), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn reservation_loss() {
+        let mut bus = setup("rv64gc");
+        let (a, b) = (RAM_BASE, RAM_BASE + 0x100);
+        // sc to other address fails and drops the reservation
+        atomic(&mut bus, LR_W, a, 0);
+        assert_eq!(atomic(&mut bus, SC_W, b, 1).1, 1);
+        assert_eq!(atomic(&mut bus, SC_W, a, 1).1, 1);
+        assert_eq!(bus.get_u32(a as usize), Ok(0));
+        assert_eq!(bus.get_u32(b as usize), Ok(0));
+        // newer lr replaces the reservation
+        atomic(&mut bus, LR_W, a, 0);
+        atomic(&mut bus, LR_W, b, 0);
+        assert_eq!(atomic(&mut bus, SC_W, b, 1).1, 0);
+        atomic(&mut bus, LR_W, a, 0);
+        atomic(&mut bus, LR_W, b, 0);
+        assert_eq!(atomic(&mut bus, SC_W, a, 1).1, 1);
+        // and the failed sc drops it too
+        assert_eq!(atomic(&mut bus, SC_W, b, 1).1, 1);
+        // sc without any lr
+        assert_eq!(atomic(&mut bus, SC_D, a, 1).1, 1);
+    }
+
+    #[test]
+    fn atomics_check_alignment() {
+        let mut bus = setup("rv64gc");
+        let addr = RAM_BASE + 4;
+        set_reg!(A1, addr);
+        assert_eq!(
+            execute_32(LR_D, &mut bus),
+            Err(Exception::LoadAddressMisaligned(addr))
+        );
+        assert_eq!(
+            execute_32(SC_D, &mut bus),
+            Err(Exception::StoreAddressMisaligned(addr))
+        );
+        assert_eq!(
+            execute_32(AMOSWAP_D, &mut bus),
+            Err(Exception::StoreAddressMisaligned(addr))
+        );
+        set_reg!(A1, addr + 2);
+        assert_eq!(
+            execute_32(AMOADD_W, &mut bus),
+            Err(Exception::StoreAddressMisaligned(addr + 2))
+        );
+        // unmapped address faults as store for AMOs
+        set_reg!(A1, 0x1000);
+        assert_eq!(
+            execute_32(AMOADD_W, &mut bus),
+            Err(Exception::StoreAccessFault(0x1000))
+        );
+    }
+
+    #[test]
+    fn amo_ops() {
+        let mut bus = setup("rv64gc");
+        let addr = RAM_BASE;
+        bus.set_u32(addr as usize, 0x7FFF_FFFF).unwrap();
+        // old value is returned and the sum wraps within the word
+        assert_eq!(atomic(&mut bus, AMOADD_W, addr, 1).0, 0x7FFF_FFFF);
+        assert_eq!(bus.get_u32(addr as usize), Ok(0x8000_0000));
+        // signed minimum of the words
+        assert_eq!(atomic(&mut bus, AMOMIN_W, addr, 3).0, 0xFFFF_FFFF_8000_0000);
+        assert_eq!(bus.get_u32(addr as usize), Ok(0x8000_0000));
+
+        let addr = RAM_BASE + 8;
+        bus.set_u64(addr as usize, 1 << 63).unwrap();
+        assert_eq!(atomic(&mut bus, AMOMAXU_D, addr, 5).0, 1 << 63);
+        assert_eq!(bus.get_u64(addr as usize), Ok(1 << 63));
+        assert_eq!(atomic(&mut bus, AMOSWAP_D, addr, 5).0, 1 << 63);
+        assert_eq!(bus.get_u64(addr as usize), Ok(5));
+    }
+
+    fn check(bus: &mut Bus, cases: &[(u32, u64, u64, u64)]) {
+        for &(raw, a, b, res) in cases {
+            assert_eq!(op(bus, raw, a, b), res, "{:#010x} {:#x} {:#x}", raw, a, b);
+        }
+    }
+
+    #[test]
+    fn bit_manipulation() {
+        let mut bus = setup("rv64gc_zba_zbb_zbc_zbs");
+        let min = i64::MIN as u64;
+        check(
+            &mut bus,
+            &[
+                (CLZ, 0, 0, 64),
+                (CLZ, u64::MAX, 0, 0),
+                (CLZW, 0, 0, 32),
+                // upper word is ignored
+                (CLZW, 0xFFFF_FFFF_0000_0001, 0, 31),
+                (CTZ, 0, 0, 64),
+                (CTZ, min, 0, 63),
+                (CPOP, u64::MAX, 0, 64),
+                (REV8, 0x0102_0304_0506_0708, 0, 0x0807_0605_0403_0201),
+                (ORC_B, 0x0100_0000_0000_8000, 0, 0xFF00_0000_0000_FF00),
+                (CLMUL, 3, 3, 5),
+                (CLMULH, min, 2, 1),
+                (CLMULH, u64::MAX, u64::MAX, 0x5555_5555_5555_5555),
+                (CLMULR, min, 2, 2),
+                (SH1ADD_UW, 0xFFFF_FFFF_8000_0000, 1, 0x1_0000_0001),
+                (ADD_UW, u64::MAX, 1, 0x1_0000_0000),
+                (SLLI_UW, 0xFFFF_FFFF_8000_0001, 0, 0x4_0000_0008),
+                (RORI, 1, 0, 1 << 60),
+                // word rotate sign extends bit 31
+                (RORIW, 0x1_0000_0008, 0, 0xFFFF_FFFF_8000_0000),
+                (BSETI, 0, 0, min),
+                // bit index is taken modulo XLEN
+                (BEXT, min, 127, 1),
+                (SEXT_B, 0x80, 0, 0xFFFF_FFFF_FFFF_FF80),
+                (ZEXT_H, 0xFFFF_1234_5678, 0, 0x5678),
+                (MIN, u64::MAX, 1, u64::MAX),
+                (MAXU, u64::MAX, 1, u64::MAX),
+                (ANDN, 0xFF, 0x0F, 0xF0),
+            ],
+        );
+    }
+
+    #[test]
+    fn rv32_bit_manipulation() {
+        let mut bus = setup("rv32gc_zbb_zbc");
+        check(
+            &mut bus,
+            &[
+                (CLZ, 0, 0, 32),
+                (CTZ, 0, 0, 32),
+                (CPOP, u64::MAX, 0, 32),
+                (CLMULH, u64::MAX, u64::MAX, 0x5555_5555),
+            ],
+        );
+    }
+
+    #[test]
+    fn extensions_are_gated() {
+        let mut bus = setup("rv64gc_zba");
+        assert_eq!(op(&mut bus, SH1ADD_UW, 1, 1), 3);
+        for raw in [CLZ, ANDN, CLMUL, BSETI] {
+            assert_eq!(
+                execute_32(raw, &mut bus),
+                Err(Exception::IllegalInstruction(raw))
+            );
+        }
+        let mut bus = setup("rv64gc");
+        assert_eq!(
+            execute_32(ADD_UW, &mut bus),
+            Err(Exception::IllegalInstruction(ADD_UW))
+        );
+    }
 }
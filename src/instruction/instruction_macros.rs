@@ -16,21 +16,21 @@ pub fn __extract_branch(raw: u32) -> (i32, u32, u32) {
 
 #[macro_export]
 macro_rules! branch {
-    ($raw: expr, $e: tt) => {
-        use crate::instruction::instruction_macros::__extract_branch;
+    ($raw: expr, $e: tt, $ilen: expr) => {
+        use $crate::instruction::instruction_macros::__extract_branch;
         let (imm, rs1, rs2) = __extract_branch($raw as u32);
-        
-        if (crate::read_reg!(rs1)) $e (crate::read_reg!(rs2)) {
-            let temp_pc = crate::get_pc!() as i64;
-            crate::set_pc!(temp_pc.wrapping_add(imm  as i64).wrapping_sub(4));
+
+        if ($crate::read_reg!(rs1)) $e ($crate::read_reg!(rs2)) {
+            let target = ($crate::get_pc!() as i64).wrapping_add(imm as i64);
+            $crate::instruction::instruction::jump(target as u64, $ilen)?;
         }
     };
-    ($raw: expr, $e: tt, int) => {
-        use crate::instruction::instruction_macros::__extract_branch;
+    ($raw: expr, $e: tt, $ilen: expr, int) => {
+        use $crate::instruction::instruction_macros::__extract_branch;
         let (imm, rs1, rs2) = __extract_branch($raw as u32);
-        if (t_i64!(crate::read_reg!(rs1))) $e (t_i64!(crate::read_reg!(rs2))) {
-            let temp_pc = crate::get_pc!() as i64;
-            crate::set_pc!(temp_pc.wrapping_add(imm as i64).wrapping_sub(4));
+        if (t_i64!($crate::read_reg!(rs1))) $e (t_i64!($crate::read_reg!(rs2))) {
+            let target = ($crate::get_pc!() as i64).wrapping_add(imm as i64);
+            $crate::instruction::instruction::jump(target as u64, $ilen)?;
         }
     };
 }
@@ -58,41 +58,47 @@ macro_rules! rs2 {
 
 #[macro_export]
 macro_rules! imm {
+    // sign extended 12 bit immediate
     (I, $raw: expr) => {
-        ($raw as u32 >> 20) & 0x7FF
+        ($raw as i32 >> 20)
     };
     (U, $raw: expr) => {
-        ($raw as u32 >> 12) & 0x7FFFF
+        ($raw as u32 >> 12) & 0xFFFFF
     };
+    // sign extended 12 bit immediate
     (S, $raw:expr) => {
-        (($raw & 0xfe000000) >> 20) | (($raw >> 7) & 0x1f)
+        (($raw & 0xfe000000) as i32 >> 20) | (($raw >> 7) & 0x1f) as i32
     };
 }
 
 #[macro_export]
 macro_rules! t_i64 {
-    ($val: expr) => {
-        unsafe { std::mem::transmute::<u64, i64>($val) }
-    };
+    ($val: expr) => {{
+        let val: u64 = $val;
+        val as i64
+    }};
 }
 
 #[macro_export]
 macro_rules! t_u64 {
-    ($val: expr) => {
-        unsafe{std::mem::transmute::<i64, u64>($val)}
-    };
+    ($val: expr) => {{
+        let val: i64 = $val;
+        val as u64
+    }};
 }
 
 #[macro_export]
 macro_rules! t_i32 {
-    ($val: expr) => {
-        unsafe { std::mem::transmute::<u32, i32>($val) }
-    };
+    ($val: expr) => {{
+        let val: u32 = $val;
+        val as i32
+    }};
 }
 
 #[macro_export]
 macro_rules! t_u32 {
-    ($val: expr) => {
-        unsafe{std::mem::transmute::<i32, u32>($val)}
-    };
+    ($val: expr) => {{
+        let val: i32 = $val;
+        val as u32
+    }};
 }
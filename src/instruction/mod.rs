@@ -1,2 +1,7 @@
+pub mod compressed;
+pub mod float;
+// decoder and executor of the base ISA, named after the directory it lives in
+#[allow(clippy::module_inception)]
 pub mod instruction;
-pub mod instruction_macros;
\ No newline at end of file
+pub mod instruction_macros;
+pub mod vector;
\ No newline at end of file
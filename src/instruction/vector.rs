@@ -0,0 +1,1581 @@
+#![allow(unused_unsafe)]
+// V extension
+// vector registers are one byte array, register vN starts at N * VLENB so elements of a register
+// group are contiguous, masked off and tail elements are always left undisturbed
+use crate::{
+    bus::Bus,
+    csr::{read_csr_raw, set_csr_raw, VCSR, VL, VLENB, VSTART, VTYPE},
+    mmu::{load, store, Access},
+    trap::Exception,
+    *,
+};
+
+// vtype.vill is the highest bit of XLEN wide vtype
+#[inline(always)]
+fn vill() -> u64 {
+    1 << (xlen!() - 1)
+}
+
+// selected element width in bits and register group multiplier as a fraction
+#[derive(Debug, Clone, Copy)]
+struct VType {
+    sew: u32,
+    lmul_num: usize,
+    lmul_den: usize,
+}
+
+// source of the first operand: vector register, scalar register or immediate
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    V(u32),
+    S(u64),
+}
+
+#[inline(always)]
+fn vlenb() -> usize {
+    read_csr_raw(VLENB) as usize
+}
+
+#[inline(always)]
+fn mask(sew: u32) -> u64 {
+    if sew == 64 {
+        u64::MAX
+    } else {
+        (1 << sew) - 1
+    }
+}
+
+#[inline(always)]
+fn sext(val: u64, sew: u32) -> i64 {
+    ((val << (64 - sew)) as i64) >> (64 - sew)
+}
+
+fn decode_vtype(vtype: u64) -> Option<VType> {
+    if vtype & vill() != 0 || vtype >> 8 != 0 {
+        return None;
+    }
+    let vsew = (vtype >> 3 & 0x7) as u32;
+    if vsew > 3 {
+        return None;
+    }
+    let sew = 8 << vsew;
+    let (lmul_num, lmul_den) = match vtype & 0x7 {
+        0b000 => (1, 1),
+        0b001 => (2, 1),
+        0b010 => (4, 1),
+        0b011 => (8, 1),
+        0b101 => (1, 8),
+        0b110 => (1, 4),
+        0b111 => (1, 2),
+        _ => return None,
+    };
+    // fractional LMUL has to hold at least one element of ELEN = 64
+    if sew as usize * lmul_den > 64 {
+        return None;
+    }
+    Some(VType {
+        sew,
+        lmul_num,
+        lmul_den,
+    })
+}
+
+fn vlmax(vt: VType) -> usize {
+    vlenb() * 8 * vt.lmul_num / vt.lmul_den / vt.sew as usize
+}
+
+// number of registers in a group of elements with given width
+fn group_regs(vt: VType, eew: u32) -> usize {
+    (eew as usize * vt.lmul_num / (vt.sew as usize * vt.lmul_den)).max(1)
+}
+
+// vtype and vl of the current configuration, vill makes every vector instruction illegal
+fn current(raw: u32) -> Result<(VType, usize), Exception> {
+    let vt = decode_vtype(read_csr_raw(VTYPE)).ok_or(Exception::IllegalInstruction(raw))?;
+    Ok((vt, read_csr_raw(VL) as usize))
+}
+
+// register groups start at a multiple of their size and end at v31 at most, the operands are
+// checked before any element is accessed so get and set never leave the register file
+fn check_groups(raw: u32, groups: &[(u32, usize)]) -> Result<(), Exception> {
+    for &(reg, regs) in groups {
+        if regs > 8 || !(reg as usize).is_multiple_of(regs) || reg as usize + regs > 32 {
+            return Err(Exception::IllegalInstruction(raw));
+        }
+    }
+    Ok(())
+}
+
+fn get(reg: u32, idx: usize, sew: u32) -> u64 {
+    let bytes = sew as usize / 8;
+    let start = reg as usize * vlenb() + idx * bytes;
+    VREGISTERS.with(|x| {
+        let v = x.borrow();
+        if start + bytes > v.len() {
+            panic!("vector register group out of range: v{}[{}]", reg, idx);
+        }
+        let mut val = 0;
+        for i in 0..bytes {
+            val |= (v[start + i] as u64) << (8 * i);
+        }
+        val
+    })
+}
+
+fn set(reg: u32, idx: usize, sew: u32, val: u64) {
+    let bytes = sew as usize / 8;
+    let start = reg as usize * vlenb() + idx * bytes;
+    VREGISTERS.with(|x| {
+        let mut v = x.borrow_mut();
+        if start + bytes > v.len() {
+            panic!("vector register group out of range: v{}[{}]", reg, idx);
+        }
+        for i in 0..bytes {
+            v[start + i] = (val >> (8 * i)) as u8;
+        }
+    })
+}
+
+fn mask_bit(reg: u32, idx: usize) -> bool {
+    let byte = reg as usize * vlenb() + idx / 8;
+    VREGISTERS.with(|x| x.borrow()[byte] >> (idx % 8) & 1 == 1)
+}
+
+fn set_mask_bit(reg: u32, idx: usize, val: bool) {
+    let byte = reg as usize * vlenb() + idx / 8;
+    VREGISTERS.with(|x| {
+        let mut v = x.borrow_mut();
+        if val {
+            v[byte] |= 1 << (idx % 8);
+        } else {
+            v[byte] &= !(1 << (idx % 8));
+        }
+    })
+}
+
+// element is active when instruction is unmasked or its bit in v0 is set
+#[inline(always)]
+fn active(vm: bool, idx: usize) -> bool {
+    vm || mask_bit(0, idx)
+}
+
+#[inline(always)]
+fn operand(src: Operand, idx: usize, sew: u32) -> u64 {
+    match src {
+        Operand::V(reg) => get(reg, idx, sew),
+        Operand::S(val) => val & mask(sew),
+    }
+}
+
+#[inline(always)]
+fn vstart() -> usize {
+    read_csr_raw(VSTART) as usize
+}
+
+#[inline(always)]
+fn set_vxsat() {
+    set_csr_raw(VCSR, read_csr_raw(VCSR) | 1);
+}
+
+// vd[i] = f(vs2[i], op1[i], vd[i]) for active elements
+fn elementwise(
+    vd: u32,
+    vs2: u32,
+    src: Operand,
+    vm: bool,
+    sew: u32,
+    vl: usize,
+    f: impl Fn(u64, u64, u64) -> u64,
+) {
+    for i in vstart()..vl {
+        if active(vm, i) {
+            let res = f(get(vs2, i, sew), operand(src, i, sew), get(vd, i, sew));
+            set(vd, i, sew, res & mask(sew));
+        }
+    }
+}
+
+// vd.mask[i] = f(vs2[i], op1[i], i) for active elements
+fn compare(
+    vd: u32,
+    vs2: u32,
+    src: Operand,
+    vm: bool,
+    sew: u32,
+    vl: usize,
+    f: impl Fn(u64, u64, usize) -> bool,
+) {
+    for i in vstart()..vl {
+        if active(vm, i) {
+            let res = f(get(vs2, i, sew), operand(src, i, sew), i);
+            set_mask_bit(vd, i, res);
+        }
+    }
+}
+
+// 2*SEW wide vd[i] = f(vs2[i], op1[i], vd[i]), vs2 is 2*SEW wide for .w forms
+#[allow(clippy::too_many_arguments)]
+fn widening(
+    vd: u32,
+    vs2: u32,
+    src: Operand,
+    vm: bool,
+    sew: u32,
+    vl: usize,
+    wide_vs2: bool,
+    f: impl Fn(u64, u64, u64) -> u64,
+) -> Result<(), Exception> {
+    if sew == 64 {
+        return Err(Exception::IllegalInstruction(0));
+    }
+    let vs2_sew = if wide_vs2 { sew * 2 } else { sew };
+    for i in vstart()..vl {
+        if active(vm, i) {
+            let res = f(
+                get(vs2, i, vs2_sew),
+                operand(src, i, sew),
+                get(vd, i, sew * 2),
+            );
+            set(vd, i, sew * 2, res & mask(sew * 2));
+        }
+    }
+    Ok(())
+}
+
+// vd[0] = f(...f(vs1[0], vs2[0])..., vs2[vl-1]) over active elements
+#[allow(clippy::too_many_arguments)]
+fn reduction(
+    vd: u32,
+    vs2: u32,
+    vs1: u32,
+    vm: bool,
+    sew: u32,
+    dst_sew: u32,
+    vl: usize,
+    f: impl Fn(u64, u64) -> u64,
+) {
+    if vl == 0 {
+        return;
+    }
+    let mut acc = get(vs1, 0, dst_sew);
+    for i in 0..vl {
+        if active(vm, i) {
+            acc = f(acc, get(vs2, i, sew)) & mask(dst_sew);
+        }
+    }
+    set(vd, 0, dst_sew, acc);
+}
+
+fn mem_read(bus: &mut Bus, addr: u64, eew: u32) -> Result<u64, Exception> {
+    load(bus, wrap_xlen!(addr), eew as u64 / 8, Access::Load)
+}
+
+fn mem_write(bus: &mut Bus, addr: u64, eew: u32, val: u64) -> Result<(), Exception> {
+    store(bus, wrap_xlen!(addr), eew as u64 / 8, val)
+}
+
+// traps are precise, vstart holds the element that faulted
+fn element_fault(i: usize, e: Exception) -> Exception {
+    set_csr_raw(VSTART, i as u64);
+    e
+}
+
+// Vsetvli, Vsetivli, Vsetvl
+fn vset(raw: u32) -> Result<(), Exception> {
+    let rd = rd!(raw);
+    let rs1 = rs1!(raw);
+    let (avl, vtype) = if raw >> 31 == 0 {
+        // Vsetvli
+        let avl = if rs1 != 0 {
+            Some(read_reg!(rs1))
+        } else if rd != 0 {
+            Some(u64::MAX)
+        } else {
+            None
+        };
+        (avl, (raw >> 20 & 0x7FF) as u64)
+    } else if raw >> 30 == 0b11 {
+        // Vsetivli
+        (Some(rs1 as u64), (raw >> 20 & 0x3FF) as u64)
+    } else {
+        // Vsetvl
+        let avl = if rs1 != 0 {
+            Some(read_reg!(rs1))
+        } else if rd != 0 {
+            Some(u64::MAX)
+        } else {
+            None
+        };
+        (avl, read_reg!(rs2!(raw)))
+    };
+
+    let vl = match decode_vtype(vtype) {
+        Some(vt) => {
+            set_csr_raw(VTYPE, vtype);
+            let vlmax = vlmax(vt) as u64;
+            // rs1 = rd = x0 keeps current vl
+            avl.map_or(read_csr_raw(VL).min(vlmax), |avl| avl.min(vlmax))
+        }
+        None => {
+            set_csr_raw(VTYPE, vill());
+            0
+        }
+    };
+    set_csr_raw(VL, vl);
+    set_csr_raw(VSTART, 0);
+    set_reg!(rd, vl);
+    Ok(())
+}
+
+// vector loads and stores encoded in LOAD-FP and STORE-FP opcodes
+pub fn execute_vector_memory(raw: u32, bus: &mut Bus, store: bool) -> Result<(), Exception> {
+    let eew = match raw >> 12 & 0x7 {
+        0b000 => 8,
+        0b101 => 16,
+        0b110 => 32,
+        0b111 => 64,
+        _ => return Err(Exception::IllegalInstruction(raw)),
+    };
+    let vd = rd!(raw);
+    let base = read_reg!(rs1!(raw));
+    let lumop = rs2!(raw);
+    let vm = raw >> 25 & 1 == 1;
+    let mop = raw >> 26 & 0x3;
+    let nf = (raw >> 29 & 0x7) as usize + 1;
+
+    // whole register and mask loads/stores don't depend on vtype
+    if mop == 0b00 && (lumop == 0b01000 || lumop == 0b01011) {
+        let (evl, eew) = if lumop == 0b01000 {
+            // Vl<nf>re<eew>.v, Vs<nf>r.v
+            if !matches!(nf, 1 | 2 | 4 | 8) {
+                return Err(Exception::IllegalInstruction(raw));
+            }
+            check_groups(raw, &[(vd, nf)])?;
+            (nf * vlenb() / (eew as usize / 8), eew)
+        } else {
+            // Vlm.v, Vsm.v
+            (read_csr_raw(VL).div_ceil(8) as usize, 8)
+        };
+        for i in vstart()..evl {
+            let addr = base.wrapping_add((i * eew as usize / 8) as u64);
+            let res = if store {
+                mem_write(bus, addr, eew, get(vd, i, eew))
+            } else {
+                mem_read(bus, addr, eew).map(|val| set(vd, i, eew, val))
+            };
+            res.map_err(|e| element_fault(i, e))?;
+        }
+        set_csr_raw(VSTART, 0);
+        return Ok(());
+    }
+
+    let (vt, vl) = current(raw)?;
+    // indexed accesses use eew for offsets and SEW for data
+    let data_eew = if mop & 0b01 == 1 { vt.sew } else { eew };
+    let regs = group_regs(vt, data_eew);
+    if nf * regs > 8 || vd as usize + nf * regs > 32 {
+        return Err(Exception::IllegalInstruction(raw));
+    }
+    check_groups(raw, &[(vd, regs)])?;
+    if mop & 0b01 == 1 {
+        check_groups(raw, &[(lumop, group_regs(vt, eew))])?;
+    }
+    let field_bytes = data_eew as u64 / 8;
+
+    'elements: for i in vstart()..vl {
+        if !active(vm, i) {
+            continue;
+        }
+        let element = match mop {
+            // unit stride, fault only first loads behave as unit stride
+            0b00 => match lumop {
+                0b00000 | 0b10000 => base.wrapping_add(i as u64 * nf as u64 * field_bytes),
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            },
+            // strided
+            0b10 => base.wrapping_add((i as u64).wrapping_mul(read_reg!(lumop))),
+            // indexed
+            _ => base.wrapping_add(get(lumop, i, eew)),
+        };
+        for field in 0..nf {
+            let addr = element.wrapping_add(field as u64 * field_bytes);
+            let reg = vd + (field * regs) as u32;
+            let res = if store {
+                mem_write(bus, addr, data_eew, get(reg, i, data_eew))
+            } else {
+                mem_read(bus, addr, data_eew).map(|val| set(reg, i, data_eew, val))
+            };
+            match res {
+                Ok(()) => {}
+                // fault only first load traps only on element 0, later faults shrink vl
+                Err(_) if !store && mop == 0b00 && lumop == 0b10000 && i > 0 => {
+                    set_csr_raw(VL, i as u64);
+                    break 'elements;
+                }
+                Err(e) => return Err(element_fault(i, e)),
+            }
+        }
+    }
+    set_csr_raw(VSTART, 0);
+    Ok(())
+}
+
+// OPIVV, OPIVX, OPIVI
+fn execute_opi(raw: u32, funct3: u32) -> Result<(), Exception> {
+    let funct6 = raw >> 26;
+    let vm = raw >> 25 & 1 == 1;
+    let vd = rd!(raw);
+    let vs2 = rs2!(raw);
+    let field = rs1!(raw);
+    let (vt, vl) = current(raw)?;
+    let sew = vt.sew;
+    let m = mask(sew);
+
+    let src = match funct3 {
+        0b000 => Operand::V(field),
+        0b100 => Operand::S(read_reg!(field)),
+        // shifts, slides and gathers use unsigned immediates
+        _ if matches!(funct6, 0b001100 | 0b001110 | 0b001111 | 0b100101..=0b101101) => {
+            Operand::S(field as u64)
+        }
+        _ => Operand::S(((field as i32) << 27 >> 27) as u64),
+    };
+
+    // scalar operands check as v0 which is always a valid group
+    let regs = group_regs(vt, sew);
+    let vs1 = match src {
+        Operand::V(reg) => reg,
+        Operand::S(_) => 0,
+    };
+    match funct6 {
+        // Vrgatherei16
+        0b001110 if funct3 == 0b000 => {
+            check_groups(raw, &[(vd, regs), (vs2, regs), (vs1, group_regs(vt, 16))])?
+        }
+        // Vmadc, Vmsbc and compares write a single mask register
+        0b010001 | 0b010011 | 0b011000..=0b011111 => {
+            check_groups(raw, &[(vd, 1), (vs2, regs), (vs1, regs)])?
+        }
+        // Vmv<nr>r.v
+        0b100111 if funct3 == 0b011 => {
+            let nr = field as usize + 1;
+            check_groups(raw, &[(vd, nr), (vs2, nr)])?
+        }
+        // Vnsrl, Vnsra
+        0b101100 | 0b101101 => check_groups(
+            raw,
+            &[(vd, regs), (vs2, group_regs(vt, sew * 2)), (vs1, regs)],
+        )?,
+        // Vwredsumu, Vwredsum
+        0b110000 | 0b110001 => check_groups(raw, &[(vd, 1), (vs2, regs), (vs1, 1)])?,
+        _ => check_groups(raw, &[(vd, regs), (vs2, regs), (vs1, regs)])?,
+    }
+
+    match funct6 {
+        // Vadd
+        0b000000 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a.wrapping_add(b)),
+        // Vsub
+        0b000010 if funct3 != 0b011 => {
+            elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a.wrapping_sub(b))
+        }
+        // Vrsub
+        0b000011 if funct3 != 0b000 => {
+            elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| b.wrapping_sub(a))
+        }
+        // Vminu
+        0b000100 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a.min(b)),
+        // Vmin
+        0b000101 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            sext(a, sew).min(sext(b, sew)) as u64
+        }),
+        // Vmaxu
+        0b000110 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a.max(b)),
+        // Vmax
+        0b000111 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            sext(a, sew).max(sext(b, sew)) as u64
+        }),
+        // Vand
+        0b001001 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a & b),
+        // Vor
+        0b001010 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a | b),
+        // Vxor
+        0b001011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a ^ b),
+        // Vrgather
+        0b001100 => {
+            let vlmax = vlmax(vt) as u64;
+            let src_vals: Vec<u64> = (0..vlmax as usize).map(|i| get(vs2, i, sew)).collect();
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let index = match src {
+                        Operand::V(reg) => get(reg, i, sew),
+                        Operand::S(val) => val,
+                    };
+                    let val = if index < vlmax {
+                        src_vals[index as usize]
+                    } else {
+                        0
+                    };
+                    set(vd, i, sew, val);
+                }
+            }
+        }
+        // Vrgatherei16
+        0b001110 if funct3 == 0b000 => {
+            let vlmax = vlmax(vt) as u64;
+            let src_vals: Vec<u64> = (0..vlmax as usize).map(|i| get(vs2, i, sew)).collect();
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let index = get(field, i, 16);
+                    let val = if index < vlmax {
+                        src_vals[index as usize]
+                    } else {
+                        0
+                    };
+                    set(vd, i, sew, val);
+                }
+            }
+        }
+        // Vslideup
+        0b001110 => {
+            let offset = match src {
+                Operand::S(val) => val as usize,
+                Operand::V(_) => unreachable!(),
+            };
+            for i in vstart().max(offset)..vl {
+                if active(vm, i) {
+                    set(vd, i, sew, get(vs2, i - offset, sew));
+                }
+            }
+        }
+        // Vslidedown
+        0b001111 if funct3 != 0b000 => {
+            let offset = match src {
+                Operand::S(val) => val,
+                Operand::V(_) => unreachable!(),
+            };
+            let vlmax = vlmax(vt) as u64;
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let index = (i as u64).saturating_add(offset);
+                    let val = if index < vlmax {
+                        get(vs2, index as usize, sew)
+                    } else {
+                        0
+                    };
+                    set(vd, i, sew, val);
+                }
+            }
+        }
+        // Vadc, Vsbc
+        0b010000 | 0b010010 if !vm => {
+            for i in vstart()..vl {
+                let carry = mask_bit(0, i) as u64;
+                let (a, b) = (get(vs2, i, sew), operand(src, i, sew));
+                let res = if funct6 == 0b010000 {
+                    a.wrapping_add(b).wrapping_add(carry)
+                } else {
+                    a.wrapping_sub(b).wrapping_sub(carry)
+                };
+                set(vd, i, sew, res & m);
+            }
+        }
+        // Vmadc
+        0b010001 => compare(vd, vs2, src, true, sew, vl, |a, b, i| {
+            let carry = (!vm && mask_bit(0, i)) as u128;
+            (a as u128 + b as u128 + carry) >> sew != 0
+        }),
+        // Vmsbc
+        0b010011 => compare(vd, vs2, src, true, sew, vl, |a, b, i| {
+            let borrow = (!vm && mask_bit(0, i)) as u128;
+            (a as u128) < b as u128 + borrow
+        }),
+        // Vmerge, Vmv.v
+        0b010111 => {
+            if vm && vs2 != 0 {
+                return Err(Exception::IllegalInstruction(raw));
+            }
+            for i in vstart()..vl {
+                let val = if vm || mask_bit(0, i) {
+                    operand(src, i, sew)
+                } else {
+                    get(vs2, i, sew)
+                };
+                set(vd, i, sew, val);
+            }
+        }
+        // Vmseq
+        0b011000 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| a == b),
+        // Vmsne
+        0b011001 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| a != b),
+        // Vmsltu
+        0b011010 if funct3 != 0b011 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| a < b),
+        // Vmslt
+        0b011011 if funct3 != 0b011 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            sext(a, sew) < sext(b, sew)
+        }),
+        // Vmsleu
+        0b011100 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| a <= b),
+        // Vmsle
+        0b011101 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            sext(a, sew) <= sext(b, sew)
+        }),
+        // Vmsgtu
+        0b011110 if funct3 != 0b000 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| a > b),
+        // Vmsgt
+        0b011111 if funct3 != 0b000 => compare(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            sext(a, sew) > sext(b, sew)
+        }),
+        // Vsaddu
+        0b100000 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            let res = a as u128 + b as u128;
+            if res > m as u128 {
+                set_vxsat();
+                m
+            } else {
+                res as u64
+            }
+        }),
+        // Vsadd
+        0b100001 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            let res = sext(a, sew) as i128 + sext(b, sew) as i128;
+            let (min, max) = (sext(1 << (sew - 1), sew) as i128, (m >> 1) as i128);
+            if res > max || res < min {
+                set_vxsat();
+                res.clamp(min, max) as u64
+            } else {
+                res as u64
+            }
+        }),
+        // Vssubu
+        0b100010 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            if b > a {
+                set_vxsat();
+                0
+            } else {
+                a - b
+            }
+        }),
+        // Vssub
+        0b100011 if funct3 != 0b011 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            let res = sext(a, sew) as i128 - sext(b, sew) as i128;
+            let (min, max) = (sext(1 << (sew - 1), sew) as i128, (m >> 1) as i128);
+            if res > max || res < min {
+                set_vxsat();
+                res.clamp(min, max) as u64
+            } else {
+                res as u64
+            }
+        }),
+        // Vsll
+        0b100101 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            a << (b & (sew as u64 - 1))
+        }),
+        // Vmv<nr>r.v
+        0b100111 if funct3 == 0b011 => {
+            let nr = field as usize + 1;
+            if !matches!(nr, 1 | 2 | 4 | 8) {
+                return Err(Exception::IllegalInstruction(raw));
+            }
+            let evl = nr * vlenb() * 8 / sew as usize;
+            for i in vstart()..evl {
+                set(vd, i, sew, get(vs2, i, sew));
+            }
+        }
+        // Vsrl
+        0b101000 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            a >> (b & (sew as u64 - 1))
+        }),
+        // Vsra
+        0b101001 => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            (sext(a, sew) >> (b & (sew as u64 - 1))) as u64
+        }),
+        // Vnsrl, Vnsra
+        0b101100 | 0b101101 => {
+            if sew == 64 {
+                return Err(Exception::IllegalInstruction(raw));
+            }
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let a = get(vs2, i, sew * 2);
+                    let shamt = operand(src, i, sew) & (sew as u64 * 2 - 1);
+                    let res = if funct6 == 0b101100 {
+                        a >> shamt
+                    } else {
+                        (sext(a, sew * 2) >> shamt) as u64
+                    };
+                    set(vd, i, sew, res & m);
+                }
+            }
+        }
+        // Vwredsumu
+        0b110000 if funct3 == 0b000 => reduction(vd, vs2, field, vm, sew, sew * 2, vl, |acc, a| {
+            acc.wrapping_add(a)
+        }),
+        // Vwredsum
+        0b110001 if funct3 == 0b000 => reduction(vd, vs2, field, vm, sew, sew * 2, vl, |acc, a| {
+            acc.wrapping_add(sext(a, sew) as u64)
+        }),
+        // error?
+        _ => return Err(Exception::IllegalInstruction(raw)),
+    }
+
+    set_csr_raw(VSTART, 0);
+    Ok(())
+}
+
+// OPMVV, OPMVX
+fn execute_opm(raw: u32, funct3: u32) -> Result<(), Exception> {
+    let funct6 = raw >> 26;
+    let vm = raw >> 25 & 1 == 1;
+    let vd = rd!(raw);
+    let vs2 = rs2!(raw);
+    let field = rs1!(raw);
+    let vv = funct3 == 0b010;
+
+    // mask logical operations and moves from/to x registers work without valid vtype
+    if vv && (0b011000..=0b011111).contains(&funct6) {
+        let vl = read_csr_raw(VL) as usize;
+        for i in vstart()..vl {
+            let a = mask_bit(vs2, i);
+            let b = mask_bit(field, i);
+            let res = match funct6 {
+                // Vmandn
+                0b011000 => a & !b,
+                // Vmand
+                0b011001 => a & b,
+                // Vmor
+                0b011010 => a | b,
+                // Vmxor
+                0b011011 => a ^ b,
+                // Vmorn
+                0b011100 => a | !b,
+                // Vmnand
+                0b011101 => !(a & b),
+                // Vmnor
+                0b011110 => !(a | b),
+                // Vmxnor
+                _ => !(a ^ b),
+            };
+            set_mask_bit(vd, i, res);
+        }
+        set_csr_raw(VSTART, 0);
+        return Ok(());
+    }
+
+    let (vt, vl) = current(raw)?;
+    let sew = vt.sew;
+    let m = mask(sew);
+    let src = if vv {
+        Operand::V(field)
+    } else {
+        Operand::S(read_reg!(field))
+    };
+
+    // scalar operands check as v0 which is always a valid group
+    let regs = group_regs(vt, sew);
+    let vs1 = if vv { field } else { 0 };
+    match (funct6, vv) {
+        // reductions
+        (0b000000..=0b000111, true) => check_groups(raw, &[(vd, 1), (vs2, regs), (vs1, 1)])?,
+        // Vmv.x.s, Vcpop.m, Vfirst.m, Vmv.s.x, Vmsbf, Vmsof, Vmsif use single registers
+        (0b010000, _) => {}
+        (0b010100, true) if field & 0b10000 == 0 => {}
+        // Viota, Vid
+        (0b010100, true) => check_groups(raw, &[(vd, regs)])?,
+        // Vzext, Vsext check their narrower source below
+        (0b010010, true) => check_groups(raw, &[(vd, regs)])?,
+        // Vcompress
+        (0b010111, true) => check_groups(raw, &[(vd, regs), (vs2, regs), (vs1, 1)])?,
+        // widening
+        (0b110000..=0b111111, _) => {
+            let vs2_regs = if funct6 >> 2 == 0b1101 {
+                group_regs(vt, sew * 2)
+            } else {
+                regs
+            };
+            check_groups(
+                raw,
+                &[(vd, group_regs(vt, sew * 2)), (vs2, vs2_regs), (vs1, regs)],
+            )?
+        }
+        _ => check_groups(raw, &[(vd, regs), (vs2, regs), (vs1, regs)])?,
+    }
+
+    match (funct6, vv) {
+        // Vredsum
+        (0b000000, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| {
+            acc.wrapping_add(a)
+        }),
+        // Vredand
+        (0b000001, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| acc & a),
+        // Vredor
+        (0b000010, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| acc | a),
+        // Vredxor
+        (0b000011, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| acc ^ a),
+        // Vredminu
+        (0b000100, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| acc.min(a)),
+        // Vredmin
+        (0b000101, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| {
+            sext(acc, sew).min(sext(a, sew)) as u64
+        }),
+        // Vredmaxu
+        (0b000110, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| acc.max(a)),
+        // Vredmax
+        (0b000111, true) => reduction(vd, vs2, field, vm, sew, sew, vl, |acc, a| {
+            sext(acc, sew).max(sext(a, sew)) as u64
+        }),
+        // Vslide1up
+        (0b001110, false) => {
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let val = if i == 0 {
+                        operand(src, 0, sew)
+                    } else {
+                        get(vs2, i - 1, sew)
+                    };
+                    set(vd, i, sew, val);
+                }
+            }
+        }
+        // Vslide1down
+        (0b001111, false) => {
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let val = if i + 1 == vl {
+                        operand(src, i, sew)
+                    } else {
+                        get(vs2, i + 1, sew)
+                    };
+                    set(vd, i, sew, val);
+                }
+            }
+        }
+        // Vmv.x.s, Vcpop.m, Vfirst.m
+        (0b010000, true) => match field {
+            // Vmv.x.s
+            0b00000 => {
+                set_reg!(vd, sext(get(vs2, 0, sew), sew));
+            }
+            // Vcpop.m
+            0b10000 => {
+                let count = (0..vl)
+                    .filter(|&i| active(vm, i) && mask_bit(vs2, i))
+                    .count();
+                set_reg!(vd, count);
+            }
+            // Vfirst.m
+            0b10001 => {
+                let first = (0..vl).find(|&i| active(vm, i) && mask_bit(vs2, i));
+                set_reg!(vd, first.map_or(-1, |x| x as i64));
+            }
+            // error?
+            _ => return Err(Exception::IllegalInstruction(raw)),
+        },
+        // Vmv.s.x
+        (0b010000, false) if vs2 == 0 => {
+            if vl > 0 {
+                set(vd, 0, sew, operand(src, 0, sew));
+            }
+        }
+        // Vzext, Vsext
+        (0b010010, true) => {
+            let (factor, signed) = match field {
+                0b00010 => (8, false),
+                0b00011 => (8, true),
+                0b00100 => (4, false),
+                0b00101 => (4, true),
+                0b00110 => (2, false),
+                0b00111 => (2, true),
+                _ => return Err(Exception::IllegalInstruction(raw)),
+            };
+            let src_sew = sew / factor;
+            if src_sew < 8 {
+                return Err(Exception::IllegalInstruction(raw));
+            }
+            check_groups(raw, &[(vs2, group_regs(vt, src_sew))])?;
+            for i in vstart()..vl {
+                if active(vm, i) {
+                    let val = get(vs2, i, src_sew);
+                    let val = if signed {
+                        sext(val, src_sew) as u64
+                    } else {
+                        val
+                    };
+                    set(vd, i, sew, val & m);
+                }
+            }
+        }
+        // Vmsbf, Vmsof, Vmsif, Viota, Vid
+        (0b010100, true) => match field {
+            // Vmsbf, Vmsof, Vmsif
+            0b00001..=0b00011 => {
+                let first = (0..vl).find(|&i| active(vm, i) && mask_bit(vs2, i));
+                for i in vstart()..vl {
+                    if active(vm, i) {
+                        let res = match (field, first) {
+                            (_, None) => field != 0b00010,
+                            (0b00001, Some(first)) => i < first,
+                            (0b00010, Some(first)) => i == first,
+                            (_, Some(first)) => i <= first,
+                        };
+                        set_mask_bit(vd, i, res);
+                    }
+                }
+            }
+            // Viota
+            0b10000 => {
+                let mut count = 0u64;
+                for i in 0..vl {
+                    if active(vm, i) {
+                        if i >= vstart() {
+                            set(vd, i, sew, count & m);
+                        }
+                        if mask_bit(vs2, i) {
+                            count += 1;
+                        }
+                    }
+                }
+            }
+            // Vid
+            0b10001 => {
+                for i in vstart()..vl {
+                    if active(vm, i) {
+                        set(vd, i, sew, i as u64 & m);
+                    }
+                }
+            }
+            // error?
+            _ => return Err(Exception::IllegalInstruction(raw)),
+        },
+        // Vcompress
+        (0b010111, true) => {
+            let src_vals: Vec<u64> = (0..vl).map(|i| get(vs2, i, sew)).collect();
+            let mut j = 0;
+            for (i, val) in src_vals.into_iter().enumerate() {
+                if mask_bit(field, i) {
+                    set(vd, j, sew, val);
+                    j += 1;
+                }
+            }
+        }
+        // Vdivu
+        (0b100000, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            a.checked_div(b).unwrap_or(m)
+        }),
+        // Vdiv
+        (0b100001, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            let (a, b) = (sext(a, sew), sext(b, sew));
+            if b == 0 {
+                m
+            } else {
+                a.wrapping_div(b) as u64
+            }
+        }),
+        // Vremu
+        (0b100010, _) => elementwise(
+            vd,
+            vs2,
+            src,
+            vm,
+            sew,
+            vl,
+            |a, b, _| {
+                if b == 0 {
+                    a
+                } else {
+                    a % b
+                }
+            },
+        ),
+        // Vrem
+        (0b100011, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            let (a, b) = (sext(a, sew), sext(b, sew));
+            if b == 0 {
+                a as u64
+            } else {
+                a.wrapping_rem(b) as u64
+            }
+        }),
+        // Vmulhu
+        (0b100100, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            ((a as u128 * b as u128) >> sew) as u64
+        }),
+        // Vmul
+        (0b100101, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| a.wrapping_mul(b)),
+        // Vmulhsu
+        (0b100110, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            ((sext(a, sew) as i128).wrapping_mul(b as i128) >> sew) as u64
+        }),
+        // Vmulh
+        (0b100111, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, _| {
+            ((sext(a, sew) as i128 * sext(b, sew) as i128) >> sew) as u64
+        }),
+        // Vmadd
+        (0b101001, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, d| {
+            b.wrapping_mul(d).wrapping_add(a)
+        }),
+        // Vnmsub
+        (0b101011, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, d| {
+            a.wrapping_sub(b.wrapping_mul(d))
+        }),
+        // Vmacc
+        (0b101101, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, d| {
+            b.wrapping_mul(a).wrapping_add(d)
+        }),
+        // Vnmsac
+        (0b101111, _) => elementwise(vd, vs2, src, vm, sew, vl, |a, b, d| {
+            d.wrapping_sub(b.wrapping_mul(a))
+        }),
+        // Vwaddu, Vwadd, Vwsubu, Vwsub and their .w forms
+        (0b110000..=0b110111, _) => {
+            let signed = funct6 & 0b001 == 1;
+            let sub = funct6 & 0b010 != 0;
+            let wide_vs2 = funct6 & 0b100 != 0;
+            let ext = |val: u64, width: u32| {
+                if signed {
+                    sext(val, width) as u64
+                } else {
+                    val
+                }
+            };
+            widening(vd, vs2, src, vm, sew, vl, wide_vs2, |a, b, _| {
+                let a = ext(a, if wide_vs2 { sew * 2 } else { sew });
+                let b = ext(b, sew);
+                if sub {
+                    a.wrapping_sub(b)
+                } else {
+                    a.wrapping_add(b)
+                }
+            })?
+        }
+        // Vwmulu
+        (0b111000, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, _| a * b)?,
+        // Vwmulsu
+        (0b111010, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, _| {
+            (sext(a, sew) as i128 * b as i128) as u64
+        })?,
+        // Vwmul
+        (0b111011, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, _| {
+            (sext(a, sew) * sext(b, sew)) as u64
+        })?,
+        // Vwmaccu
+        (0b111100, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, d| {
+            d.wrapping_add(a * b)
+        })?,
+        // Vwmacc
+        (0b111101, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, d| {
+            d.wrapping_add((sext(a, sew) * sext(b, sew)) as u64)
+        })?,
+        // Vwmaccus
+        (0b111110, false) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, d| {
+            d.wrapping_add((sext(a, sew) as i128 * b as i128) as u64)
+        })?,
+        // Vwmaccsu
+        (0b111111, _) => widening(vd, vs2, src, vm, sew, vl, false, |a, b, d| {
+            d.wrapping_add((a as i128 * sext(b, sew) as i128) as u64)
+        })?,
+        // error?
+        _ => return Err(Exception::IllegalInstruction(raw)),
+    }
+    set_csr_raw(VSTART, 0);
+    Ok(())
+}
+
+// OP-V major opcode
+pub fn execute_vector(raw: u32) -> Result<(), Exception> {
+    let funct3 = raw >> 12 & 0x7;
+    match funct3 {
+        // OPIVV, OPIVI, OPIVX
+        0b000 | 0b011 | 0b100 => execute_opi(raw, funct3),
+        // OPMVV, OPMVX
+        0b010 | 0b110 => execute_opm(raw, funct3),
+        // OPCFG
+        0b111 => vset(raw),
+        // error?
+        _ => Err(Exception::IllegalInstruction(raw)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{csr::init_csrs, dram::Dram, isa::parse_isa};
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const RAM_SIZE: u64 = 0x10000;
+
+    // vsetvli a0, a1, e32, m1, ta, ma
+    const VSETVLI_E32_M1: u32 = 0x0d05f557;
+    // vsetvli a0, a1, e32, m8, ta, ma
+    const VSETVLI_E32_M8: u32 = 0x0d35f557;
+    // vsetvli a0, a1, e8, m1, ta, ma
+    const VSETVLI_E8_M1: u32 = 0x0c05f557;
+    // vsetvli a0, a1, e8, m8, ta, ma
+    const VSETVLI_E8_M8: u32 = 0x0c35f557;
+    // vsetvli a0, zero, e16, m2, ta, ma
+    const VSETVLI_VLMAX_E16_M2: u32 = 0x0c907557;
+    // vsetvli zero, zero, e32, m1, ta, ma
+    const VSETVLI_KEEP_E32_M1: u32 = 0x0d007057;
+    // vsetivli a0, 3, e64, m1, ta, ma
+    const VSETIVLI_3_E64_M1: u32 = 0xcd81f557;
+    // vsetvl a0, a1, a2
+    const VSETVL: u32 = 0x80c5f557;
+
+    // vle32.v v1, (a1)
+    const VLE32: u32 = 0x0205e087;
+    // vse32.v v1, (a2)
+    const VSE32: u32 = 0x020660a7;
+    // vlse32.v v2, (a1), a3
+    const VLSE32: u32 = 0x0ad5e107;
+    // vsse32.v v2, (a2), a3
+    const VSSE32: u32 = 0x0ad66127;
+    // vluxei32.v v3, (a1), v4
+    const VLUXEI32: u32 = 0x0645e187;
+    // vsuxei32.v v3, (a2), v4
+    const VSUXEI32: u32 = 0x064661a7;
+    // vluxei64.v v1, (a1), v4
+    const VLUXEI64: u32 = 0x0645f087;
+    // vlseg2e32.v v4, (a1)
+    const VLSEG2E32: u32 = 0x2205e207;
+    // vle8ff.v v1, (a1)
+    const VLE8FF: u32 = 0x03058087;
+    // vl2re32.v v2, (a1)
+    const VL2RE32_V2: u32 = 0x2285e107;
+    // vl2re32.v v3, (a1)
+    const VL2RE32_V3: u32 = 0x2285e187;
+    // vl2re8.v v31, (a1)
+    const VL2RE8_V31: u32 = 0x22858f87;
+    // vs1r.v v31, (a2)
+    const VS1R_V31: u32 = 0x02860fa7;
+
+    // vadd.vv v1, v2, v3
+    const VADD_VV: u32 = 0x022180d7;
+    // vadd.vx v1, v2, a1
+    const VADD_VX: u32 = 0x0225c0d7;
+    // vadd.vi v1, v2, -3
+    const VADD_VI: u32 = 0x022eb0d7;
+    // vadd.vv v1, v2, v3, v0.t
+    const VADD_VV_MASKED: u32 = 0x002180d7;
+    // vadd.vv v3, v4, v6
+    const VADD_V3_V4_V6: u32 = 0x024301d7;
+    // vadd.vv v4, v6, v31
+    const VADD_V4_V6_V31: u32 = 0x026f8257;
+    // vsaddu.vv v1, v2, v3
+    const VSADDU_VV: u32 = 0x822180d7;
+    // vdiv.vv v1, v2, v3
+    const VDIV_VV: u32 = 0x8621a0d7;
+    // vzext.vf2 v1, v2
+    const VZEXT_VF2: u32 = 0x4a2320d7;
+    // vwaddu.vv v2, v4, v5
+    const VWADDU_V2: u32 = 0xc242a157;
+    // vwaddu.vv v3, v4, v5
+    const VWADDU_V3: u32 = 0xc242a1d7;
+    // vnsrl.wi v1, v2, 4
+    const VNSRL_V2: u32 = 0xb22230d7;
+    // vnsrl.wi v1, v3, 4
+    const VNSRL_V3: u32 = 0xb23230d7;
+
+    // vredsum.vs v1, v2, v3
+    const VREDSUM: u32 = 0x0221a0d7;
+    // vredsum.vs v1, v2, v3, v0.t
+    const VREDSUM_MASKED: u32 = 0x0021a0d7;
+    // vredmax.vs v1, v2, v3
+    const VREDMAX: u32 = 0x1e21a0d7;
+    // vwredsumu.vs v1, v2, v3
+    const VWREDSUMU: u32 = 0xc22180d7;
+
+    // vmseq.vv v0, v2, v3
+    const VMSEQ_VV: u32 = 0x62218057;
+    // vmslt.vx v5, v2, a1
+    const VMSLT_VX: u32 = 0x6e25c2d7;
+    // vmand.mm v1, v2, v3
+    const VMAND_MM: u32 = 0x6621a0d7;
+    // vmnot.m v1, v2
+    const VMNOT_M: u32 = 0x762120d7;
+    // vcpop.m a0, v2
+    const VCPOP_M: u32 = 0x42282557;
+    // vfirst.m a0, v2
+    const VFIRST_M: u32 = 0x4228a557;
+
+    // vrgather.vv v1, v2, v3
+    const VRGATHER_VV: u32 = 0x322180d7;
+    // vslideup.vi v1, v2, 2
+    const VSLIDEUP_VI: u32 = 0x3a2130d7;
+    // vslidedown.vx v1, v2, a1
+    const VSLIDEDOWN_VX: u32 = 0x3e25c0d7;
+    // vcompress.vm v1, v2, v3
+    const VCOMPRESS_VM: u32 = 0x5e21a0d7;
+    // viota.m v1, v2
+    const VIOTA_M: u32 = 0x522820d7;
+    // vid.v v1
+    const VID_V: u32 = 0x5208a0d7;
+    // vmv.x.s a0, v2
+    const VMV_X_S: u32 = 0x42202557;
+    // vmv.s.x v1, a1
+    const VMV_S_X: u32 = 0x4205e0d7;
+    // vmv2r.v v2, v4
+    const VMV2R_V2: u32 = 0x9e40b157;
+    // vmv2r.v v3, v4
+    const VMV2R_V3: u32 = 0x9e40b1d7;
+
+    // RV64 hart with VLEN = 128 and DRAM at RAM_BASE, vtype starts with vill set
+    fn setup() -> Bus {
+        let isa = parse_isa("rv64gcv").unwrap();
+        init_csrs(&isa);
+        VREGISTERS.with(|x| *x.borrow_mut() = vec![0; 32 * isa.vlen as usize / 8]);
+        ISA.with(|x| *x.borrow_mut() = isa);
+        let mut bus = Bus::new();
+        bus.register(
+            "dram",
+            RAM_BASE,
+            RAM_SIZE,
+            Box::new(Dram::new_dram(RAM_SIZE as usize)),
+        )
+        .unwrap();
+        bus
+    }
+
+    fn vsetvli(avl: u64, raw: u32) {
+        set_reg!(A1, avl);
+        execute_vector(raw).unwrap();
+    }
+
+    fn write_v(reg: u32, sew: u32, vals: &[u64]) {
+        for (i, &val) in vals.iter().enumerate() {
+            set(reg, i, sew, val);
+        }
+    }
+
+    fn read_v(reg: u32, sew: u32, n: usize) -> Vec<u64> {
+        (0..n).map(|i| get(reg, i, sew)).collect()
+    }
+
+    fn write_words(bus: &mut Bus, addr: u64, vals: &[u32]) {
+        for (i, &val) in vals.iter().enumerate() {
+            bus.set_u32((addr + 4 * i as u64) as usize, val).unwrap();
+        }
+    }
+
+    fn read_words(bus: &mut Bus, addr: u64, n: usize) -> Vec<u32> {
+        (0..n)
+            .map(|i| bus.get_u32((addr + 4 * i as u64) as usize).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn vsetvl_variants() {
+        let _bus = setup();
+        vsetvli(3, VSETVLI_E32_M1);
+        assert_eq!(read_reg!(A0), 3);
+        assert_eq!(read_csr_raw(VL), 3);
+        assert_eq!(read_csr_raw(VTYPE), 0xd0);
+        // avl above VLMAX = 128 / 32
+        vsetvli(100, VSETVLI_E32_M1);
+        assert_eq!(read_csr_raw(VL), 4);
+        // rs1 = rd = x0 keeps vl
+        vsetvli(3, VSETVLI_E32_M1);
+        execute_vector(VSETVLI_KEEP_E32_M1).unwrap();
+        assert_eq!(read_csr_raw(VL), 3);
+        // rs1 = x0 with rd != x0 asks for VLMAX = 128 * 2 / 16
+        execute_vector(VSETVLI_VLMAX_E16_M2).unwrap();
+        assert_eq!(read_reg!(A0), 16);
+        vsetvli(1000, VSETVLI_E8_M8);
+        assert_eq!(read_csr_raw(VL), 128);
+        execute_vector(VSETIVLI_3_E64_M1).unwrap();
+        assert_eq!(read_csr_raw(VL), 2);
+        // vtype from a2, reserved vsew sets vill and clears vl
+        set_reg!(A2, 0b100 << 3);
+        vsetvli(4, VSETVL);
+        assert_eq!(read_reg!(A0), 0);
+        assert_eq!(read_csr_raw(VL), 0);
+        assert_eq!(read_csr_raw(VTYPE), 1 << 63);
+        // fractional LMUL that can't hold an ELEN element
+        set_reg!(A2, 0b011 << 3 | 0b101);
+        vsetvli(4, VSETVL);
+        assert_eq!(read_csr_raw(VTYPE), 1 << 63);
+        set_reg!(A2, 0b010 << 3 | 0b111);
+        vsetvli(4, VSETVL);
+        assert_eq!(read_csr_raw(VTYPE), 0b010 << 3 | 0b111);
+        assert_eq!(read_csr_raw(VL), 2);
+    }
+
+    #[test]
+    fn vill_makes_instructions_illegal() {
+        let mut bus = setup();
+        assert_eq!(read_csr_raw(VTYPE), 1 << 63);
+        assert_eq!(read_csr_raw(VL), 0);
+        assert_eq!(
+            execute_vector(VADD_VV),
+            Err(Exception::IllegalInstruction(VADD_VV))
+        );
+        assert_eq!(
+            execute_vector(VREDSUM),
+            Err(Exception::IllegalInstruction(VREDSUM))
+        );
+        set_reg!(A1, RAM_BASE);
+        assert_eq!(
+            execute_vector_memory(VLE32, &mut bus, false),
+            Err(Exception::IllegalInstruction(VLE32))
+        );
+        // whole register loads and mask logical instructions don't depend on vtype
+        assert_eq!(execute_vector_memory(VL2RE32_V2, &mut bus, false), Ok(()));
+        assert_eq!(execute_vector(VMAND_MM), Ok(()));
+        // a valid vtype clears vill
+        vsetvli(4, VSETVLI_E32_M1);
+        assert_eq!(execute_vector(VADD_VV), Ok(()));
+    }
+
+    #[test]
+    fn unit_stride() {
+        let mut bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_words(&mut bus, RAM_BASE, &[10, 11, 12, 13]);
+        set_reg!(A1, RAM_BASE);
+        execute_vector_memory(VLE32, &mut bus, false).unwrap();
+        assert_eq!(read_v(1, 32, 4), [10, 11, 12, 13]);
+        set_reg!(A2, RAM_BASE + 0x100);
+        execute_vector_memory(VSE32, &mut bus, true).unwrap();
+        assert_eq!(read_words(&mut bus, RAM_BASE + 0x100, 4), [10, 11, 12, 13]);
+        // elements past vl are left alone
+        vsetvli(3, VSETVLI_E32_M1);
+        set_reg!(A2, RAM_BASE + 0x200);
+        execute_vector_memory(VSE32, &mut bus, true).unwrap();
+        assert_eq!(read_words(&mut bus, RAM_BASE + 0x200, 4), [10, 11, 12, 0]);
+    }
+
+    #[test]
+    fn strided() {
+        let mut bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_words(&mut bus, RAM_BASE, &[20, 0, 21, 0, 22, 0, 23, 0]);
+        set_reg!(A1, RAM_BASE);
+        set_reg!(A3, 8);
+        execute_vector_memory(VLSE32, &mut bus, false).unwrap();
+        assert_eq!(read_v(2, 32, 4), [20, 21, 22, 23]);
+        // negative stride stores backwards
+        set_reg!(A2, RAM_BASE + 0x10C);
+        set_reg!(A3, -4i64);
+        execute_vector_memory(VSSE32, &mut bus, true).unwrap();
+        assert_eq!(read_words(&mut bus, RAM_BASE + 0x100, 4), [23, 22, 21, 20]);
+    }
+
+    #[test]
+    fn indexed() {
+        let mut bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_words(&mut bus, RAM_BASE, &[30, 31, 32, 33]);
+        write_v(4, 32, &[12, 0, 4, 8]);
+        set_reg!(A1, RAM_BASE);
+        execute_vector_memory(VLUXEI32, &mut bus, false).unwrap();
+        assert_eq!(read_v(3, 32, 4), [33, 30, 31, 32]);
+        set_reg!(A2, RAM_BASE + 0x100);
+        execute_vector_memory(VSUXEI32, &mut bus, true).unwrap();
+        assert_eq!(read_words(&mut bus, RAM_BASE + 0x100, 4), [30, 31, 32, 33]);
+    }
+
+    #[test]
+    fn segments() {
+        let mut bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_words(&mut bus, RAM_BASE, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        set_reg!(A1, RAM_BASE);
+        execute_vector_memory(VLSEG2E32, &mut bus, false).unwrap();
+        assert_eq!(read_v(4, 32, 4), [1, 3, 5, 7]);
+        assert_eq!(read_v(5, 32, 4), [2, 4, 6, 8]);
+        // 2 fields of 8 registers
+        vsetvli(4, VSETVLI_E32_M8);
+        assert_eq!(
+            execute_vector_memory(VLSEG2E32, &mut bus, false),
+            Err(Exception::IllegalInstruction(VLSEG2E32))
+        );
+    }
+
+    #[test]
+    fn whole_registers() {
+        let mut bus = setup();
+        write_words(&mut bus, RAM_BASE, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        set_reg!(A1, RAM_BASE);
+        execute_vector_memory(VL2RE32_V2, &mut bus, false).unwrap();
+        assert_eq!(read_v(2, 32, 8), [1, 2, 3, 4, 5, 6, 7, 8]);
+        // register groups of 2 start at even registers
+        assert_eq!(
+            execute_vector_memory(VL2RE32_V3, &mut bus, false),
+            Err(Exception::IllegalInstruction(VL2RE32_V3))
+        );
+        // v31 and v32 don't make a group
+        assert_eq!(
+            execute_vector_memory(VL2RE8_V31, &mut bus, false),
+            Err(Exception::IllegalInstruction(VL2RE8_V31))
+        );
+        write_v(31, 32, &[9, 10, 11, 12]);
+        set_reg!(A2, RAM_BASE + 0x100);
+        execute_vector_memory(VS1R_V31, &mut bus, true).unwrap();
+        assert_eq!(read_words(&mut bus, RAM_BASE + 0x100, 4), [9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn fault_only_first() {
+        let mut bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        // elements 2 and 3 are past the end of RAM
+        let base = RAM_BASE + RAM_SIZE - 2;
+        set_reg!(A1, base);
+        execute_vector_memory(VLE8FF, &mut bus, false).unwrap();
+        assert_eq!(read_csr_raw(VL), 2);
+        // fault on element 0 traps
+        set_reg!(A1, RAM_BASE + RAM_SIZE);
+        assert_eq!(
+            execute_vector_memory(VLE8FF, &mut bus, false),
+            Err(Exception::LoadAccessFault(RAM_BASE + RAM_SIZE))
+        );
+        assert_eq!(read_csr_raw(VSTART), 0);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let _bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_v(2, 32, &[1, 2, 3, 0xFFFF_FFFF]);
+        write_v(3, 32, &[10, 20, 30, 1]);
+        execute_vector(VADD_VV).unwrap();
+        assert_eq!(read_v(1, 32, 4), [11, 22, 33, 0]);
+        set_reg!(A1, 5);
+        execute_vector(VADD_VX).unwrap();
+        assert_eq!(read_v(1, 32, 4), [6, 7, 8, 4]);
+        execute_vector(VADD_VI).unwrap();
+        assert_eq!(read_v(1, 32, 4), [0xFFFF_FFFE, 0xFFFF_FFFF, 0, 0xFFFF_FFFC]);
+        // masked off elements are undisturbed
+        write_v(0, 8, &[0b0101]);
+        write_v(1, 32, &[7, 7, 7, 7]);
+        execute_vector(VADD_VV_MASKED).unwrap();
+        assert_eq!(read_v(1, 32, 4), [11, 7, 33, 7]);
+        // saturation sets vxsat
+        execute_vector(VSADDU_VV).unwrap();
+        assert_eq!(read_v(1, 32, 4), [11, 22, 33, 0xFFFF_FFFF]);
+        assert_eq!(read_csr_raw(VCSR) & 1, 1);
+        // division by zero gives all ones
+        write_v(2, 32, &[20, 7, -9i32 as u32 as u64, 5]);
+        write_v(3, 32, &[3, 0, 2, 5]);
+        execute_vector(VDIV_VV).unwrap();
+        assert_eq!(read_v(1, 32, 4), [6, 0xFFFF_FFFF, -4i32 as u32 as u64, 1]);
+        write_v(2, 16, &[0xFFFF, 1, 2, 3]);
+        execute_vector(VZEXT_VF2).unwrap();
+        assert_eq!(read_v(1, 32, 4), [0xFFFF, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reductions() {
+        let _bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_v(2, 32, &[1, 2, 3, 4]);
+        write_v(3, 32, &[10]);
+        execute_vector(VREDSUM).unwrap();
+        assert_eq!(get(1, 0, 32), 20);
+        // only active elements
+        write_v(0, 8, &[0b0011]);
+        execute_vector(VREDSUM_MASKED).unwrap();
+        assert_eq!(get(1, 0, 32), 13);
+        // signed maximum
+        write_v(2, 32, &[1, -5i32 as u32 as u64, 7, 3]);
+        write_v(3, 32, &[-100i32 as u32 as u64]);
+        execute_vector(VREDMAX).unwrap();
+        assert_eq!(get(1, 0, 32), 7);
+        write_v(3, 32, &[50]);
+        execute_vector(VREDMAX).unwrap();
+        assert_eq!(get(1, 0, 32), 50);
+        // widening sum into a 64 bit element
+        write_v(2, 32, &[0xFFFF_FFFF; 4]);
+        write_v(3, 64, &[1]);
+        execute_vector(VWREDSUMU).unwrap();
+        assert_eq!(get(1, 0, 64), 4 * 0xFFFF_FFFF + 1);
+    }
+
+    #[test]
+    fn masks() {
+        let _bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_v(2, 32, &[1, 2, 3, 4]);
+        write_v(3, 32, &[1, 0, 3, 0]);
+        execute_vector(VMSEQ_VV).unwrap();
+        assert_eq!(get(0, 0, 8) & 0xF, 0b0101);
+        set_reg!(A1, 3);
+        execute_vector(VMSLT_VX).unwrap();
+        assert_eq!(get(5, 0, 8) & 0xF, 0b0011);
+        write_v(2, 8, &[0b1100]);
+        write_v(3, 8, &[0b1010]);
+        execute_vector(VMAND_MM).unwrap();
+        assert_eq!(get(1, 0, 8) & 0xF, 0b1000);
+        execute_vector(VMNOT_M).unwrap();
+        assert_eq!(get(1, 0, 8) & 0xF, 0b0011);
+        execute_vector(VCPOP_M).unwrap();
+        assert_eq!(read_reg!(A0), 2);
+        execute_vector(VFIRST_M).unwrap();
+        assert_eq!(read_reg!(A0), 2);
+        write_v(2, 8, &[0]);
+        execute_vector(VFIRST_M).unwrap();
+        assert_eq!(read_reg!(A0), u64::MAX);
+    }
+
+    #[test]
+    fn permutations() {
+        let _bus = setup();
+        vsetvli(4, VSETVLI_E32_M1);
+        write_v(2, 32, &[10, 20, 30, 40]);
+        // index past VLMAX reads 0
+        write_v(3, 32, &[3, 0, 9, 1]);
+        execute_vector(VRGATHER_VV).unwrap();
+        assert_eq!(read_v(1, 32, 4), [40, 10, 0, 20]);
+        write_v(1, 32, &[1, 1, 1, 1]);
+        execute_vector(VSLIDEUP_VI).unwrap();
+        assert_eq!(read_v(1, 32, 4), [1, 1, 10, 20]);
+        set_reg!(A1, 1);
+        execute_vector(VSLIDEDOWN_VX).unwrap();
+        assert_eq!(read_v(1, 32, 4), [20, 30, 40, 0]);
+        write_v(1, 32, &[0, 0, 0, 0]);
+        write_v(3, 8, &[0b1010]);
+        execute_vector(VCOMPRESS_VM).unwrap();
+        assert_eq!(read_v(1, 32, 4), [20, 40, 0, 0]);
+        write_v(2, 8, &[0b1011]);
+        execute_vector(VIOTA_M).unwrap();
+        assert_eq!(read_v(1, 32, 4), [0, 1, 2, 2]);
+        execute_vector(VID_V).unwrap();
+        assert_eq!(read_v(1, 32, 4), [0, 1, 2, 3]);
+        write_v(2, 32, &[0x8000_0000]);
+        execute_vector(VMV_X_S).unwrap();
+        assert_eq!(read_reg!(A0), 0xFFFF_FFFF_8000_0000);
+        set_reg!(A1, 0x1234);
+        execute_vector(VMV_S_X).unwrap();
+        assert_eq!(read_v(1, 32, 2), [0x1234, 1]);
+        write_v(4, 32, &[5, 6, 7, 8, 9, 10, 11, 12]);
+        execute_vector(VMV2R_V2).unwrap();
+        assert_eq!(read_v(2, 32, 8), [5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn register_groups_are_checked() {
+        let mut bus = setup();
+        let illegal = |raw| Err(Exception::IllegalInstruction(raw));
+        // LMUL = 2
+        execute_vector(VSETVLI_VLMAX_E16_M2).unwrap();
+        assert_eq!(execute_vector(VADD_V3_V4_V6), illegal(VADD_V3_V4_V6));
+        assert_eq!(execute_vector(VADD_V4_V6_V31), illegal(VADD_V4_V6_V31));
+        // widening and narrowing use groups of 2 * LMUL
+        vsetvli(4, VSETVLI_E32_M1);
+        assert_eq!(execute_vector(VWADDU_V2), Ok(()));
+        assert_eq!(execute_vector(VWADDU_V3), illegal(VWADDU_V3));
+        assert_eq!(execute_vector(VNSRL_V2), Ok(()));
+        assert_eq!(execute_vector(VNSRL_V3), illegal(VNSRL_V3));
+        assert_eq!(execute_vector(VMV2R_V3), illegal(VMV2R_V3));
+        // LMUL = 8, widening would need 16 registers
+        vsetvli(4, VSETVLI_E8_M8);
+        assert_eq!(execute_vector(VADD_VV), illegal(VADD_VV));
+        assert_eq!(execute_vector(VWADDU_V2), illegal(VWADDU_V2));
+        // 64 bit indexes of 8 bit elements take 8 registers from v4
+        vsetvli(4, VSETVLI_E8_M1);
+        set_reg!(A1, RAM_BASE);
+        assert_eq!(
+            execute_vector_memory(VLUXEI64, &mut bus, false),
+            illegal(VLUXEI64)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::error::EmulatorError;
+
+// default ISA string, can be overridden with --isa=<string>
+pub const DEFAULT_ISA: &str = "rv64imafdcv_zicsr_zifencei_zba_zbb_zbc_zbs";
+
+// set of extensions that instructions are checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Isa {
+    pub xlen: u32,
+    pub m: bool,
+    pub a: bool,
+    pub f: bool,
+    pub d: bool,
+    pub c: bool,
+    pub v: bool,
+    // vector register length in bits, set with zvl<N>b
+    pub vlen: u32,
+    pub zba: bool,
+    pub zbb: bool,
+    pub zbc: bool,
+    pub zbs: bool,
+}
+
+impl Isa {
+    // value of misa CSR
+    pub fn misa(&self) -> u64 {
+        let mxl: u64 = if self.xlen == 64 { 2 } else { 1 };
+        // supervisor and user modes are always implemented
+        let mut misa =
+            (mxl << (self.xlen - 2)) | 1 << (b'i' - b'a') | 1 << (b's' - b'a') | 1 << (b'u' - b'a');
+        for (enabled, letter) in [
+            (self.m, b'm'),
+            (self.a, b'a'),
+            (self.f, b'f'),
+            (self.d, b'd'),
+            (self.c, b'c'),
+            (self.v, b'v'),
+        ] {
+            if enabled {
+                misa |= 1 << (letter - b'a');
+            }
+        }
+        misa
+    }
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        parse_isa(DEFAULT_ISA).unwrap()
+    }
+}
+
+// parses ISA string like "rv64gc_zba_zbb", names are case insensitive
+pub fn parse_isa(isa: &str) -> Result<Isa, EmulatorError> {
+    let lower = isa.to_lowercase();
+    let error = || EmulatorError::IsaString(isa.to_string());
+
+    let rest = lower.strip_prefix("rv").ok_or_else(error)?;
+    let (xlen, rest) = if let Some(rest) = rest.strip_prefix("64") {
+        (64, rest)
+    } else if let Some(rest) = rest.strip_prefix("32") {
+        (32, rest)
+    } else {
+        return Err(error());
+    };
+
+    let mut parsed = Isa {
+        xlen,
+        m: false,
+        a: false,
+        f: false,
+        d: false,
+        c: false,
+        v: false,
+        vlen: 128,
+        zba: false,
+        zbb: false,
+        zbc: false,
+        zbs: false,
+    };
+
+    let mut parts = rest.split('_');
+    let letters = parts.next().unwrap_or("");
+    let mut letters = letters.chars();
+    match letters.next() {
+        Some('i') => {}
+        Some('g') => {
+            parsed.m = true;
+            parsed.a = true;
+            parsed.f = true;
+            parsed.d = true;
+        }
+        _ => return Err(error()),
+    }
+    for letter in letters {
+        match letter {
+            'm' => parsed.m = true,
+            'a' => parsed.a = true,
+            'f' => parsed.f = true,
+            'd' => parsed.d = true,
+            'c' => parsed.c = true,
+            'v' => parsed.v = true,
+            _ => return Err(error()),
+        }
+    }
+    // D depends on F, V depends on D
+    if (parsed.d && !parsed.f) || (parsed.v && !parsed.d) {
+        return Err(error());
+    }
+
+    for part in parts {
+        match part {
+            // always present
+            "zicsr" | "zifencei" => {}
+            "zba" => parsed.zba = true,
+            "zbb" => parsed.zbb = true,
+            "zbc" => parsed.zbc = true,
+            "zbs" => parsed.zbs = true,
+            _ => {
+                // Zvl<N>b sets minimal vector length, which is used as VLEN
+                let vlen = part
+                    .strip_prefix("zvl")
+                    .and_then(|x| x.strip_suffix('b'))
+                    .and_then(|x| x.parse::<u32>().ok())
+                    .filter(|x| x.is_power_of_two() && (32..=65536).contains(x))
+                    .ok_or_else(error)?;
+                parsed.vlen = parsed.vlen.max(vlen);
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted_strings() {
+        let isa = parse_isa("rv64gc").unwrap();
+        assert_eq!(isa.xlen, 64);
+        assert!(isa.m && isa.a && isa.f && isa.d && isa.c);
+        assert!(!isa.v && !isa.zba && !isa.zbb && !isa.zbc && !isa.zbs);
+
+        let isa = parse_isa("RV32IMAC_Zicsr_Zbb").unwrap();
+        assert_eq!(isa.xlen, 32);
+        assert!(isa.m && isa.a && isa.c && isa.zbb);
+        assert!(!isa.f && !isa.d && !isa.zba);
+
+        let isa = parse_isa(DEFAULT_ISA).unwrap();
+        assert!(isa.v && isa.zba && isa.zbb && isa.zbc && isa.zbs);
+        assert_eq!(isa.vlen, 128);
+        // zvl only raises VLEN
+        assert_eq!(parse_isa("rv64gcv_zvl512b").unwrap().vlen, 512);
+        assert_eq!(parse_isa("rv64gcv_zvl64b").unwrap().vlen, 128);
+    }
+
+    #[test]
+    fn rejected_strings() {
+        for isa in [
+            "",
+            "rv",
+            "rv128i",
+            "x64gc",
+            "rv64",
+            "rv64e",
+            "rv64mi",
+            // unknown letter and extension
+            "rv64gq",
+            "rv64gc_zbx",
+            // D without F, V without D
+            "rv64id",
+            "rv64imafv",
+            "rv64gcv_zvl100b",
+            "rv64gcv_zvl16b",
+            "rv64gcv_zvlb",
+        ] {
+            assert!(
+                matches!(parse_isa(isa), Err(EmulatorError::IsaString(ref s)) if s == isa),
+                "{}",
+                isa
+            );
+        }
+    }
+
+    #[test]
+    fn misa_bits() {
+        let misa = parse_isa("rv64imac").unwrap().misa();
+        // MXL = 2, I, M, A, C, S and U
+        assert_eq!(
+            misa,
+            2 << 62 | 1 << 8 | 1 << 12 | 1 | 1 << 2 | 1 << 18 | 1 << 20
+        );
+        assert_eq!(parse_isa("rv32i").unwrap().misa() >> 30, 1);
+    }
+}
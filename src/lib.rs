@@ -0,0 +1,195 @@
+// RISC-V hart with its memory and peripherals, the emulator binary is a client of this crate
+// and embedders can build their own bus of devices around the same state
+pub mod bus;
+pub mod clint;
+pub mod csr;
+pub mod dram;
+pub mod elf_parser;
+pub mod error;
+pub mod instruction;
+pub mod isa;
+pub mod loader;
+pub mod memmap;
+pub mod misc;
+pub mod mmu;
+pub mod plic;
+pub mod pmp;
+pub mod rom;
+pub mod symbols;
+pub mod tlb;
+pub mod trap;
+pub mod uart;
+pub use bus::{Bus, BusFault, Device};
+pub use memmap::{MemoryMap, Window};
+use std::cell::RefCell;
+
+use crate::loader::Segment;
+use clint::Clint;
+use isa::Isa;
+use plic::Plic;
+use symbols::SymbolIndex;
+use tlb::Tlb;
+use uart::Uart;
+
+pub const DEBUG: bool = false;
+pub const P_PC: bool = false;
+pub const P_REG: bool = false;
+pub const P_STACK: bool = false;
+pub const P_STACK_SIZE: u64 = 64;
+
+thread_local! {
+    pub static REGISTERS: RefCell<[u64;32]> = const { RefCell::new([0;32]) };
+    pub static FREGISTERS: RefCell<[u64;32]> = const { RefCell::new([0;32]) };
+    // 32 vector registers of VLEN bits each
+    pub static VREGISTERS: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+    // fflags in bits 4:0, frm in bits 7:5
+    pub static FCSR: RefCell<u32> = const { RefCell::new(0) };
+    pub static PC: RefCell<u64> = const { RefCell::new(0) };
+    pub static CSRS: RefCell<[u64; 4096]> = const { RefCell::new([0; 4096]) };
+    // current privilege level of the hart, starts in machine mode
+    pub static PRIVILEGE: RefCell<u64> = const { RefCell::new(csr::MACHINE) };
+    pub static TLB: RefCell<Tlb> = RefCell::new(Tlb::default());
+    // number of implemented PMP entries, 0 means PMP isn't implemented
+    pub static PMP_ENTRIES: RefCell<usize> = const { RefCell::new(pmp::MAX_PMP_ENTRIES) };
+    pub static CLINT: RefCell<Clint> = RefCell::new(Clint::default());
+    pub static PLIC: RefCell<Plic> = RefCell::new(Plic::default());
+    pub static UART: RefCell<Uart> = RefCell::new(Uart::default());
+    // permissions of memory loaded from ELF segments
+    pub static SEGMENTS: RefCell<Vec<Segment>> = const { RefCell::new(vec![]) };
+    // symbols of the loaded ELF for naming addresses
+    pub static SYMBOLS: RefCell<SymbolIndex> = RefCell::new(SymbolIndex::default());
+    pub static ISA: RefCell<Isa> = RefCell::new(Isa::default());
+    // address reserved by the last lr.w/lr.d, consumed by sc.w/sc.d
+    pub static RESERVATION: RefCell<Option<u64>> = const { RefCell::new(None) };
+}
+
+//stack pointer register index
+pub const SP: usize = 2;
+// global pointer register index
+pub const GP: usize = 3;
+
+// zero register index
+pub const ZERO: usize = 0;
+
+// A registers indexes
+pub const A0: usize = 10;
+pub const A1: usize = A0 + 1;
+pub const A2: usize = A1 + 1;
+pub const A3: usize = A2 + 1;
+pub const A4: usize = A3 + 1;
+pub const A5: usize = A4 + 1;
+pub const A6: usize = A5 + 1;
+pub const A7: usize = A6 + 1;
+
+#[macro_export]
+macro_rules! inc_pc {
+    ($raw: expr) => {
+        $crate::PC.with(|x| {
+            let mut pc = x.borrow_mut();
+            *pc = pc.wrapping_add($raw as u64);
+        });
+    };
+}
+
+#[macro_export]
+macro_rules! set_pc {
+    ($pc: expr) => {
+        $crate::PC.with(|x| *x.borrow_mut() = $pc as u64);
+    };
+}
+
+#[macro_export]
+macro_rules! get_pc {
+    () => {
+        $crate::PC.with(|x| *x.borrow())
+    };
+}
+
+// in RV32 mode registers hold 32 bit values sign extended to 64 bits
+#[macro_export]
+macro_rules! set_reg {
+    ($reg: expr, $val: expr) => {
+        let val: i64 = $val as i64;
+        let val = if $crate::xlen!() == 32 {
+            val as i32 as i64
+        } else {
+            val
+        };
+        $crate::REGISTERS.with(|x| x.borrow_mut()[$reg as usize] = val as u64);
+    };
+}
+
+#[macro_export]
+macro_rules! read_reg {
+    ($reg: expr) => {
+        $crate::REGISTERS.with(|x| x.borrow()[$reg as usize] as u64)
+    };
+}
+
+#[macro_export]
+macro_rules! set_freg {
+    ($reg: expr, $val: expr) => {
+        $crate::FREGISTERS.with(|x| x.borrow_mut()[$reg as usize] = $val as u64);
+    };
+}
+
+#[macro_export]
+macro_rules! read_freg {
+    ($reg: expr) => {
+        $crate::FREGISTERS.with(|x| x.borrow()[$reg as usize])
+    };
+}
+
+#[macro_export]
+macro_rules! set_fcsr {
+    ($val: expr) => {
+        $crate::FCSR.with(|x| *x.borrow_mut() = ($val as u32) & 0xFF);
+    };
+}
+
+#[macro_export]
+macro_rules! read_fcsr {
+    () => {
+        $crate::FCSR.with(|x| *x.borrow())
+    };
+}
+
+#[macro_export]
+macro_rules! has_ext {
+    ($ext: ident) => {
+        $crate::ISA.with(|x| x.borrow().$ext)
+    };
+}
+
+#[macro_export]
+macro_rules! xlen {
+    () => {
+        $crate::ISA.with(|x| x.borrow().xlen)
+    };
+}
+
+// zero extends lower XLEN bits of value, used for addresses and unsigned operations
+#[macro_export]
+macro_rules! wrap_xlen {
+    ($val: expr) => {
+        if $crate::xlen!() == 32 {
+            ($val as u64) & 0xFFFFFFFF
+        } else {
+            $val as u64
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! set_reservation {
+    ($addr: expr) => {
+        $crate::RESERVATION.with(|x| *x.borrow_mut() = Some($addr as u64));
+    };
+}
+
+#[macro_export]
+macro_rules! take_reservation {
+    () => {
+        $crate::RESERVATION.with(|x| x.borrow_mut().take())
+    };
+}
@@ -0,0 +1,101 @@
+// copies loadable segments of ELF into memory, nothing else from the file is mapped
+// permissions of the segments are enforced on every access, memory outside of them is unrestricted
+use crate::{
+    dram::Dram,
+    elf_parser::{ProgramHeader, ProgramHeaderFlags, ProgramHeaderType},
+    error::EmulatorError,
+    memmap::MemoryMap,
+    mmu::Access,
+    rom::Rom,
+    SEGMENTS,
+};
+
+// memory of loaded segment, end is exclusive
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    start: u64,
+    end: u64,
+    read: bool,
+    write: bool,
+    exec: bool,
+}
+
+impl Segment {
+    fn new(header: &ProgramHeader) -> Self {
+        let has = |flag| header.p_flags.contains(&flag);
+        Self {
+            start: header.virtual_address,
+            end: header.virtual_address.saturating_add(header.mem_size),
+            read: has(ProgramHeaderFlags::PfR),
+            write: has(ProgramHeaderFlags::PfW),
+            exec: has(ProgramHeaderFlags::PfX),
+        }
+    }
+}
+
+// every PT_LOAD segment is placed at its virtual address, translation is off at reset so it's
+// the physical one too, bytes past the file contents up to mem_size are zeroed
+pub fn load_segments(
+    data: &[u8],
+    headers: &[ProgramHeader],
+    map: &MemoryMap,
+    dram: &mut Dram,
+    mut rom: Option<&mut Rom>,
+) -> Result<(), EmulatorError> {
+    for header in headers {
+        if header.p_type != ProgramHeaderType::PtLoad {
+            continue;
+        }
+        // whole segment has to be backed before anything is copied, mem_size comes from the file
+        if !map.fits(header.virtual_address, header.mem_size) {
+            return Err(EmulatorError::BadSegment(format!(
+                "{:#x} bytes at {:#x} are outside of RAM and ROM",
+                header.mem_size, header.virtual_address
+            )));
+        }
+        let file_range = header
+            .segment_offset
+            .checked_add(header.segment_size)
+            .filter(|end| *end <= data.len() as u64 && header.segment_size <= header.mem_size);
+        let Some(end) = file_range else {
+            return Err(EmulatorError::BadSegment(format!(
+                "{:#x} bytes at offset {:#x} loaded to {:#x} with memory size {:#x}",
+                header.segment_size, header.segment_offset, header.virtual_address, header.mem_size
+            )));
+        };
+        let bytes = &data[header.segment_offset as usize..end as usize];
+        map.load(dram, rom.as_deref_mut(), header.virtual_address, bytes)?;
+
+        // .bss and similar, only matters where an earlier segment wrote
+        let addr = header.virtual_address + header.segment_size;
+        let zeroed = header.mem_size - header.segment_size;
+        map.zero(dram, rom.as_deref_mut(), addr, zeroed)?;
+        SEGMENTS.with(|x| x.borrow_mut().push(Segment::new(header)));
+    }
+    Ok(())
+}
+
+// true when physical address is in a segment with code
+pub fn executable(addr: u64) -> bool {
+    SEGMENTS.with(|x| {
+        x.borrow()
+            .iter()
+            .any(|s| s.exec && addr >= s.start && addr < s.end)
+    })
+}
+
+// checks access of size bytes at physical address against permissions of loaded segments,
+// false means access fault
+pub fn check(paddr: u64, size: u64, access: Access) -> bool {
+    let end = paddr.saturating_add(size);
+    SEGMENTS.with(|x| {
+        x.borrow()
+            .iter()
+            .filter(|s| paddr < s.end && end > s.start)
+            .all(|s| match access {
+                Access::Fetch => s.exec,
+                Access::Load => s.read,
+                Access::Store => s.write,
+            })
+    })
+}
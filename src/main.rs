@@ -1,62 +1,84 @@
-#[allow(unused_unsafe)]
-mod dram;
-mod elf_parser;
-mod error;
-mod instruction;
-mod misc;
-use crate::{
-    dram::DRAM_SIZE,
-    instruction::instruction::{execute_32, get_instructions},
-    misc::{dbg_reg, dbg_stack},
-};
-use std::{cell::RefCell, mem::transmute, process::exit};
+use std::process::exit;
 
-use crate::{
+use risc_v::{
+    bus::Bus,
+    clint::{self, Clint, ClintDevice, Timebase},
+    csr,
     dram::Dram,
-    elf_parser::{extract_prog_bits, program_header_parser, raw_section_header_parser},
+    elf_parser::{self, program_header_parser, raw_section_header_parser, symbol_parser},
+    error::*,
+    get_pc, inc_pc,
+    instruction::instruction::{execute_32, get_instructions},
+    isa::{parse_isa, DEFAULT_ISA},
+    loader::{self, load_segments},
+    memmap::{parse_hex, parse_window, MemoryMap},
+    misc::{dbg_mem, dbg_reg, dbg_stack, dbg_tlb},
+    mmu::{self, Access},
+    plic::PlicDevice,
+    read_reg,
+    rom::Rom,
+    set_pc, set_reg,
+    symbols::{self, SymbolIndex},
+    tlb::Tlb,
+    trap::{self, Exception, Syscall},
+    uart::{self, Uart, UartDevice},
+    CLINT, DEBUG, GP, ISA, PMP_ENTRIES, P_PC, P_REG, P_STACK, P_STACK_SIZE, SP, SYMBOLS, TLB, UART,
+    VREGISTERS, ZERO,
 };
-use error::*;
-
-const DEBUG: bool = false;
-const P_PC: bool = false;
-const P_REG: bool = false;
-const P_STACK: bool = false;
-const P_STACK_SIZE: u64 = 64;
-
-thread_local! {
-    pub static REGISTERS: RefCell<[u64;32]> = RefCell::new([0;32]);
-    pub static PC: RefCell<u32> = RefCell::new(0);
-}
-
-//stack pointer register index
-const SP: usize = 2;
-// global pointer register index
-const GP: usize = 3;
-
-// zero register index
-const ZERO: usize = 0;
-
-// A registers indexes
-const A0: usize = 10;
-const A1: usize = A0 + 1;
-const A2: usize = A1 + 1;
-const A3: usize = A2 + 1;
-const A4: usize = A3 + 1;
-const A5: usize = A4 + 1;
-const A6: usize = A5 + 1;
-const A7: usize = A6 + 1;
 
 fn main() -> Result<(), EmulatorError> {
+    let isa = std::env::args().find_map(|x| x.strip_prefix("--isa=").map(String::from));
+    // number of TLB entries, power of two or 0 to disable it
+    let tlb_entries = std::env::args().find_map(|x| x.strip_prefix("--tlb=").map(String::from));
+    // number of PMP entries, 64 (default), 16 or 0 to leave PMP unimplemented
+    let pmp_entries = std::env::args().find_map(|x| x.strip_prefix("--pmp=").map(String::from));
+    let tlb_stats = std::env::args().any(|x| x == "--tlb-stats");
+    // host memory backing guest memory is printed at exit
+    let mem_stats = std::env::args().any(|x| x == "--mem-stats");
+    // CLINT base address in hex and source of mtime, instret or host
+    let clint_base = std::env::args().find_map(|x| x.strip_prefix("--clint=").map(String::from));
+    let timebase = std::env::args().find_map(|x| x.strip_prefix("--mtime=").map(String::from));
+    // PLIC base address in hex
+    let plic_base = std::env::args().find_map(|x| x.strip_prefix("--plic=").map(String::from));
+    // UART base address in hex and file receiving its output instead of stdout
+    let uart_base = std::env::args().find_map(|x| x.strip_prefix("--uart=").map(String::from));
+    let uart_out = std::env::args().find_map(|x| x.strip_prefix("--uart-out=").map(String::from));
+    // --uart-in=stdin feeds host stdin to the receive FIFO, without it nothing is received
+    let uart_in = std::env::args().find_map(|x| x.strip_prefix("--uart-in=").map(String::from));
+    // RAM and ROM as <base>,<size> in hex, --rom=none leaves ROM unmapped
+    let ram = std::env::args().find_map(|x| x.strip_prefix("--ram=").map(String::from));
+    let rom = std::env::args().find_map(|x| x.strip_prefix("--rom=").map(String::from));
+    // execution stops before instruction at symbol or hex address, can be given more than once
+    let breaks: Vec<String> = std::env::args()
+        .filter_map(|x| x.strip_prefix("--break=").map(String::from))
+        .collect();
+
     let data = std::fs::read("./test_asm/a.out")?;
 
-    let elf = elf_parser::elf_parser(&data);
+    let elf = elf_parser::elf_parser(&data)?;
+    // memory is always little endian
+    if elf.is_big_endian() {
+        return Err(EmulatorError::BigEndianElf);
+    }
 
-    let program_headers: Vec<elf_parser::ProgramHeader> = program_header_parser(&data, &elf);
+    // XLEN is taken from ELF class unless ISA string is given explicitly
+    let isa = match isa {
+        Some(isa) => parse_isa(&isa)?,
+        None => parse_isa(&DEFAULT_ISA.replacen("rv64", &format!("rv{}", elf.xlen()), 1))?,
+    };
+    if isa.xlen != elf.xlen() {
+        println!(
+            "\x1b[93mWARNING\x1b[0m: running {} bit ELF with XLEN = {}",
+            elf.xlen(),
+            isa.xlen
+        )
+    }
 
-    let mut section_headers = raw_section_header_parser(&data, &elf);
-    section_headers.fill_names(&data)?;
+    let program_headers: Vec<elf_parser::ProgramHeader> = program_header_parser(&data, &elf)?;
 
-    let text = section_headers.find_text_section()?;
+    let mut section_headers = raw_section_header_parser(&data, &elf)?;
+    section_headers.fill_names(&data, &elf)?;
+    let symbols = SymbolIndex::new(symbol_parser(&data, &elf, &section_headers)?);
 
     if DEBUG {
         println!("{:?}\n\n", elf);
@@ -64,107 +86,197 @@ fn main() -> Result<(), EmulatorError> {
         for i in 0..section_headers.len() {
             println!("{:?}", section_headers.headers[i]);
         }
-        println!("{:?}", text);
-        println!("text addr: {:x?}", text.section_address);
+        println!("symbols: {}", symbols.len());
     }
 
-    // creating dram
-    let mut dram = Dram::new_dram();
-    // setting starting PC
-    set_pc!(text.section_address);
-    set_reg!(SP, DRAM_SIZE);
-    let prog_bits = extract_prog_bits(&data, text)?;
-
-    // copying program_bits into dram
-    let mut i = text.section_address as usize;
-    for e in prog_bits {
-        dram.set_u8(i, *e);
-        i += 1;
+    // symbol names take precedence over hex addresses
+    let breakpoints = breaks
+        .into_iter()
+        .map(|x| match symbols.address(&x).or_else(|| parse_hex(&x)) {
+            Some(addr) => Ok(addr),
+            None => Err(EmulatorError::UnknownSymbol(x)),
+        })
+        .collect::<Result<Vec<u64>, EmulatorError>>()?;
+    SYMBOLS.with(|x| *x.borrow_mut() = symbols);
+
+    csr::init_csrs(&isa);
+    VREGISTERS.with(|x| *x.borrow_mut() = vec![0; 32 * isa.vlen as usize / 8]);
+    ISA.with(|x| *x.borrow_mut() = isa);
+    if let Some(entries) = tlb_entries {
+        match entries.parse::<usize>() {
+            Ok(n) if n == 0 || n.is_power_of_two() => TLB.with(|x| *x.borrow_mut() = Tlb::new(n)),
+            _ => return Err(EmulatorError::TlbSize(entries)),
+        }
+    }
+    if let Some(entries) = pmp_entries {
+        match entries.parse::<usize>() {
+            Ok(n @ (0 | 16 | 64)) => PMP_ENTRIES.with(|x| *x.borrow_mut() = n),
+            _ => return Err(EmulatorError::PmpEntries(entries)),
+        }
     }
 
-    // processing data section
-    match section_headers.find_data_section() {
-        Some(res) => {
-            let mut i = res.section_address as usize;
-
-            let start = res.section_offset as usize;
-            // setting up global pointer ( start of data section )
-            set_reg!(GP, i);
-            let end = start + res.section_size as usize;
-            for e in &data[start..end] {
-                dram.set_u8(i, *e);
-                i += 1;
-            }
+    // memory map, QEMU virt layout unless overridden
+    let mut map = MemoryMap::default();
+    if let Some(ram) = ram {
+        map.ram = parse_window(&ram).ok_or(EmulatorError::MemoryMap(ram))?;
+    }
+    match rom.as_deref() {
+        None => {}
+        Some("none") => map.rom = None,
+        Some(other) => {
+            map.rom = Some(parse_window(other).ok_or(EmulatorError::MemoryMap(other.to_string()))?)
+        }
+    }
+    if let Some(base) = clint_base {
+        map.clint.base = parse_hex(&base).ok_or(EmulatorError::ClintArgument(base))?;
+    }
+    if let Some(base) = plic_base {
+        map.plic.base = parse_hex(&base).ok_or(EmulatorError::PlicArgument(base))?;
+    }
+    if let Some(base) = uart_base {
+        map.uart.base = parse_hex(&base).ok_or(EmulatorError::UartArgument(base))?;
+    }
+    map.validate()?;
 
-            if DEBUG {
-                println!("data addr: {:x}", res.section_address);
-                println!("{:?}", &data[start..end]);
-            }
+    let timebase = match timebase.as_deref() {
+        None | Some("instret") => Timebase::Instret,
+        Some("host") => Timebase::Host,
+        Some(other) => return Err(EmulatorError::ClintArgument(other.to_string())),
+    };
+    CLINT.with(|x| *x.borrow_mut() = Clint::new(timebase));
+    let uart_out: Box<dyn std::io::Write> = match uart_out {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    let uart_in = match uart_in.as_deref() {
+        None => None,
+        Some("stdin") => Some(uart::stdin_input()),
+        Some(other) => return Err(EmulatorError::UartArgument(other.to_string())),
+    };
+    let uart = Uart::new(uart_in, uart_out);
+    UART.with(|x| *x.borrow_mut() = uart);
+
+    // creating dram and rom
+    let mut dram = Dram::new_dram(map.ram.size as usize);
+    let mut rom = map.rom.map(|rom| Rom::new(rom.size as usize));
+
+    // setting starting PC
+    set_pc!(elf.entry_point());
+    set_reg!(SP, map.stack_top());
+
+    // copying loadable segments into memory
+    load_segments(&data, &program_headers, &map, &mut dram, rom.as_mut())?;
+
+    // setting up global pointer ( start of data section )
+    if let Some(res) = section_headers.find_data_section() {
+        set_reg!(GP, res.section_address);
+        if DEBUG {
+            println!("data addr: {:x}", res.section_address);
         }
-        None => {}
     }
 
+    // creating bus with memory and peripherals mapped
+    let mut bus = Bus::new();
+    bus.register("dram", map.ram.base, map.ram.size, Box::new(dram))?;
+    if let (Some(window), Some(rom)) = (map.rom, rom) {
+        bus.register("rom", window.base, window.size, Box::new(rom))?;
+    }
+    bus.register(
+        "clint",
+        map.clint.base,
+        map.clint.size,
+        Box::new(ClintDevice),
+    )?;
+    bus.register("plic", map.plic.base, map.plic.size, Box::new(PlicDevice))?;
+    bus.register("uart", map.uart.base, map.uart.size, Box::new(UartDevice))?;
+
     loop {
-        let raw = get_instructions(&dram, get_pc!());
+        clint::tick();
+        uart::tick();
+        if let Some(code) = trap::pending_interrupt() {
+            trap::take_interrupt(code);
+        }
+        if breakpoints.contains(&get_pc!()) {
+            println!("breakpoint");
+            println!("pc: {:x}{}", get_pc!(), symbols::describe(get_pc!()));
+            dbg_reg();
+            if tlb_stats {
+                dbg_tlb();
+            }
+            if mem_stats {
+                dbg_mem(&bus);
+            }
+            exit(0);
+        }
+        let raw = get_instructions(&mut bus, get_pc!());
 
-        execute_32(raw, &mut dram);
+        match raw.and_then(|raw| execute_32(raw, &mut bus)) {
+            Ok(()) => csr::tick(),
+            Err(e) => {
+                if !trap::take_trap(e) {
+                    // without a handler ecalls are emulated on the host
+                    let fault = match e {
+                        Exception::EnvironmentCall(_) => match trap::emulate_syscall(&mut bus) {
+                            Ok(Syscall::Done) => {
+                                inc_pc!(4);
+                                csr::tick();
+                                continue;
+                            }
+                            Ok(Syscall::Exit(code)) => {
+                                println!("exit: {}", code);
+                                if tlb_stats {
+                                    dbg_tlb();
+                                }
+                                if mem_stats {
+                                    dbg_mem(&bus);
+                                }
+                                exit(code);
+                            }
+                            Ok(Syscall::Unknown) => e,
+                            // fault reading syscall buffer is reported instead of the ecall
+                            Err(fault) => fault,
+                        },
+                        _ => e,
+                    };
+                    trap::report_fault(fault, raw.ok());
+                    if tlb_stats {
+                        dbg_tlb();
+                    }
+                    if mem_stats {
+                        dbg_mem(&bus);
+                    }
+                    exit(1);
+                }
+            }
+        }
 
         if DEBUG || P_PC {
-            println!("PC: {:x?}", get_pc!());
+            println!("PC: {:x?}{}", get_pc!(), symbols::describe(get_pc!()));
         }
 
         if DEBUG || P_REG {
             dbg_reg();
         }
         if DEBUG || P_STACK {
-            dbg_stack(SP, P_STACK_SIZE, &mut dram);
+            dbg_stack(SP, P_STACK_SIZE, &mut bus);
         }
 
         if read_reg!(ZERO) != 0 {
             set_reg!(ZERO, 0);
         };
 
-        if get_pc!() as u64 >= text.section_address + text.section_size {
+        // without translation the program ends when it leaves the code it was loaded with
+        if mmu::bare(Access::Fetch) && !loader::executable(get_pc!()) {
             println!("end");
-            println!("pc: {:x}", get_pc!());
+            println!("pc: {:x}{}", get_pc!(), symbols::describe(get_pc!()));
             println!("sp: {:x}", read_reg!(SP));
+            if tlb_stats {
+                dbg_tlb();
+            }
+            if mem_stats {
+                dbg_mem(&bus);
+            }
             exit(0);
         }
     }
 }
-
-#[macro_export]
-macro_rules! inc_pc {
-    ($raw: expr) => {
-        crate::PC.with(|x| *x.borrow_mut() += $raw);
-    };
-}
-
-#[macro_export]
-macro_rules! set_pc {
-    ($pc: expr) => {
-        crate::PC.with(|x| *x.borrow_mut() = $pc as u32);
-    };
-}
-
-#[macro_export]
-macro_rules! get_pc {
-    () => {
-        crate::PC.with(|x| *x.borrow())
-    };
-}
-
-#[macro_export]
-macro_rules! set_reg {
-    ($reg: expr, $val: expr) => {
-        crate::REGISTERS
-            .with(|x| x.borrow_mut()[$reg as usize] = unsafe { transmute($val as i64) });
-    };
-}
-
-#[macro_export]
-macro_rules! read_reg {
-    ($reg: expr) => {
-        crate::REGISTERS.with(|x| x.borrow()[$reg as usize] as u64)
-    };
-}
@@ -0,0 +1,167 @@
+// physical memory map, the default layout follows QEMU virt machine
+use crate::{
+    bus::BusFault,
+    clint::{CLINT_SIZE, DEFAULT_CLINT_BASE},
+    dram::{Dram, DRAM_SIZE},
+    error::EmulatorError,
+    plic::{DEFAULT_PLIC_BASE, PLIC_SIZE},
+    rom::Rom,
+    uart::{DEFAULT_UART_BASE, UART_SIZE},
+};
+
+pub const DEFAULT_RAM_BASE: u64 = 0x8000_0000;
+pub const DEFAULT_ROM_BASE: u64 = 0x1000;
+pub const DEFAULT_ROM_SIZE: u64 = 0xF000;
+
+// range of physical addresses, end is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl Window {
+    pub fn new(base: u64, size: u64) -> Self {
+        Self { base, size }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, addr: u64, size: u64) -> bool {
+        addr >= self.base && addr.checked_add(size).is_some_and(|end| end <= self.end())
+    }
+
+    fn overlaps(&self, other: &Window) -> bool {
+        self.base < other.end() && other.base < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMap {
+    pub ram: Window,
+    // no ROM is mapped when None
+    pub rom: Option<Window>,
+    pub clint: Window,
+    pub plic: Window,
+    pub uart: Window,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            ram: Window::new(DEFAULT_RAM_BASE, DRAM_SIZE as u64),
+            rom: Some(Window::new(DEFAULT_ROM_BASE, DEFAULT_ROM_SIZE)),
+            clint: Window::new(DEFAULT_CLINT_BASE, CLINT_SIZE),
+            plic: Window::new(DEFAULT_PLIC_BASE, PLIC_SIZE),
+            uart: Window::new(DEFAULT_UART_BASE, UART_SIZE),
+        }
+    }
+}
+
+impl MemoryMap {
+    fn windows(&self) -> Vec<(&'static str, Window)> {
+        let mut windows = vec![
+            ("ram", self.ram),
+            ("clint", self.clint),
+            ("plic", self.plic),
+            ("uart", self.uart),
+        ];
+        if let Some(rom) = self.rom {
+            windows.push(("rom", rom));
+        }
+        windows
+    }
+
+    // windows have to be non empty, inside the address space and disjoint
+    pub fn validate(&self) -> Result<(), EmulatorError> {
+        let windows = self.windows();
+        for (i, (name, window)) in windows.iter().enumerate() {
+            if window.size == 0 || window.base.checked_add(window.size).is_none() {
+                return Err(EmulatorError::MemoryMap(format!(
+                    "{} at {:#x} with size {:#x}",
+                    name, window.base, window.size
+                )));
+            }
+            if let Some((other, _)) = windows[..i].iter().find(|(_, w)| w.overlaps(window)) {
+                return Err(EmulatorError::MemoryMap(format!(
+                    "{} overlaps {}",
+                    name, other
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // initial stack pointer, top of RAM aligned to 16 bytes
+    pub fn stack_top(&self) -> u64 {
+        self.ram.end() & !0xF
+    }
+
+    // true when size bytes at physical address are in RAM or ROM
+    pub fn fits(&self, addr: u64, size: u64) -> bool {
+        self.ram.contains(addr, size) || self.rom.is_some_and(|rom| rom.contains(addr, size))
+    }
+
+    // zeroes size bytes at physical address in RAM or ROM
+    pub fn zero(
+        &self,
+        dram: &mut Dram,
+        rom: Option<&mut Rom>,
+        addr: u64,
+        size: u64,
+    ) -> Result<(), EmulatorError> {
+        let res = if self.ram.contains(addr, size) {
+            dram.zero((addr - self.ram.base) as usize, size as usize)
+        } else {
+            match (self.rom, rom) {
+                (Some(window), Some(rom)) if window.contains(addr, size) => {
+                    rom.zero((addr - window.base) as usize, size as usize)
+                }
+                _ => Err(BusFault::AccessFault),
+            }
+        };
+        res.map_err(|_| outside(addr, size))
+    }
+
+    // copies bytes to physical address in RAM or ROM, they can't be placed anywhere else
+    pub fn load(
+        &self,
+        dram: &mut Dram,
+        rom: Option<&mut Rom>,
+        addr: u64,
+        bytes: &[u8],
+    ) -> Result<(), EmulatorError> {
+        let size = bytes.len() as u64;
+        let res = if self.ram.contains(addr, size) {
+            dram.load((addr - self.ram.base) as usize, bytes)
+        } else {
+            match (self.rom, rom) {
+                (Some(window), Some(rom)) if window.contains(addr, size) => {
+                    rom.load((addr - window.base) as usize, bytes)
+                }
+                _ => Err(BusFault::AccessFault),
+            }
+        };
+        res.map_err(|_| outside(addr, size))
+    }
+}
+
+fn outside(addr: u64, size: u64) -> EmulatorError {
+    EmulatorError::MemoryMap(format!(
+        "{:#x} bytes at {:#x} are outside of RAM and ROM",
+        size, addr
+    ))
+}
+
+// hex address or size, with or without 0x prefix
+pub fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+// window given as <base>,<size> in hex
+pub fn parse_window(s: &str) -> Option<Window> {
+    let (base, size) = s.split_once(',')?;
+    Some(Window::new(parse_hex(base)?, parse_hex(size)?))
+}
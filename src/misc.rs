@@ -1,40 +1,4 @@
-use crate::{dram::Dram, read_reg, REGISTERS};
-
-#[macro_export]
-macro_rules! fast_transmute {
-    (<$start:expr, u16>, $data: expr ) => {
-        unsafe { std::mem::transmute::<[u8; 2], u16>([$data[$start + 0], $data[$start + 1]]) }
-    };
-    (<$start:expr, u32>, $data: expr ) => {
-        unsafe {
-            std::mem::transmute::<[u8; 4], u32>([
-                $data[$start + 0],
-                $data[$start + 1],
-                $data[$start + 2],
-                $data[$start + 3],
-            ])
-        }
-    };
-    (<$start:expr, u64>, $data: expr ) => {
-        unsafe {
-            std::mem::transmute::<[u8; 8], u64>([
-                $data[$start + 0],
-                $data[$start + 1],
-                $data[$start + 2],
-                $data[$start + 3],
-                $data[$start + 4],
-                $data[$start + 5],
-                $data[$start + 6],
-                $data[$start + 7],
-            ])
-        }
-    };
-    (<$start:expr, $t_type:tt @ $t_temp:tt>, $data: expr) => {
-        unsafe {
-            std::mem::transmute::<$t_temp, $t_type>(fast_transmute!(<$start, $t_temp>, $data))
-        }
-    }
-}
+use crate::{bus::Bus, read_reg, tlb, REGISTERS};
 
 #[inline(always)]
 pub fn dbg_reg() {
@@ -49,12 +13,33 @@ pub fn dbg_reg() {
     })
 }
 
-pub fn dbg_stack(sp: usize, dbg_size: u64, dram: &mut Dram) {
+pub fn dbg_stack(sp: usize, dbg_size: u64, bus: &mut Bus) {
     let sp = read_reg!(sp);
     for i in (sp..sp + dbg_size).step_by(8) {
         for j in 0..8 {
-            print!("M{:02x}: 0x{:02x} ", i + j, dram.get_u32((i + j) as usize));
+            match bus.get_u32((i + j) as usize) {
+                Ok(val) => print!("M{:02x}: 0x{:02x} ", i + j, val),
+                Err(_) => print!("M{:02x}: -- ", i + j),
+            }
         }
         println!()
     }
 }
+
+pub fn dbg_tlb() {
+    let stats = tlb::stats();
+    let lookups = stats.hits + stats.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        stats.hits as f64 * 100.0 / lookups as f64
+    };
+    println!(
+        "tlb: {} hits, {} misses ({:.2}% hit rate), {} flushes",
+        stats.hits, stats.misses, hit_rate, stats.flushes
+    );
+}
+
+pub fn dbg_mem(bus: &Bus) {
+    println!("mem: {} KiB resident", bus.resident() / 1024);
+}
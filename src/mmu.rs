@@ -0,0 +1,522 @@
+// virtual memory, Sv32 in RV32 and Sv39/Sv48/Sv57 in RV64
+// every fetch, load and store goes through translate which returns physical address
+use crate::{
+    bus::{Bus, BusFault},
+    csr::{
+        current_privilege, read_csr_raw, MACHINE, MSTATUS, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_MXR,
+        MSTATUS_SUM, SATP, SUPERVISOR, USER,
+    },
+    loader, pmp, tlb,
+    trap::Exception,
+    xlen,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    // stores and AMOs
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+
+    fn misaligned(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAddressMisaligned(addr),
+            Access::Load => Exception::LoadAddressMisaligned(addr),
+            Access::Store => Exception::StoreAddressMisaligned(addr),
+        }
+    }
+
+    // exception for fault reported by the bus, at virtual address of the access
+    pub fn bus_fault(self, fault: BusFault, addr: u64) -> Exception {
+        match fault {
+            BusFault::AccessFault => self.access_fault(addr),
+            BusFault::Misaligned => self.misaligned(addr),
+        }
+    }
+}
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// page table entry bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+// satp modes
+const SATP_BARE: u64 = 0;
+const SATP_SV32: u64 = 1;
+const SATP_SV39: u64 = 8;
+const SATP_SV48: u64 = 9;
+const SATP_SV57: u64 = 10;
+
+// shape of the page table for given satp mode
+#[derive(Debug, Clone, Copy)]
+struct Scheme {
+    levels: u32,
+    pte_size: u64,
+    vpn_bits: u32,
+    ppn_bits: u32,
+}
+
+fn satp_mode(satp: u64) -> u64 {
+    if xlen!() == 32 {
+        satp >> 31
+    } else {
+        satp >> 60
+    }
+}
+
+fn satp_ppn(satp: u64) -> u64 {
+    if xlen!() == 32 {
+        satp & 0x3FFFFF
+    } else {
+        satp & 0xFFF_FFFF_FFFF
+    }
+}
+
+fn satp_asid(satp: u64) -> u64 {
+    if xlen!() == 32 {
+        satp >> 22 & 0x1FF
+    } else {
+        satp >> 44 & 0xFFFF
+    }
+}
+
+fn scheme(mode: u64) -> Option<Scheme> {
+    let (levels, pte_size, vpn_bits, ppn_bits) = match mode {
+        SATP_SV32 if xlen!() == 32 => (2, 4, 10, 22),
+        SATP_SV39 if xlen!() == 64 => (3, 8, 9, 44),
+        SATP_SV48 if xlen!() == 64 => (4, 8, 9, 44),
+        SATP_SV57 if xlen!() == 64 => (5, 8, 9, 44),
+        _ => return None,
+    };
+    Some(Scheme {
+        levels,
+        pte_size,
+        vpn_bits,
+        ppn_bits,
+    })
+}
+
+// satp is WARL, writes selecting unsupported mode are ignored
+pub fn satp_mode_supported(satp: u64) -> bool {
+    let mode = satp_mode(satp);
+    mode == SATP_BARE || scheme(mode).is_some()
+}
+
+// cached translations are tagged with ASID, so they go stale only when satp switches mode or
+// points the same ASID at different page table
+pub fn satp_written(old: u64, new: u64) {
+    if satp_mode(old) != satp_mode(new) {
+        tlb::flush(None, None);
+    } else if satp_asid(old) == satp_asid(new) && satp_ppn(old) != satp_ppn(new) {
+        tlb::flush(None, Some(satp_asid(new)));
+    }
+}
+
+// privilege used for translation, MPRV makes M mode loads and stores use MPP
+fn effective_privilege(access: Access) -> u64 {
+    let privilege = current_privilege();
+    let status = read_csr_raw(MSTATUS);
+    if access != Access::Fetch && privilege == MACHINE && status & MSTATUS_MPRV != 0 {
+        (status & MSTATUS_MPP) >> 11
+    } else {
+        privilege
+    }
+}
+
+// true when addresses of the access are physical
+pub fn bare(access: Access) -> bool {
+    effective_privilege(access) == MACHINE || scheme(satp_mode(read_csr_raw(SATP))).is_none()
+}
+
+// translates virtual address of size bytes long access and checks that it's backed by memory
+// or a device and passes PMP, without translation also permissions of loaded segments
+pub fn translate(bus: &mut Bus, vaddr: u64, size: u64, access: Access) -> Result<usize, Exception> {
+    let privilege = effective_privilege(access);
+    let (paddr, bare) = match page_walk(bus, vaddr, access, privilege)? {
+        Some(paddr) => {
+            // accesses crossing page boundary would need two translations
+            if vaddr % PAGE_SIZE + size > PAGE_SIZE {
+                return Err(access.misaligned(vaddr));
+            }
+            (paddr, false)
+        }
+        None => (vaddr, true),
+    };
+    // once a kernel runs with paging its page tables decide permissions, segments only describe
+    // the program as loaded
+    if !bus.contains(paddr, size)
+        || !pmp::check(paddr, size, access, privilege)
+        || (bare && !loader::check(paddr, size, access))
+    {
+        return Err(access.access_fault(vaddr));
+    }
+    Ok(paddr as usize)
+}
+
+// reads size bytes at virtual address, zero extended
+pub fn load(bus: &mut Bus, vaddr: u64, size: u64, access: Access) -> Result<u64, Exception> {
+    let paddr = translate(bus, vaddr, size, access)?;
+    bus.read(paddr as u64, size)
+        .map_err(|e| access.bus_fault(e, vaddr))
+}
+
+// writes lowest size bytes of val at virtual address
+pub fn store(bus: &mut Bus, vaddr: u64, size: u64, val: u64) -> Result<(), Exception> {
+    let paddr = translate(bus, vaddr, size, Access::Store)?;
+    bus.write(paddr as u64, size, val)
+        .map_err(|e| Access::Store.bus_fault(e, vaddr))
+}
+
+// walks the page table, None means translation is off
+fn page_walk(
+    bus: &mut Bus,
+    vaddr: u64,
+    access: Access,
+    privilege: u64,
+) -> Result<Option<u64>, Exception> {
+    let satp = read_csr_raw(SATP);
+    if privilege == MACHINE {
+        return Ok(None);
+    }
+    let scheme = match scheme(satp_mode(satp)) {
+        Some(scheme) => scheme,
+        None => return Ok(None),
+    };
+    let fault = access.page_fault(vaddr);
+
+    // bits above the virtual address have to be copies of its highest bit
+    if xlen!() == 64 {
+        let shift = 64 - (12 + scheme.levels * scheme.vpn_bits);
+        if ((vaddr << shift) as i64 >> shift) as u64 != vaddr {
+            return Err(fault);
+        }
+    }
+
+    let asid = satp_asid(satp);
+    // stores to pages that aren't dirty yet walk again to set D
+    let required = if access == Access::Store { PTE_D } else { 0 };
+    let (pte, page_shift) = match tlb::lookup(vaddr, asid, required) {
+        Some((pte, page_shift)) => {
+            if !permitted(pte, privilege, access) {
+                return Err(fault);
+            }
+            (pte, page_shift)
+        }
+        _ => {
+            let (pte, pte_addr, page_shift) = walk(bus, satp, scheme, vaddr, access)?;
+            if !permitted(pte, privilege, access) {
+                return Err(fault);
+            }
+            // A and D are updated by hardware
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                if !pmp::check(pte_addr, scheme.pte_size, Access::Store, SUPERVISOR) {
+                    return Err(access.access_fault(vaddr));
+                }
+                bus.write(pte_addr, scheme.pte_size, updated)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+            tlb::insert(vaddr, asid, updated & PTE_G != 0, updated, page_shift);
+            (updated, page_shift)
+        }
+    };
+
+    let ppn = pte >> 10 & ((1 << scheme.ppn_bits) - 1);
+    let offset_mask = (1 << page_shift) - 1;
+    Ok(Some(((ppn << 12) & !offset_mask) | (vaddr & offset_mask)))
+}
+
+// finds leaf PTE, returns it with its address and log2 of page size
+fn walk(
+    bus: &mut Bus,
+    satp: u64,
+    scheme: Scheme,
+    vaddr: u64,
+    access: Access,
+) -> Result<(u64, u64, u32), Exception> {
+    let fault = access.page_fault(vaddr);
+    let ppn_mask = (1 << scheme.ppn_bits) - 1;
+    let vpn_mask = (1 << scheme.vpn_bits) - 1;
+    let mut table = satp_ppn(satp) * PAGE_SIZE;
+    let mut level = scheme.levels - 1;
+    loop {
+        let vpn = vaddr >> (12 + level * scheme.vpn_bits) & vpn_mask;
+        let pte_addr = table + vpn * scheme.pte_size;
+        // page table accesses are checked by PMP as S mode loads
+        if !bus.contains(pte_addr, scheme.pte_size)
+            || !pmp::check(pte_addr, scheme.pte_size, Access::Load, SUPERVISOR)
+        {
+            return Err(access.access_fault(vaddr));
+        }
+        let pte = bus
+            .read(pte_addr, scheme.pte_size)
+            .map_err(|_| access.access_fault(vaddr))?;
+        // W without R is reserved, so are bits 63:54 without Svpbmt and Svnapot
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+            return Err(fault);
+        }
+        if pte & (PTE_R | PTE_X) != 0 {
+            // superpages need ppn aligned to their size
+            let ppn = pte >> 10 & ppn_mask;
+            if ppn & ((1 << (level * scheme.vpn_bits)) - 1) != 0 {
+                return Err(fault);
+            }
+            return Ok((pte, pte_addr, 12 + level * scheme.vpn_bits));
+        }
+        // pointer to next level
+        if level == 0 {
+            return Err(fault);
+        }
+        level -= 1;
+        table = (pte >> 10 & ppn_mask) * PAGE_SIZE;
+    }
+}
+
+// leaf permissions for effective privilege
+fn permitted(pte: u64, privilege: u64, access: Access) -> bool {
+    let status = read_csr_raw(MSTATUS);
+    let user_page = pte & PTE_U != 0;
+    let privilege_ok = match privilege {
+        USER => user_page,
+        // S mode can't execute user pages and reads or writes them only with SUM
+        SUPERVISOR => !user_page || (access != Access::Fetch && status & MSTATUS_SUM != 0),
+        _ => true,
+    };
+    privilege_ok
+        && match access {
+            Access::Fetch => pte & PTE_X != 0,
+            // MXR makes executable pages readable
+            Access::Load => pte & PTE_R != 0 || (status & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csr::{set_csr_raw, set_privilege, PMPADDR0, PMPCFG0},
+        dram::Dram,
+        elf_parser::{ProgramHeader, ProgramHeaderFlags, ProgramHeaderType},
+        loader::load_segments,
+        memmap::MemoryMap,
+    };
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    // root table at the start of RAM, next level right after it
+    const ROOT: u64 = RAM_BASE;
+    const L1: u64 = RAM_BASE + PAGE_SIZE;
+
+    fn sv39_bus() -> Bus {
+        let mut bus = Bus::new();
+        bus.register(
+            "dram",
+            RAM_BASE,
+            0x100_0000,
+            Box::new(Dram::new_dram(0x100_0000)),
+        )
+        .unwrap();
+        set_csr_raw(SATP, (SATP_SV39 << 60) | (ROOT / PAGE_SIZE));
+        // PMP entry 0 is NAPOT over the whole address space with RWX so S mode passes PMP
+        set_csr_raw(PMPADDR0, u64::MAX >> 10);
+        set_csr_raw(PMPCFG0, 0x1F);
+        bus
+    }
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        (paddr / PAGE_SIZE) << 10 | flags | PTE_V
+    }
+
+    // vpn[level] of Sv39 address
+    fn vpn(vaddr: u64, level: u32) -> u64 {
+        vaddr >> (12 + level * 9) & 0x1FF
+    }
+
+    #[test]
+    fn gigapage_translates_offset() {
+        let mut bus = sv39_bus();
+        let vaddr = 0x4000_1234;
+        bus.set_u64(
+            (ROOT + vpn(vaddr, 2) * 8) as usize,
+            pte(RAM_BASE, PTE_R | PTE_W),
+        )
+        .unwrap();
+        let paddr = page_walk(&mut bus, vaddr, Access::Load, SUPERVISOR);
+        assert_eq!(paddr, Ok(Some(RAM_BASE + 0x1234)));
+    }
+
+    #[test]
+    fn misaligned_gigapage_faults() {
+        let mut bus = sv39_bus();
+        let vaddr = 0x4000_0000;
+        // ppn has to be aligned to 1 GiB
+        bus.set_u64(
+            (ROOT + vpn(vaddr, 2) * 8) as usize,
+            pte(RAM_BASE + 0x20_0000, PTE_R),
+        )
+        .unwrap();
+        let paddr = page_walk(&mut bus, vaddr, Access::Load, SUPERVISOR);
+        assert_eq!(paddr, Err(Exception::LoadPageFault(vaddr)));
+    }
+
+    #[test]
+    fn misaligned_megapage_faults() {
+        let mut bus = sv39_bus();
+        let vaddr = 0x4020_0000;
+        bus.set_u64((ROOT + vpn(vaddr, 2) * 8) as usize, pte(L1, 0))
+            .unwrap();
+        // ppn has to be aligned to 2 MiB
+        bus.set_u64(
+            (L1 + vpn(vaddr, 1) * 8) as usize,
+            pte(RAM_BASE + PAGE_SIZE, PTE_R | PTE_X),
+        )
+        .unwrap();
+        let paddr = page_walk(&mut bus, vaddr, Access::Fetch, SUPERVISOR);
+        assert_eq!(paddr, Err(Exception::InstructionPageFault(vaddr)));
+    }
+
+    #[test]
+    fn walk_sets_accessed_and_dirty() {
+        let mut bus = sv39_bus();
+        let vaddr = 0x4000_0000;
+        let pte_addr = (ROOT + vpn(vaddr, 2) * 8) as usize;
+        bus.set_u64(pte_addr, pte(RAM_BASE, PTE_R | PTE_W)).unwrap();
+
+        // loads set only A
+        page_walk(&mut bus, vaddr, Access::Load, SUPERVISOR).unwrap();
+        let flags = bus.get_u64(pte_addr).unwrap() & (PTE_A | PTE_D);
+        assert_eq!(flags, PTE_A);
+
+        // store after the load hits the TLB entry without D and walks again
+        page_walk(&mut bus, vaddr, Access::Store, SUPERVISOR).unwrap();
+        let flags = bus.get_u64(pte_addr).unwrap() & (PTE_A | PTE_D);
+        assert_eq!(flags, PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn denied_access_leaves_pte_unchanged() {
+        let mut bus = sv39_bus();
+        let vaddr = 0x4000_0000;
+        let pte_addr = (ROOT + vpn(vaddr, 2) * 8) as usize;
+        let read_only = pte(RAM_BASE, PTE_R);
+        bus.set_u64(pte_addr, read_only).unwrap();
+        let paddr = page_walk(&mut bus, vaddr, Access::Store, SUPERVISOR);
+        assert_eq!(paddr, Err(Exception::StorePageFault(vaddr)));
+        assert_eq!(bus.get_u64(pte_addr), Ok(read_only));
+    }
+
+    #[test]
+    fn machine_mode_is_untranslated() {
+        let mut bus = sv39_bus();
+        let paddr = page_walk(&mut bus, 0x4000_0000, Access::Load, MACHINE);
+        assert_eq!(paddr, Ok(None));
+    }
+
+    // .text with code and read only .rodata right after it, both without file contents
+    const TEXT: u64 = RAM_BASE + 0x10_0000;
+    const RODATA: u64 = TEXT + PAGE_SIZE;
+
+    fn load_text_and_rodata() {
+        let segment = |addr, p_flags| ProgramHeader {
+            p_type: ProgramHeaderType::PtLoad,
+            p_flags,
+            segment_offset: 0,
+            virtual_address: addr,
+            physical_address: addr,
+            segment_size: 0,
+            mem_size: PAGE_SIZE,
+            alignment: PAGE_SIZE,
+        };
+        let headers = [
+            segment(TEXT, vec![ProgramHeaderFlags::PfR, ProgramHeaderFlags::PfX]),
+            segment(RODATA, vec![ProgramHeaderFlags::PfR]),
+        ];
+        let map = MemoryMap::default();
+        let mut dram = Dram::new_dram(map.ram.size as usize);
+        load_segments(&[], &headers, &map, &mut dram, None).unwrap();
+    }
+
+    #[test]
+    fn bare_accesses_follow_segment_permissions() {
+        let mut bus = sv39_bus();
+        load_text_and_rodata();
+        set_privilege(MACHINE);
+        assert_eq!(
+            translate(&mut bus, TEXT, 4, Access::Fetch),
+            Ok(TEXT as usize)
+        );
+        assert_eq!(
+            translate(&mut bus, RODATA, 4, Access::Fetch),
+            Err(Exception::InstructionAccessFault(RODATA))
+        );
+        assert_eq!(
+            translate(&mut bus, RODATA + 8, 8, Access::Load),
+            Ok(RODATA as usize + 8)
+        );
+        assert_eq!(
+            translate(&mut bus, RODATA + 8, 8, Access::Store),
+            Err(Exception::StoreAccessFault(RODATA + 8))
+        );
+        // store overlapping the end of .text
+        assert_eq!(
+            translate(&mut bus, RODATA - 4, 8, Access::Store),
+            Err(Exception::StoreAccessFault(RODATA - 4))
+        );
+        // memory outside of segments isn't restricted
+        assert_eq!(
+            translate(&mut bus, RODATA + PAGE_SIZE, 8, Access::Store),
+            Ok((RODATA + PAGE_SIZE) as usize)
+        );
+    }
+
+    #[test]
+    fn translated_accesses_ignore_segments() {
+        let mut bus = sv39_bus();
+        load_text_and_rodata();
+        set_privilege(SUPERVISOR);
+        // gigapage over RAM that is writable and executable
+        let vaddr = 0x4000_0000;
+        bus.set_u64(
+            (ROOT + vpn(vaddr, 2) * 8) as usize,
+            pte(RAM_BASE, PTE_R | PTE_W | PTE_X),
+        )
+        .unwrap();
+        let rodata = vaddr + (RODATA - RAM_BASE);
+        assert_eq!(
+            translate(&mut bus, rodata, 8, Access::Store),
+            Ok(RODATA as usize)
+        );
+        assert_eq!(
+            translate(&mut bus, rodata, 4, Access::Fetch),
+            Ok(RODATA as usize)
+        );
+    }
+}
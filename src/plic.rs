@@ -0,0 +1,376 @@
+// platform level interrupt controller, context 0 is M mode and context 1 is S mode of the hart
+// register layout follows SiFive PLIC used by QEMU virt machine
+use crate::{
+    bus::{BusFault, Device},
+    csr::{read_csr_raw, set_csr_raw, MIP},
+    PLIC,
+};
+
+pub const DEFAULT_PLIC_BASE: u64 = 0x0C00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+// source 0 is reserved and never raised
+pub const PLIC_SOURCES: usize = 1024;
+const CONTEXTS: usize = 2;
+const WORDS: usize = PLIC_SOURCES / 32;
+
+// register offsets
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+// priorities are 3 bits wide
+const PRIORITY_MASK: u32 = 0x7;
+
+// external interrupt pending bits raised by context 0 and 1
+const MIP_EIP: [u64; CONTEXTS] = [1 << 11, 1 << 9];
+
+#[derive(Debug)]
+pub struct Plic {
+    priority: Vec<u32>,
+    pending: [u32; WORDS],
+    // claimed and not completed yet, gateway holds further requests
+    in_service: [u32; WORDS],
+    enable: [[u32; WORDS]; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: vec![0; PLIC_SOURCES],
+            pending: [0; WORDS],
+            in_service: [0; WORDS],
+            enable: [[0; WORDS]; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        }
+    }
+
+    fn bit(words: &[u32; WORDS], source: usize) -> bool {
+        words[source / 32] >> (source % 32) & 1 == 1
+    }
+
+    // highest priority pending source enabled for context above its threshold, lowest id wins ties
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best: Option<(usize, u32)> = None;
+        for source in 1..PLIC_SOURCES {
+            let priority = self.priority[source];
+            if Plic::bit(&self.pending, source)
+                && !Plic::bit(&self.in_service, source)
+                && Plic::bit(&self.enable[context], source)
+                && priority > self.threshold[context]
+                && best.is_none_or(|(_, p)| priority > p)
+            {
+                best = Some((source, priority));
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source / 32] &= !(1 << (source % 32));
+                self.in_service[source / 32] |= 1 << (source % 32);
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        // completion for source that isn't enabled in the context is ignored
+        if source < PLIC_SOURCES && Plic::bit(&self.enable[context], source) {
+            self.in_service[source / 32] &= !(1 << (source % 32));
+        }
+    }
+
+    fn read(&mut self, offset: u64) -> u32 {
+        match offset {
+            PRIORITY..PENDING => {
+                let source = (offset / 4) as usize;
+                self.priority.get(source).copied().unwrap_or(0)
+            }
+            PENDING..ENABLE => self
+                .pending
+                .get(((offset - PENDING) / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                match self.enable.get(context) {
+                    Some(enable) => enable.get(word).copied().unwrap_or(0),
+                    None => 0,
+                }
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= CONTEXTS {
+                    return 0;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u64, val: u32) {
+        match offset {
+            PRIORITY..PENDING => {
+                let source = (offset / 4) as usize;
+                if source != 0 && source < PLIC_SOURCES {
+                    self.priority[source] = val & PRIORITY_MASK;
+                }
+            }
+            // pending bits are read only
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                if context < CONTEXTS && word < WORDS {
+                    // source 0 can't be enabled
+                    let val = if word == 0 { val & !1 } else { val };
+                    self.enable[context][word] = val;
+                }
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= CONTEXTS {
+                    return;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = val & PRIORITY_MASK,
+                    4 => self.complete(context, val),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // MEIP and SEIP follow whether context has claimable interrupt
+    fn update_mip(&self) {
+        let mut mip = read_csr_raw(MIP);
+        for (context, bit) in MIP_EIP.iter().enumerate() {
+            if self.best(context).is_some() {
+                mip |= bit;
+            } else {
+                mip &= !bit;
+            }
+        }
+        set_csr_raw(MIP, mip);
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Plic::new()
+    }
+}
+
+// interrupt request from device, stays pending until claimed
+pub fn raise_irq(source: usize) {
+    assert!(
+        source != 0 && source < PLIC_SOURCES,
+        "invalid PLIC source {}",
+        source
+    );
+    PLIC.with(|x| {
+        let mut plic = x.borrow_mut();
+        plic.pending[source / 32] |= 1 << (source % 32);
+        plic.update_mip();
+    });
+}
+
+// withdraws request of level triggered device before it was claimed
+pub fn lower_irq(source: usize) {
+    assert!(
+        source != 0 && source < PLIC_SOURCES,
+        "invalid PLIC source {}",
+        source
+    );
+    PLIC.with(|x| {
+        let mut plic = x.borrow_mut();
+        plic.pending[source / 32] &= !(1 << (source % 32));
+        plic.update_mip();
+    });
+}
+
+// bus handle of the PLIC, state stays in the thread local so devices can raise interrupts
+pub struct PlicDevice;
+
+// registers are 32 bit wide, narrower and wider aligned accesses read as zero and their writes
+// are ignored
+impl Device for PlicDevice {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        if size != 4 {
+            return Ok(0);
+        }
+        PLIC.with(|x| {
+            let mut plic = x.borrow_mut();
+            let val = plic.read(offset);
+            plic.update_mip();
+            Ok(val as u64)
+        })
+    }
+
+    fn write(&mut self, offset: u64, size: u64, val: u64) -> Result<(), BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        if size == 4 {
+            PLIC.with(|x| {
+                let mut plic = x.borrow_mut();
+                plic.write(offset, val as u32);
+                plic.update_mip();
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: u64 = 0;
+    const S: u64 = 1;
+    const MEIP: u64 = 1 << 11;
+    const SEIP: u64 = 1 << 9;
+
+    fn set_priority(plic: &mut PlicDevice, source: u64, priority: u64) {
+        plic.write(PRIORITY + source * 4, 4, priority).unwrap();
+    }
+
+    fn enable(plic: &mut PlicDevice, context: u64, source: u64) {
+        let word = ENABLE + context * ENABLE_STRIDE + source / 32 * 4;
+        let old = plic.read(word, 4).unwrap();
+        plic.write(word, 4, old | 1 << (source % 32)).unwrap();
+    }
+
+    fn set_threshold(plic: &mut PlicDevice, context: u64, threshold: u64) {
+        plic.write(CONTEXT + context * CONTEXT_STRIDE, 4, threshold)
+            .unwrap();
+    }
+
+    fn claim(plic: &mut PlicDevice, context: u64) -> u64 {
+        plic.read(CONTEXT + context * CONTEXT_STRIDE + 4, 4)
+            .unwrap()
+    }
+
+    fn complete(plic: &mut PlicDevice, context: u64, source: u64) {
+        plic.write(CONTEXT + context * CONTEXT_STRIDE + 4, 4, source)
+            .unwrap();
+    }
+
+    #[test]
+    fn priority_and_threshold() {
+        let mut plic = PlicDevice;
+        enable(&mut plic, M, 3);
+        enable(&mut plic, M, 40);
+        // priority 0 never interrupts
+        raise_irq(3);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        assert_eq!(claim(&mut plic, M), 0);
+
+        set_priority(&mut plic, 3, 2);
+        set_priority(&mut plic, 40, 5);
+        raise_irq(40);
+        assert_ne!(read_csr_raw(MIP) & MEIP, 0);
+        assert_eq!(plic.read(PENDING + 4, 4), Ok(1 << 8));
+        // sources at or below threshold are masked
+        set_threshold(&mut plic, M, 5);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        set_threshold(&mut plic, M, 2);
+        assert_eq!(claim(&mut plic, M), 40);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        set_threshold(&mut plic, M, 1);
+        assert_eq!(claim(&mut plic, M), 3);
+        // priorities and thresholds are 3 bits wide
+        set_priority(&mut plic, 3, 0xFF);
+        assert_eq!(plic.read(PRIORITY + 12, 4), Ok(7));
+        set_threshold(&mut plic, M, 0xF);
+        assert_eq!(plic.read(CONTEXT, 4), Ok(7));
+    }
+
+    #[test]
+    fn lowest_id_wins_tie() {
+        let mut plic = PlicDevice;
+        for source in [7, 5, 9] {
+            set_priority(&mut plic, source, 1);
+            enable(&mut plic, M, source);
+            raise_irq(source as usize);
+        }
+        assert_eq!(claim(&mut plic, M), 5);
+        assert_eq!(claim(&mut plic, M), 7);
+        assert_eq!(claim(&mut plic, M), 9);
+        assert_eq!(claim(&mut plic, M), 0);
+    }
+
+    #[test]
+    fn claim_and_complete() {
+        let mut plic = PlicDevice;
+        set_priority(&mut plic, 10, 1);
+        enable(&mut plic, M, 10);
+        raise_irq(10);
+        assert_eq!(claim(&mut plic, M), 10);
+        assert_eq!(plic.read(PENDING, 4), Ok(0));
+        // new request of source in service is held until completion
+        raise_irq(10);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        assert_eq!(claim(&mut plic, M), 0);
+        // completion of other source changes nothing
+        complete(&mut plic, M, 11);
+        assert_eq!(claim(&mut plic, M), 0);
+        complete(&mut plic, M, 10);
+        assert_ne!(read_csr_raw(MIP) & MEIP, 0);
+        assert_eq!(claim(&mut plic, M), 10);
+        complete(&mut plic, M, 10);
+        // lowered request before it was claimed is gone
+        raise_irq(10);
+        lower_irq(10);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        assert_eq!(claim(&mut plic, M), 0);
+    }
+
+    #[test]
+    fn contexts_raise_own_bit() {
+        let mut plic = PlicDevice;
+        set_priority(&mut plic, 1, 1);
+        set_priority(&mut plic, 2, 1);
+        enable(&mut plic, M, 1);
+        enable(&mut plic, S, 2);
+        raise_irq(2);
+        assert_eq!(read_csr_raw(MIP) & (MEIP | SEIP), SEIP);
+        raise_irq(1);
+        assert_eq!(read_csr_raw(MIP) & (MEIP | SEIP), MEIP | SEIP);
+        assert_eq!(claim(&mut plic, S), 2);
+        assert_eq!(read_csr_raw(MIP) & (MEIP | SEIP), MEIP);
+        // source enabled only in M context can't be claimed from S
+        assert_eq!(claim(&mut plic, S), 0);
+        assert_eq!(claim(&mut plic, M), 1);
+        assert_eq!(read_csr_raw(MIP) & (MEIP | SEIP), 0);
+    }
+
+    #[test]
+    fn source_0_is_reserved() {
+        let mut plic = PlicDevice;
+        set_priority(&mut plic, 0, 7);
+        assert_eq!(plic.read(PRIORITY, 4), Ok(0));
+        plic.write(ENABLE, 4, u32::MAX as u64).unwrap();
+        assert_eq!(plic.read(ENABLE, 4), Ok(0xFFFF_FFFE));
+        // only 32 bit accesses reach registers
+        assert_eq!(plic.read(ENABLE, 8), Ok(0));
+        assert_eq!(plic.read(ENABLE + 2, 4), Err(BusFault::Misaligned));
+    }
+}
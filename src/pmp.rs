@@ -0,0 +1,283 @@
+// physical memory protection checked on every physical access, all 64 entries are implemented
+// unless --pmp sets fewer, with 0 entries PMP is not implemented and every access succeeds
+use crate::{
+    csr::{read_csr_raw, MACHINE, PMPADDR0, PMPCFG0},
+    mmu::Access,
+    xlen, PMP_ENTRIES,
+};
+
+// architectural number of entries, pmpcfg0..15 and pmpaddr0..63
+pub const MAX_PMP_ENTRIES: usize = 64;
+
+// implemented entries, the rest read as zero and ignore writes
+pub fn entries() -> usize {
+    PMP_ENTRIES.with(|x| *x.borrow())
+}
+
+// entry of pmpaddr register
+pub fn addr_entry(reg: u16) -> usize {
+    (reg - PMPADDR0) as usize
+}
+
+// pmpcfg fields
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// address matching modes
+const PMP_OFF: u8 = 0;
+const PMP_TOR: u8 = 1;
+const PMP_NA4: u8 = 2;
+const PMP_NAPOT: u8 = 3;
+
+// entries per pmpcfg register, in RV64 only even pmpcfg registers exist
+fn cfg_location(entry: usize) -> (u16, usize) {
+    if xlen!() == 32 {
+        (PMPCFG0 + (entry / 4) as u16, entry % 4)
+    } else {
+        (PMPCFG0 + (entry / 8 * 2) as u16, entry % 8)
+    }
+}
+
+fn cfg(entry: usize) -> u8 {
+    let (reg, byte) = cfg_location(entry);
+    (read_csr_raw(reg) >> (byte * 8)) as u8
+}
+
+fn locked(entry: usize) -> bool {
+    cfg(entry) & PMP_L != 0
+}
+
+// new pmpcfg value, locked entries keep old value and so do the ones with reserved W without R
+pub fn cfg_write_value(reg: u16, val: u64) -> u64 {
+    let old = read_csr_raw(reg);
+    let bytes = (xlen!() / 8) as usize;
+    // RV64 has entries of two RV32 registers in each even register
+    let first = if xlen!() == 32 {
+        (reg - PMPCFG0) as usize * 4
+    } else {
+        (reg - PMPCFG0) as usize / 2 * 8
+    };
+    let mut res = 0;
+    for byte in (0..bytes).take_while(|byte| first + byte < entries()) {
+        let old_cfg = (old >> (byte * 8)) as u8;
+        // bits 6:5 are reserved
+        let new_cfg = (val >> (byte * 8)) as u8 & !(0b11 << 5);
+        let keep = old_cfg & PMP_L != 0 || new_cfg & (PMP_R | PMP_W) == PMP_W;
+        res |= (if keep { old_cfg } else { new_cfg } as u64) << (byte * 8);
+    }
+    res
+}
+
+// pmpaddr writes are ignored when its entry is locked, or when the next entry is locked TOR
+pub fn addr_locked(reg: u16) -> bool {
+    let entry = addr_entry(reg);
+    locked(entry)
+        || (entry + 1 < entries() && locked(entry + 1) && (cfg(entry + 1) & PMP_A) >> 3 == PMP_TOR)
+}
+
+// address range matched by the entry, end is exclusive
+fn range(entry: usize, mode: u8) -> (u64, u64) {
+    let addr = read_csr_raw(PMPADDR0 + entry as u16);
+    match mode {
+        PMP_TOR => {
+            let start = if entry == 0 {
+                0
+            } else {
+                read_csr_raw(PMPADDR0 + entry as u16 - 1) << 2
+            };
+            (start, addr << 2)
+        }
+        PMP_NA4 => (addr << 2, (addr << 2) + 4),
+        // trailing ones encode size, 2^(n + 3) bytes
+        PMP_NAPOT => {
+            let size = 1u128 << (addr.trailing_ones() + 3);
+            let start = (addr << 2) & !(size - 1) as u64;
+            (start, (start as u128 + size).min(u64::MAX as u128) as u64)
+        }
+        _ => unreachable!(),
+    }
+}
+
+// checks access of size bytes at physical address, false means access fault
+pub fn check(paddr: u64, size: u64, access: Access, privilege: u64) -> bool {
+    if entries() == 0 {
+        return true;
+    }
+    // fast path, nothing configured
+    let cfg_regs = if xlen!() == 32 { 16 } else { 8 };
+    let configured = (0..cfg_regs).any(|i| {
+        let reg = if xlen!() == 32 { i } else { i * 2 };
+        read_csr_raw(PMPCFG0 + reg) != 0
+    });
+    if !configured {
+        return privilege == MACHINE;
+    }
+
+    let end = paddr.saturating_add(size);
+    for entry in 0..entries() {
+        let cfg = cfg(entry);
+        let mode = (cfg & PMP_A) >> 3;
+        if mode == PMP_OFF {
+            continue;
+        }
+        let (start, stop) = range(entry, mode);
+        // lowest entry matching any byte decides, access has to be fully inside it
+        if paddr >= stop || end <= start {
+            continue;
+        }
+        if paddr < start || end > stop {
+            return false;
+        }
+        // M mode is checked only against locked entries
+        if privilege == MACHINE && cfg & PMP_L == 0 {
+            return true;
+        }
+        let bit = match access {
+            Access::Fetch => PMP_X,
+            Access::Load => PMP_R,
+            Access::Store => PMP_W,
+        };
+        return cfg & bit != 0;
+    }
+    // no match, only M mode succeeds
+    privilege == MACHINE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::{set_csr_raw, USER};
+
+    fn set_entries(n: usize) {
+        PMP_ENTRIES.with(|x| *x.borrow_mut() = n);
+    }
+
+    #[test]
+    fn all_entries_by_default() {
+        assert_eq!(entries(), MAX_PMP_ENTRIES);
+        // nothing configured, only M mode passes
+        assert!(check(0x8000_0000, 4, Access::Load, MACHINE));
+        assert!(!check(0x8000_0000, 4, Access::Load, USER));
+    }
+
+    #[test]
+    fn tor_entry_0_starts_at_zero() {
+        set_csr_raw(PMPADDR0, 0x2000_0000);
+        assert_eq!(range(0, PMP_TOR), (0, 0x8000_0000));
+    }
+
+    #[test]
+    fn tor_starts_at_previous_entry() {
+        set_csr_raw(PMPADDR0, 0x2000_0000);
+        set_csr_raw(PMPADDR0 + 1, 0x2000_0400);
+        assert_eq!(range(1, PMP_TOR), (0x8000_0000, 0x8000_1000));
+    }
+
+    #[test]
+    fn na4_covers_four_bytes() {
+        set_csr_raw(PMPADDR0, 0x2000_0001);
+        assert_eq!(range(0, PMP_NA4), (0x8000_0004, 0x8000_0008));
+    }
+
+    #[test]
+    fn napot_size_from_trailing_ones() {
+        // 0x8000_0000 with 4 KiB size
+        set_csr_raw(PMPADDR0, 0x2000_01FF);
+        assert_eq!(range(0, PMP_NAPOT), (0x8000_0000, 0x8000_1000));
+    }
+
+    #[test]
+    fn napot_all_ones_covers_everything() {
+        set_csr_raw(PMPADDR0, u64::MAX);
+        assert_eq!(range(0, PMP_NAPOT), (0, u64::MAX));
+    }
+
+    #[test]
+    fn cfg_write_keeps_locked_entry() {
+        set_entries(16);
+        let locked = (PMP_L | PMP_R) as u64;
+        set_csr_raw(PMPCFG0, locked << 8);
+        let val = cfg_write_value(PMPCFG0, 0x0F0F);
+        assert_eq!(val, 0x0F | locked << 8);
+    }
+
+    #[test]
+    fn cfg_write_ignores_w_without_r() {
+        set_entries(16);
+        set_csr_raw(PMPCFG0, PMP_X as u64);
+        assert_eq!(cfg_write_value(PMPCFG0, PMP_W as u64), PMP_X as u64);
+        assert_eq!(
+            cfg_write_value(PMPCFG0, (PMP_R | PMP_W) as u64),
+            (PMP_R | PMP_W) as u64
+        );
+    }
+
+    #[test]
+    fn cfg_write_clears_reserved_and_unimplemented() {
+        set_entries(4);
+        let val = cfg_write_value(PMPCFG0, 0x7F7F_7F7F_7F7F_7F7F);
+        // bits 6:5 are reserved, entries 4..7 aren't implemented
+        assert_eq!(val, 0x1F1F_1F1F);
+    }
+
+    #[test]
+    fn cfg_write_without_pmp() {
+        set_entries(0);
+        assert_eq!(cfg_write_value(PMPCFG0, 0x0F), 0);
+    }
+
+    #[test]
+    fn addr_locked_by_next_tor_entry() {
+        set_entries(16);
+        let tor = PMP_L | PMP_TOR << 3;
+        set_csr_raw(PMPCFG0, (tor as u64) << 8);
+        assert!(addr_locked(PMPADDR0));
+        assert!(addr_locked(PMPADDR0 + 1));
+        assert!(!addr_locked(PMPADDR0 + 2));
+    }
+
+    #[test]
+    fn addr_not_locked_by_next_napot_entry() {
+        set_entries(16);
+        let napot = PMP_L | PMP_NAPOT << 3;
+        set_csr_raw(PMPCFG0, (napot as u64) << 8);
+        assert!(!addr_locked(PMPADDR0));
+        assert!(addr_locked(PMPADDR0 + 1));
+    }
+
+    #[test]
+    fn addr_last_entry_has_no_next() {
+        set_entries(16);
+        // entry 16 isn't implemented even though its cfg byte would be locked TOR
+        let tor = PMP_L | PMP_TOR << 3;
+        set_csr_raw(PMPCFG0 + 4, tor as u64);
+        assert!(!addr_locked(PMPADDR0 + 15));
+    }
+
+    #[test]
+    fn check_without_pmp() {
+        set_entries(0);
+        assert!(check(0x8000_0000, 8, Access::Store, USER));
+    }
+
+    #[test]
+    fn check_lowest_entry_wins() {
+        set_entries(16);
+        // entry 0 read only 4 KiB at 0x8000_0000, entry 1 everything RWX
+        set_csr_raw(PMPADDR0, 0x2000_01FF);
+        set_csr_raw(PMPADDR0 + 1, u64::MAX);
+        let ro = PMP_R | PMP_NAPOT << 3;
+        let rwx = PMP_R | PMP_W | PMP_X | PMP_NAPOT << 3;
+        set_csr_raw(PMPCFG0, ro as u64 | (rwx as u64) << 8);
+        assert!(check(0x8000_0000, 8, Access::Load, USER));
+        assert!(!check(0x8000_0000, 8, Access::Store, USER));
+        assert!(check(0x8000_1000, 8, Access::Store, USER));
+        // straddling the boundary of entry 0 fails
+        assert!(!check(0x8000_0FFC, 8, Access::Load, USER));
+        // M mode ignores unlocked entries
+        assert!(check(0x8000_0000, 8, Access::Store, MACHINE));
+    }
+}
@@ -0,0 +1,67 @@
+// read only memory, contents are set when loading the program and guest writes are ignored
+use crate::bus::{BusFault, Device};
+
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size],
+        }
+    }
+
+    // len bytes at offset, or a fault when they don't fit
+    fn range(&mut self, offset: usize, len: usize) -> Result<&mut [u8], BusFault> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get_mut(offset..end))
+            .ok_or(BusFault::AccessFault)
+    }
+
+    pub fn load(&mut self, offset: usize, bytes: &[u8]) -> Result<(), BusFault> {
+        self.range(offset, bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn zero(&mut self, offset: usize, len: usize) -> Result<(), BusFault> {
+        self.range(offset, len)?.fill(0);
+        Ok(())
+    }
+}
+
+impl Device for Rom {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(self.range(offset as usize, size as usize)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, _offset: u64, _size: u64, _val: u64) -> Result<(), BusFault> {
+        Ok(())
+    }
+
+    fn resident(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_outside_faults() {
+        let mut rom = Rom::new(0x10);
+        assert_eq!(rom.load(0xC, &[1, 2, 3, 4]), Ok(()));
+        assert_eq!(rom.read(0xC, 4), Ok(0x0403_0201));
+        assert_eq!(rom.load(0xD, &[1, 2, 3, 4]), Err(BusFault::AccessFault));
+        assert_eq!(rom.zero(0x8, 0x9), Err(BusFault::AccessFault));
+        assert_eq!(rom.zero(usize::MAX, 2), Err(BusFault::AccessFault));
+        assert_eq!(rom.read(0xC, 8), Err(BusFault::AccessFault));
+        // writes are ignored
+        assert_eq!(rom.write(0, 4, 0xFFFF_FFFF), Ok(()));
+        assert_eq!(rom.read(0, 4), Ok(0));
+    }
+}
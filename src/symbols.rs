@@ -0,0 +1,214 @@
+// symbols of the loaded ELF, used to name addresses in traces and reports
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    elf_parser::{Symbol, SymbolBinding, SymbolType, SHN_LORESERVE, SHN_UNDEF},
+    SYMBOLS,
+};
+
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    // symbols naming code or data sorted by value, one per address
+    by_addr: Vec<Symbol>,
+    by_name: HashMap<Rc<str>, u64>,
+}
+
+impl SymbolIndex {
+    pub fn new(symbols: Vec<Symbol>) -> Self {
+        let mut by_name = HashMap::new();
+        let mut by_addr = vec![];
+        for symbol in symbols {
+            if symbol.name.is_empty() || symbol.section == SHN_UNDEF {
+                continue;
+            }
+            // first definition wins, .symtab comes before .dynsym
+            by_name.entry(symbol.name.clone()).or_insert(symbol.value);
+
+            // absolute values, sections, files and $x/$d mapping symbols aren't code or data
+            let named = matches!(
+                symbol.sym_type,
+                SymbolType::SttFunc | SymbolType::SttObject | SymbolType::SttNotype
+            );
+            if named && symbol.section < SHN_LORESERVE && !symbol.name.starts_with('$') {
+                by_addr.push(symbol);
+            }
+        }
+
+        // global functions are preferred over other symbols at the same address
+        by_addr.sort_by_key(|s| {
+            (
+                s.value,
+                s.binding == SymbolBinding::StbLocal,
+                s.sym_type != SymbolType::SttFunc,
+            )
+        });
+        by_addr.dedup_by_key(|s| s.value);
+
+        Self { by_addr, by_name }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    // closest symbol at or below addr with offset from it, symbols without size
+    // extend to the next one
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let i = self.by_addr.partition_point(|s| s.value <= addr);
+        let symbol = self.by_addr.get(i.checked_sub(1)?)?;
+        let offset = addr - symbol.value;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    pub fn address(&self, name: &str) -> Option<u64> {
+        self.by_name.get(name).copied()
+    }
+}
+
+// address as <func+0x1c> with a leading space, empty when no symbol covers it
+pub fn describe(addr: u64) -> String {
+    SYMBOLS.with(|x| match x.borrow().lookup(addr) {
+        Some((symbol, 0)) => format!(" <{}>", symbol.name),
+        Some((symbol, offset)) => format!(" <{}+{:#x}>", symbol.name, offset),
+        None => String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(
+        name: &str,
+        value: u64,
+        size: u64,
+        sym_type: SymbolType,
+        binding: SymbolBinding,
+    ) -> Symbol {
+        Symbol {
+            name: name.into(),
+            value,
+            size,
+            sym_type,
+            binding,
+            section: 1,
+        }
+    }
+
+    fn index() -> SymbolIndex {
+        SymbolIndex::new(vec![
+            symbol(
+                "_start",
+                0x1000,
+                0,
+                SymbolType::SttNotype,
+                SymbolBinding::StbGlobal,
+            ),
+            symbol(
+                "helper",
+                0x1040,
+                0,
+                SymbolType::SttNotype,
+                SymbolBinding::StbLocal,
+            ),
+            // function and local label at the same address
+            symbol(
+                "main",
+                0x1040,
+                0x20,
+                SymbolType::SttFunc,
+                SymbolBinding::StbGlobal,
+            ),
+            symbol(
+                "buffer",
+                0x2000,
+                0x100,
+                SymbolType::SttObject,
+                SymbolBinding::StbGlobal,
+            ),
+            symbol(
+                "$x",
+                0x1000,
+                0,
+                SymbolType::SttNotype,
+                SymbolBinding::StbLocal,
+            ),
+            symbol("main.c", 0, 0, SymbolType::SttFile, SymbolBinding::StbLocal),
+            Symbol {
+                section: SHN_UNDEF,
+                ..symbol("puts", 0, 0, SymbolType::SttFunc, SymbolBinding::StbGlobal)
+            },
+            Symbol {
+                section: 0xFFF1,
+                ..symbol(
+                    "STACK",
+                    0x9000,
+                    0,
+                    SymbolType::SttNotype,
+                    SymbolBinding::StbGlobal,
+                )
+            },
+        ])
+    }
+
+    fn name(index: &SymbolIndex, addr: u64) -> Option<(String, u64)> {
+        index
+            .lookup(addr)
+            .map(|(symbol, offset)| (symbol.name.to_string(), offset))
+    }
+
+    #[test]
+    fn lookup_by_address() {
+        let index = index();
+        assert_eq!(index.len(), 3);
+        assert_eq!(name(&index, 0xFFF), None);
+        assert_eq!(name(&index, 0x1000), Some(("_start".into(), 0)));
+        // symbol without size extends to the next one
+        assert_eq!(name(&index, 0x103C), Some(("_start".into(), 0x3C)));
+        // global function wins over local label
+        assert_eq!(name(&index, 0x1040), Some(("main".into(), 0)));
+        assert_eq!(name(&index, 0x105C), Some(("main".into(), 0x1C)));
+        // but not past its size
+        assert_eq!(name(&index, 0x1060), None);
+        assert_eq!(name(&index, 0x20FF), Some(("buffer".into(), 0xFF)));
+        assert_eq!(name(&index, 0x2100), None);
+        // absolute symbols don't name addresses
+        assert_eq!(name(&index, 0x9000), None);
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        let index = index();
+        assert_eq!(index.address("main"), Some(0x1040));
+        assert_eq!(index.address("helper"), Some(0x1040));
+        assert_eq!(index.address("buffer"), Some(0x2000));
+        assert_eq!(index.address("STACK"), Some(0x9000));
+        // undefined symbols have no address
+        assert_eq!(index.address("puts"), None);
+        assert_eq!(index.address("missing"), None);
+    }
+
+    #[test]
+    fn first_definition_wins() {
+        let index = SymbolIndex::new(vec![
+            symbol("f", 0x10, 4, SymbolType::SttFunc, SymbolBinding::StbGlobal),
+            symbol("f", 0x20, 4, SymbolType::SttFunc, SymbolBinding::StbGlobal),
+        ]);
+        assert_eq!(index.address("f"), Some(0x10));
+    }
+
+    #[test]
+    fn describe_address() {
+        SYMBOLS.with(|x| *x.borrow_mut() = index());
+        assert_eq!(describe(0x1040), " <main>");
+        assert_eq!(describe(0x1044), " <main+0x4>");
+        assert_eq!(describe(0x1060), "");
+    }
+}
@@ -0,0 +1,193 @@
+// software TLB caching leaf PTEs of recent translations
+// direct mapped by virtual page number, superpages are cached as their 4 KiB slices
+use crate::TLB;
+
+pub const DEFAULT_TLB_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    // virtual page number of 4 KiB page
+    vpn: u64,
+    asid: u64,
+    global: bool,
+    // leaf PTE with A and D as they are in memory
+    pte: u64,
+    // log2 of page size, 12 for base pages and more for superpages
+    page_shift: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+}
+
+#[derive(Debug)]
+pub struct Tlb {
+    entries: Vec<Option<Entry>>,
+    pub stats: TlbStats,
+}
+
+impl Tlb {
+    // number of entries has to be power of two, 0 disables the TLB
+    pub fn new(entries: usize) -> Self {
+        assert!(entries == 0 || entries.is_power_of_two());
+        Self {
+            entries: vec![None; entries],
+            stats: TlbStats::default(),
+        }
+    }
+
+    fn slot(&self, vpn: u64) -> Option<usize> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(vpn as usize & (self.entries.len() - 1))
+        }
+    }
+
+    fn lookup(&mut self, vpn: u64, asid: u64, required: u64) -> Option<(u64, u32)> {
+        let hit = self
+            .slot(vpn)
+            .and_then(|slot| self.entries[slot])
+            .filter(|e| e.vpn == vpn && (e.global || e.asid == asid))
+            .filter(|e| e.pte & required == required);
+        match hit {
+            Some(e) => {
+                self.stats.hits += 1;
+                Some((e.pte, e.page_shift))
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, vpn: u64, asid: u64, global: bool, pte: u64, page_shift: u32) {
+        if let Some(slot) = self.slot(vpn) {
+            self.entries[slot] = Some(Entry {
+                vpn,
+                asid,
+                global,
+                pte,
+                page_shift,
+            });
+        }
+    }
+
+    // drops entries matching given virtual address and ASID, None matches everything
+    // global entries are dropped only when no ASID is given
+    fn flush(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        self.stats.flushes += 1;
+        for entry in self.entries.iter_mut() {
+            let Some(e) = entry else {
+                continue;
+            };
+            // entry covers the whole superpage it was cached from
+            let addr_match = vaddr.is_none_or(|vaddr| (e.vpn << 12 ^ vaddr) >> e.page_shift == 0);
+            let asid_match = asid.is_none_or(|asid| !e.global && e.asid == asid);
+            if addr_match && asid_match {
+                *entry = None;
+            }
+        }
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb::new(DEFAULT_TLB_ENTRIES)
+    }
+}
+
+// cached leaf PTE and page shift for the virtual page, entries without all required PTE bits
+// set count as misses
+pub fn lookup(vaddr: u64, asid: u64, required: u64) -> Option<(u64, u32)> {
+    TLB.with(|x| x.borrow_mut().lookup(vaddr >> 12, asid, required))
+}
+
+pub fn insert(vaddr: u64, asid: u64, global: bool, pte: u64, page_shift: u32) {
+    TLB.with(|x| {
+        x.borrow_mut()
+            .insert(vaddr >> 12, asid, global, pte, page_shift)
+    });
+}
+
+// Sfence.vma, rs1 = x0 and rs2 = x0 are passed as None
+pub fn flush(vaddr: Option<u64>, asid: Option<u64>) {
+    TLB.with(|x| x.borrow_mut().flush(vaddr, asid));
+}
+
+pub fn stats() -> TlbStats {
+    TLB.with(|x| x.borrow().stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PTE: u64 = 0xCF;
+
+    #[test]
+    fn hit_needs_matching_asid() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(0x4000_1000 >> 12, 1, false, PTE, 12);
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 1, 0), Some((PTE, 12)));
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 2, 0), None);
+    }
+
+    #[test]
+    fn lookup_requires_pte_bits() {
+        let mut tlb = Tlb::new(8);
+        // accessed but not dirty
+        tlb.insert(0x4000_1000 >> 12, 1, false, 0x4F, 12);
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 1, 0x80), None);
+    }
+
+    #[test]
+    fn global_entry_survives_asid_flush() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(0x4000_1000 >> 12, 1, true, PTE, 12);
+        tlb.insert(0x4000_2000 >> 12, 1, false, PTE, 12);
+        tlb.flush(None, Some(1));
+        // global entries match every ASID
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 2, 0), Some((PTE, 12)));
+        assert_eq!(tlb.lookup(0x4000_2000 >> 12, 1, 0), None);
+
+        tlb.flush(None, None);
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 2, 0), None);
+    }
+
+    #[test]
+    fn flush_by_address_drops_superpage_slices() {
+        let mut tlb = Tlb::new(8);
+        // two 4 KiB slices of the 2 MiB page at 0x4020_0000 and a base page after it
+        tlb.insert(0x4020_1000 >> 12, 1, false, PTE, 21);
+        tlb.insert(0x4020_2000 >> 12, 1, false, PTE, 21);
+        tlb.insert(0x4040_3000 >> 12, 1, false, PTE, 12);
+        // any address inside the superpage flushes all of its slices
+        tlb.flush(Some(0x403F_F000), None);
+        assert_eq!(tlb.lookup(0x4020_1000 >> 12, 1, 0), None);
+        assert_eq!(tlb.lookup(0x4020_2000 >> 12, 1, 0), None);
+        assert_eq!(tlb.lookup(0x4040_3000 >> 12, 1, 0), Some((PTE, 12)));
+    }
+
+    #[test]
+    fn flush_by_address_keeps_other_pages() {
+        let mut tlb = Tlb::new(8);
+        tlb.insert(0x4000_1000 >> 12, 1, false, PTE, 12);
+        tlb.insert(0x4000_2000 >> 12, 1, false, PTE, 12);
+        tlb.flush(Some(0x4000_1ABC), Some(1));
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 1, 0), None);
+        assert_eq!(tlb.lookup(0x4000_2000 >> 12, 1, 0), Some((PTE, 12)));
+    }
+
+    #[test]
+    fn disabled_tlb_always_misses() {
+        let mut tlb = Tlb::new(0);
+        tlb.insert(0x4000_1000 >> 12, 1, false, PTE, 12);
+        assert_eq!(tlb.lookup(0x4000_1000 >> 12, 1, 0), None);
+        assert_eq!(tlb.stats.misses, 1);
+    }
+}
@@ -0,0 +1,459 @@
+// synchronous exceptions, instructions return them instead of panicking and the run loop turns
+// them into traps
+use crate::{
+    bus::Bus,
+    csr::{
+        current_privilege, read_csr_raw, set_csr_raw, set_privilege, MACHINE, MCAUSE, MEDELEG,
+        MEPC, MIDELEG, MIE, MIP, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPRV,
+        MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_TSR, MTVAL, MTVEC, SCAUSE, SEPC, STVAL,
+        STVEC, SUPERVISOR, USER,
+    },
+    get_pc,
+    misc::dbg_reg,
+    read_reg, set_pc, symbols, xlen, A0, A1, A2, A7, DEBUG,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    // target address
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    // raw instruction
+    IllegalInstruction(u32),
+    // address of ebreak
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    // privilege level of the caller
+    EnvironmentCall(u64),
+    // virtual address
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    // value written to mcause
+    pub fn cause(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            // 8 from U, 9 from S, 11 from M
+            Exception::EnvironmentCall(privilege) => 8 + privilege,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    // value written to mtval
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::Breakpoint(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAddressMisaligned(addr)
+            | Exception::StoreAccessFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StorePageFault(addr) => addr,
+            Exception::IllegalInstruction(raw) => raw as u64,
+            Exception::EnvironmentCall(_) => 0,
+        }
+    }
+}
+
+// enters trap handler at mtvec, or stvec when delegated, returns false when there is no
+// handler installed
+pub fn take_trap(e: Exception) -> bool {
+    let delegated = read_csr_raw(MEDELEG) >> e.cause() & 1 == 1;
+    enter(e.cause(), e.tval(), delegated)
+}
+
+// interrupts in priority order, external before software before timer, M before S
+const INTERRUPT_PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+// highest priority interrupt that is pending, enabled and not masked at current privilege
+pub fn pending_interrupt() -> Option<u64> {
+    let pending = read_csr_raw(MIP) & read_csr_raw(MIE);
+    if pending == 0 {
+        return None;
+    }
+    let privilege = current_privilege();
+    let status = read_csr_raw(MSTATUS);
+    let mideleg = read_csr_raw(MIDELEG);
+    // interrupts for higher privilege are always enabled, for the same only with xIE set
+    let m_enabled = privilege < MACHINE || status & MSTATUS_MIE != 0;
+    let s_enabled =
+        privilege < SUPERVISOR || (privilege == SUPERVISOR && status & MSTATUS_SIE != 0);
+    INTERRUPT_PRIORITY.into_iter().find(|&code| {
+        let bit = 1 << code;
+        if pending & bit == 0 {
+            false
+        } else if mideleg & bit != 0 {
+            s_enabled
+        } else {
+            m_enabled
+        }
+    })
+}
+
+// enters interrupt handler, PC points at the instruction that hasn't been executed yet
+pub fn take_interrupt(code: u64) -> bool {
+    let delegated = read_csr_raw(MIDELEG) >> code & 1 == 1;
+    enter(1 << (xlen!() - 1) | code, 0, delegated)
+}
+
+fn enter(cause: u64, tval: u64, delegated: bool) -> bool {
+    let privilege = current_privilege();
+    // traps are never delegated to lower privilege than the current one
+    let supervisor = delegated && privilege <= SUPERVISOR;
+    let tvec = read_csr_raw(if supervisor { STVEC } else { MTVEC });
+    if tvec == 0 {
+        return false;
+    }
+
+    let status = read_csr_raw(MSTATUS);
+    let status = if supervisor {
+        set_csr_raw(SEPC, get_pc!());
+        set_csr_raw(SCAUSE, cause);
+        set_csr_raw(STVAL, tval);
+        // SPIE = SIE, SIE = 0, SPP = privilege the trap was taken from
+        let spie = if status & MSTATUS_SIE != 0 {
+            MSTATUS_SPIE
+        } else {
+            0
+        };
+        (status & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | (privilege << 8)
+    } else {
+        set_csr_raw(MEPC, get_pc!());
+        set_csr_raw(MCAUSE, cause);
+        set_csr_raw(MTVAL, tval);
+        // MPIE = MIE, MIE = 0, MPP = privilege the trap was taken from
+        let mpie = if status & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        (status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | (privilege << 11)
+    };
+    set_csr_raw(MSTATUS, status);
+    set_privilege(if supervisor { SUPERVISOR } else { MACHINE });
+
+    // vectored mode jumps to base + 4 * cause for interrupts, exceptions always use the base
+    let interrupt = cause >> (xlen!() - 1) == 1;
+    let base = tvec & !0b11;
+    let pc = if tvec & 0b11 == 1 && interrupt {
+        base + 4 * (cause & 0xFF)
+    } else {
+        base
+    };
+    set_pc!(pc);
+    true
+}
+
+// Mret, returns address to continue at, None when not executed in M mode
+pub fn mret() -> Option<u64> {
+    if current_privilege() < MACHINE {
+        return None;
+    }
+    let status = read_csr_raw(MSTATUS);
+    let mpp = (status & MSTATUS_MPP) >> 11;
+    // MIE = MPIE, MPIE = 1, MPP = U, returning to lower privilege clears MPRV
+    let mie = if status & MSTATUS_MPIE != 0 {
+        MSTATUS_MIE
+    } else {
+        0
+    };
+    let mut status = (status & !(MSTATUS_MIE | MSTATUS_MPP)) | mie | MSTATUS_MPIE | (USER << 11);
+    if mpp != MACHINE {
+        status &= !MSTATUS_MPRV;
+    }
+    set_csr_raw(MSTATUS, status);
+    set_privilege(mpp);
+    Some(read_csr_raw(MEPC))
+}
+
+// Sret, returns address to continue at, None when executed in U mode or in S mode with TSR set
+pub fn sret() -> Option<u64> {
+    let privilege = current_privilege();
+    let status = read_csr_raw(MSTATUS);
+    if privilege < SUPERVISOR || (privilege == SUPERVISOR && status & MSTATUS_TSR != 0) {
+        return None;
+    }
+    let spp = (status & MSTATUS_SPP) >> 8;
+    // SIE = SPIE, SPIE = 1, SPP = U, S and U never have MPRV set
+    let sie = if status & MSTATUS_SPIE != 0 {
+        MSTATUS_SIE
+    } else {
+        0
+    };
+    let status = (status & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | sie | MSTATUS_SPIE;
+    set_csr_raw(MSTATUS, status);
+    set_privilege(spp);
+    Some(read_csr_raw(SEPC))
+}
+
+// outcome of an ecall handled on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    // execution continues after the ecall
+    Done,
+    // program exited with the status code
+    Exit(i32),
+    Unknown,
+}
+
+// handles ecall on the host when program has no trap handler
+// buffers are physical addresses, error is the fault of reading one
+pub fn emulate_syscall(bus: &mut Bus) -> Result<Syscall, Exception> {
+    match (read_reg!(A0), read_reg!(A7)) {
+        // write to stdout
+        (1, 64) => {
+            let addr = read_reg!(A1);
+            let len = read_reg!(A2);
+            let bytes = (0..len)
+                .map(|i| {
+                    let addr = addr.wrapping_add(i);
+                    bus.read(addr, 1)
+                        .map(|byte| byte as u8)
+                        .map_err(|_| Exception::LoadAccessFault(addr))
+                })
+                .collect::<Result<Vec<u8>, Exception>>()?;
+            let s = String::from_utf8_lossy(&bytes);
+            if DEBUG {
+                println!("{:x}", addr);
+                println!("ecall: print\n{:?}", s);
+            } else {
+                print!("{}", s);
+            }
+            Ok(Syscall::Done)
+        }
+        // exit, exit_group
+        (code, 93 | 94) => Ok(Syscall::Exit(code as i32)),
+        _ => Ok(Syscall::Unknown),
+    }
+}
+
+// prints state of the hart after unhandled exception
+pub fn report_fault(e: Exception, raw: Option<u32>) {
+    println!("unhandled exception: {:?}", e);
+    println!("pc: {:x}{}", get_pc!(), symbols::describe(get_pc!()));
+    match e {
+        Exception::IllegalInstruction(_) | Exception::EnvironmentCall(_) => {}
+        _ => println!("address: {:x}", e.tval()),
+    }
+    if let Some(raw) = raw {
+        println!("instruction: {:x}", raw);
+    }
+    dbg_reg();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csr::init_csrs, instruction::instruction::execute_32, isa::parse_isa, set_reg, ISA,
+    };
+
+    const PC: u64 = 0x8000_0000;
+    const HANDLER: u64 = 0x8000_1000;
+
+    fn setup() -> Bus {
+        let isa = parse_isa("rv64gc").unwrap();
+        init_csrs(&isa);
+        ISA.with(|x| *x.borrow_mut() = isa);
+        set_pc!(PC);
+        Bus::new()
+    }
+
+    #[test]
+    fn exit_syscall() {
+        let mut bus = Bus::new();
+        set_reg!(A0, 3);
+        set_reg!(A7, 93);
+        assert_eq!(emulate_syscall(&mut bus), Ok(Syscall::Exit(3)));
+        set_reg!(A7, 94);
+        assert_eq!(emulate_syscall(&mut bus), Ok(Syscall::Exit(3)));
+        set_reg!(A7, 1000);
+        assert_eq!(emulate_syscall(&mut bus), Ok(Syscall::Unknown));
+        // write of a buffer that isn't mapped
+        set_reg!(A0, 1);
+        set_reg!(A1, 0x1000);
+        set_reg!(A2, 4);
+        set_reg!(A7, 64);
+        assert_eq!(
+            emulate_syscall(&mut bus),
+            Err(Exception::LoadAccessFault(0x1000))
+        );
+    }
+
+    #[test]
+    fn illegal_instruction_trap() {
+        let mut bus = setup();
+        // custom-0 opcode, no extension is implemented there
+        let raw = 0x0000_050B;
+        let e = execute_32(raw, &mut bus).unwrap_err();
+        assert_eq!(e, Exception::IllegalInstruction(raw));
+        // without mtvec the fault is left to the run loop
+        assert!(!take_trap(e));
+        assert_eq!(get_pc!(), PC);
+
+        // exceptions use the base even in vectored mode
+        set_csr_raw(MTVEC, HANDLER | 1);
+        set_csr_raw(MSTATUS, MSTATUS_MIE);
+        assert!(take_trap(e));
+        assert_eq!(get_pc!(), HANDLER);
+        assert_eq!(read_csr_raw(MEPC), PC);
+        assert_eq!(read_csr_raw(MCAUSE), 2);
+        assert_eq!(read_csr_raw(MTVAL), raw as u64);
+        let status = read_csr_raw(MSTATUS);
+        assert_eq!(status & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+        assert_eq!(status & MSTATUS_MPP, MACHINE << 11);
+    }
+
+    #[test]
+    fn compressed_illegal_reports_16_bits() {
+        let mut bus = setup();
+        // all zero halfword is defined to be illegal
+        assert_eq!(
+            execute_32(0xFFFF_0000, &mut bus),
+            Err(Exception::IllegalInstruction(0))
+        );
+        // without C compressed encodings are illegal too
+        ISA.with(|x| *x.borrow_mut() = parse_isa("rv64g").unwrap());
+        assert_eq!(
+            execute_32(0x0001, &mut bus),
+            Err(Exception::IllegalInstruction(0x0001))
+        );
+    }
+
+    #[test]
+    fn breakpoint_and_ecall() {
+        let mut bus = setup();
+        // ebreak
+        assert_eq!(
+            execute_32(0x0010_0073, &mut bus),
+            Err(Exception::Breakpoint(PC))
+        );
+        // ecall
+        let e = execute_32(0x0000_0073, &mut bus).unwrap_err();
+        assert_eq!(e, Exception::EnvironmentCall(MACHINE));
+        set_csr_raw(MTVEC, HANDLER);
+        assert!(take_trap(e));
+        assert_eq!(read_csr_raw(MCAUSE), 11);
+        assert_eq!(read_csr_raw(MTVAL), 0);
+    }
+
+    #[test]
+    fn delegated_exception_and_sret() {
+        let mut bus = setup();
+        set_csr_raw(MTVEC, HANDLER);
+        set_csr_raw(STVEC, HANDLER + 0x100);
+        set_csr_raw(MEDELEG, 1 << 2);
+        set_csr_raw(MSTATUS, MSTATUS_SIE);
+        set_privilege(USER);
+        let e = execute_32(0x0000_050B, &mut bus).unwrap_err();
+        assert!(take_trap(e));
+        assert_eq!(current_privilege(), SUPERVISOR);
+        assert_eq!(get_pc!(), HANDLER + 0x100);
+        assert_eq!(read_csr_raw(SEPC), PC);
+        assert_eq!(read_csr_raw(SCAUSE), 2);
+        assert_eq!(read_csr_raw(STVAL), 0x050B);
+        // M mode registers are left alone
+        assert_eq!(read_csr_raw(MCAUSE), 0);
+        let status = read_csr_raw(MSTATUS);
+        assert_eq!(
+            status & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SPIE
+        );
+
+        assert_eq!(sret(), Some(PC));
+        assert_eq!(current_privilege(), USER);
+        let status = read_csr_raw(MSTATUS);
+        assert_eq!(
+            status & (MSTATUS_SIE | MSTATUS_SPIE),
+            MSTATUS_SIE | MSTATUS_SPIE
+        );
+        // causes that aren't delegated still go to M mode
+        assert!(take_trap(Exception::Breakpoint(PC)));
+        assert_eq!(current_privilege(), MACHINE);
+        assert_eq!(get_pc!(), HANDLER);
+        assert_eq!(read_csr_raw(MSTATUS) & MSTATUS_MPP, USER << 11);
+    }
+
+    #[test]
+    fn machine_mode_traps_are_not_delegated() {
+        setup();
+        set_csr_raw(MTVEC, HANDLER);
+        set_csr_raw(STVEC, HANDLER + 0x100);
+        set_csr_raw(MEDELEG, u64::MAX);
+        assert!(take_trap(Exception::IllegalInstruction(0)));
+        assert_eq!(current_privilege(), MACHINE);
+        assert_eq!(get_pc!(), HANDLER);
+        assert_eq!(read_csr_raw(SCAUSE), 0);
+    }
+
+    #[test]
+    fn delegated_interrupts() {
+        setup();
+        const STIP: u64 = 1 << 5;
+        const MTIP: u64 = 1 << 7;
+        set_csr_raw(MTVEC, HANDLER);
+        set_csr_raw(STVEC, HANDLER + 0x100);
+        set_csr_raw(MIDELEG, STIP);
+        set_csr_raw(MIE, STIP | MTIP);
+        set_csr_raw(MIP, STIP);
+        set_privilege(SUPERVISOR);
+        // S mode interrupts in S mode need SIE
+        assert_eq!(pending_interrupt(), None);
+        set_csr_raw(MSTATUS, MSTATUS_SIE);
+        assert_eq!(pending_interrupt(), Some(5));
+        // M mode interrupts are enabled below M regardless of MIE and go first
+        set_csr_raw(MIP, STIP | MTIP);
+        assert_eq!(pending_interrupt(), Some(7));
+        set_csr_raw(MIP, STIP);
+        assert!(take_interrupt(5));
+        assert_eq!(current_privilege(), SUPERVISOR);
+        assert_eq!(get_pc!(), HANDLER + 0x100);
+        assert_eq!(read_csr_raw(SCAUSE), 1 << 63 | 5);
+        assert_eq!(read_csr_raw(MSTATUS) & MSTATUS_SPP, SUPERVISOR << 8);
+        // SIE is cleared by the trap
+        assert_eq!(pending_interrupt(), None);
+        // delegated interrupts never reach M mode
+        set_privilege(MACHINE);
+        set_csr_raw(MSTATUS, MSTATUS_MIE);
+        assert_eq!(pending_interrupt(), None);
+    }
+
+    #[test]
+    fn mret_state() {
+        setup();
+        set_csr_raw(MEPC, PC);
+        set_csr_raw(MSTATUS, MSTATUS_MPIE | (SUPERVISOR << 11) | MSTATUS_MPRV);
+        assert_eq!(mret(), Some(PC));
+        assert_eq!(current_privilege(), SUPERVISOR);
+        let status = read_csr_raw(MSTATUS);
+        assert_eq!(
+            status & (MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP | MSTATUS_MPRV),
+            MSTATUS_MIE | MSTATUS_MPIE
+        );
+        // xRET from lower privilege than its own is refused
+        assert_eq!(mret(), None);
+        set_csr_raw(MSTATUS, MSTATUS_TSR);
+        assert_eq!(sret(), None);
+        set_privilege(USER);
+        set_csr_raw(MSTATUS, 0);
+        assert_eq!(sret(), None);
+    }
+}
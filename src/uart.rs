@@ -0,0 +1,403 @@
+// NS16550A compatible UART, transmitted bytes go to host stdout or a file and host stdin can feed
+// the receive FIFO when asked for, interrupt line is connected to the PLIC
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use crate::{
+    bus::{BusFault, Device},
+    plic, UART,
+};
+
+pub const DEFAULT_UART_BASE: u64 = 0x1000_0000;
+// PLIC source of the UART in QEMU virt machine
+pub const UART_IRQ: usize = 10;
+pub const UART_SIZE: u64 = 0x100;
+const FIFO_SIZE: usize = 16;
+// host input is checked once per this many steps
+const POLL_INTERVAL: u32 = 1024;
+
+// register offsets
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+// interrupt enable bits
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+
+// interrupt identification values
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+
+// line status bits
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+pub struct Uart {
+    rx: VecDeque<u8>,
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    // divisor latch, kept only so it reads back
+    dll: u8,
+    dlm: u8,
+    // transmitter is always empty, this tracks whether it was reported since last write
+    thre_pending: bool,
+    irq: bool,
+    poll: u32,
+}
+
+impl Uart {
+    // without input receive FIFO stays empty
+    pub fn new(input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Self {
+        Self {
+            rx: VecDeque::new(),
+            input,
+            output,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+            irq: false,
+            poll: 0,
+        }
+    }
+
+    fn iir(&self) -> u8 {
+        let id = if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        };
+        if self.fcr & FCR_ENABLE != 0 {
+            id | IIR_FIFO
+        } else {
+            id
+        }
+    }
+
+    fn read(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => self.rx.pop_front().unwrap_or(0),
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                // reading THRE as the interrupt source acknowledges it
+                if iir & 0x0F == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                dr | LSR_THRE | LSR_TEMT
+            }
+            // carrier detect, data set ready and clear to send are always set
+            MSR => 0xB0,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, val: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = val,
+            RBR_THR_DLL => {
+                if self.mcr & MCR_LOOP != 0 {
+                    if self.rx.len() < FIFO_SIZE {
+                        self.rx.push_back(val);
+                    }
+                } else {
+                    // host output errors can't be reported to the guest
+                    let _ = self.output.write_all(&[val]);
+                    let _ = self.output.flush();
+                }
+                self.thre_pending = true;
+            }
+            IER_DLM if dlab => self.dlm = val,
+            IER_DLM => {
+                // enabling THRE interrupt with empty transmitter raises it right away
+                if val & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = val & 0x0F;
+            }
+            IIR_FCR => {
+                if val & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = val & FCR_ENABLE;
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val & 0x1F,
+            SCR => self.scr = val,
+            _ => {}
+        }
+    }
+
+    fn poll_input(&mut self) {
+        if let Some(input) = &self.input {
+            while self.rx.len() < FIFO_SIZE {
+                match input.try_recv() {
+                    Ok(byte) => self.rx.push_back(byte),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    // interrupt line is level triggered, PLIC is told only about changes
+    fn update_irq(&mut self) {
+        let irq = self.iir() & IIR_NONE == 0;
+        if irq != self.irq {
+            self.irq = irq;
+            if irq {
+                plic::raise_irq(UART_IRQ);
+            } else {
+                plic::lower_irq(UART_IRQ);
+            }
+        }
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Uart::new(None, Box::new(std::io::stdout()))
+    }
+}
+
+// bytes from host stdin, read on a separate thread so the hart never blocks
+pub fn stdin_input() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0; 64];
+        loop {
+            let n = match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            for byte in &buf[..n] {
+                if sender.send(*byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// moves host input to receive FIFO, called once per step
+pub fn tick() {
+    UART.with(|x| {
+        let mut uart = x.borrow_mut();
+        uart.poll += 1;
+        if uart.poll >= POLL_INTERVAL {
+            uart.poll = 0;
+            uart.poll_input();
+            uart.update_irq();
+        }
+    });
+}
+
+// bus handle of the UART, state stays in the thread local so tick can reach it
+pub struct UartDevice;
+
+// registers are one byte wide, wider aligned accesses use only the lowest byte
+impl Device for UartDevice {
+    fn read(&mut self, offset: u64, size: u64) -> Result<u64, BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        UART.with(|x| {
+            let mut uart = x.borrow_mut();
+            let val = uart.read(offset);
+            uart.update_irq();
+            Ok(val as u64)
+        })
+    }
+
+    fn write(&mut self, offset: u64, size: u64, val: u64) -> Result<(), BusFault> {
+        if !offset.is_multiple_of(size) {
+            return Err(BusFault::Misaligned);
+        }
+        UART.with(|x| {
+            let mut uart = x.borrow_mut();
+            uart.write(offset, val as u8);
+            uart.update_irq();
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, sync::mpsc::Sender};
+
+    use super::*;
+    use crate::{
+        csr::{read_csr_raw, MIP},
+        plic::PlicDevice,
+    };
+
+    // output shared with the test after the UART took ownership of it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup() -> (Output, Sender<u8>) {
+        let output = Output::default();
+        let (sender, receiver) = mpsc::channel();
+        UART.with(|x| *x.borrow_mut() = Uart::new(Some(receiver), Box::new(output.clone())));
+        (output, sender)
+    }
+
+    fn read(offset: u64) -> u8 {
+        UartDevice.read(offset, 1).unwrap() as u8
+    }
+
+    fn write(offset: u64, val: u8) {
+        UartDevice.write(offset, 1, val as u64).unwrap();
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (output, _) = setup();
+        write(LCR, LCR_DLAB | 0x03);
+        write(RBR_THR_DLL, 0x0C);
+        write(IER_DLM, 0x01);
+        assert_eq!(read(RBR_THR_DLL), 0x0C);
+        assert_eq!(read(IER_DLM), 0x01);
+        // latch writes neither transmit nor enable interrupts
+        assert!(output.0.borrow().is_empty());
+        write(LCR, 0x03);
+        assert_eq!(read(IER_DLM), 0);
+        write(RBR_THR_DLL, b'x');
+        assert_eq!(*output.0.borrow(), b"x");
+        write(LCR, LCR_DLAB);
+        assert_eq!(read(RBR_THR_DLL), 0x0C);
+    }
+
+    #[test]
+    fn transmit_and_receive() {
+        let (output, input) = setup();
+        for byte in b"hi" {
+            write(RBR_THR_DLL, *byte);
+        }
+        assert_eq!(*output.0.borrow(), b"hi");
+        assert_eq!(read(LSR), LSR_THRE | LSR_TEMT);
+
+        for byte in b"ok" {
+            input.send(*byte).unwrap();
+        }
+        // host input shows up only after polling
+        assert_eq!(read(LSR) & LSR_DR, 0);
+        for _ in 0..POLL_INTERVAL {
+            tick();
+        }
+        assert_eq!(read(LSR) & LSR_DR, LSR_DR);
+        assert_eq!(read(RBR_THR_DLL), b'o');
+        assert_eq!(read(RBR_THR_DLL), b'k');
+        assert_eq!(read(LSR) & LSR_DR, 0);
+        assert_eq!(read(RBR_THR_DLL), 0);
+    }
+
+    #[test]
+    fn loopback_fifo() {
+        let (output, _) = setup();
+        write(MCR, MCR_LOOP);
+        for byte in 0..20 {
+            write(RBR_THR_DLL, byte);
+        }
+        assert!(output.0.borrow().is_empty());
+        // bytes past FIFO size are lost
+        let received: Vec<u8> = (0..20).map(|_| read(RBR_THR_DLL)).collect();
+        assert_eq!(&received[..FIFO_SIZE], (0..16).collect::<Vec<u8>>());
+        assert!(received[FIFO_SIZE..].iter().all(|byte| *byte == 0));
+
+        write(RBR_THR_DLL, 1);
+        write(IIR_FCR, FCR_ENABLE | FCR_CLEAR_RX);
+        assert_eq!(read(LSR) & LSR_DR, 0);
+    }
+
+    #[test]
+    fn interrupt_identification() {
+        setup();
+        assert_eq!(read(IIR_FCR), IIR_NONE);
+        write(IIR_FCR, FCR_ENABLE);
+        assert_eq!(read(IIR_FCR), IIR_NONE | IIR_FIFO);
+        write(IIR_FCR, 0);
+        // enabling THRE with empty transmitter reports it once
+        write(IER_DLM, IER_THRE);
+        assert_eq!(read(IIR_FCR), IIR_THRE);
+        assert_eq!(read(IIR_FCR), IIR_NONE);
+        write(MCR, MCR_LOOP);
+        write(RBR_THR_DLL, b'a');
+        write(IER_DLM, IER_THRE | IER_RDA);
+        // received data goes before THRE
+        assert_eq!(read(IIR_FCR), IIR_RDA);
+        read(RBR_THR_DLL);
+        assert_eq!(read(IIR_FCR), IIR_THRE);
+        assert_eq!(read(IIR_FCR), IIR_NONE);
+    }
+
+    #[test]
+    fn interrupt_goes_through_plic() {
+        setup();
+        const MEIP: u64 = 1 << 11;
+        // priority 1 for the UART source, enabled in M context
+        PlicDevice.write(UART_IRQ as u64 * 4, 4, 1).unwrap();
+        PlicDevice.write(0x2000, 4, 1 << UART_IRQ).unwrap();
+        write(MCR, MCR_LOOP);
+        write(IER_DLM, IER_RDA);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+        write(RBR_THR_DLL, b'a');
+        assert_ne!(read_csr_raw(MIP) & MEIP, 0);
+        // emptying FIFO lowers the line before the interrupt was claimed
+        read(RBR_THR_DLL);
+        assert_eq!(read_csr_raw(MIP) & MEIP, 0);
+    }
+}
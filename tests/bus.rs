@@ -0,0 +1,55 @@
+// devices registered from outside the crate
+use risc_v::{memmap::DEFAULT_RAM_BASE, Bus, BusFault, Device, MemoryMap};
+
+// counts writes, reads return the number of them
+#[derive(Default)]
+struct Counter {
+    writes: u64,
+}
+
+impl Device for Counter {
+    fn read(&mut self, _offset: u64, _size: u64) -> Result<u64, BusFault> {
+        Ok(self.writes)
+    }
+
+    fn write(&mut self, offset: u64, _size: u64, _val: u64) -> Result<(), BusFault> {
+        if offset != 0 {
+            return Err(BusFault::AccessFault);
+        }
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_device() {
+    let mut bus = Bus::new();
+    bus.register("counter", 0x1000_1000, 0x10, Box::new(Counter::default()))
+        .unwrap();
+    bus.write(0x1000_1000, 4, 7).unwrap();
+    bus.write(0x1000_1000, 4, 7).unwrap();
+    assert_eq!(bus.read(0x1000_1000, 4), Ok(2));
+    assert_eq!(bus.write(0x1000_1004, 4, 7), Err(BusFault::AccessFault));
+    // outside of every region
+    assert_eq!(bus.read(0x1000_1010, 4), Err(BusFault::AccessFault));
+}
+
+#[test]
+fn device_shadows_ram() {
+    let map = MemoryMap::default();
+    assert!(map.validate().is_ok());
+    let mut bus = Bus::new();
+    bus.register(
+        "dram",
+        map.ram.base,
+        map.ram.size,
+        Box::new(risc_v::dram::Dram::new_dram(map.ram.size as usize)),
+    )
+    .unwrap();
+    bus.register("counter", DEFAULT_RAM_BASE, 8, Box::new(Counter::default()))
+        .unwrap();
+    bus.write(DEFAULT_RAM_BASE, 8, 0x55).unwrap();
+    assert_eq!(bus.read(DEFAULT_RAM_BASE, 8), Ok(1));
+    bus.write(DEFAULT_RAM_BASE + 8, 8, 0x55).unwrap();
+    assert_eq!(bus.read(DEFAULT_RAM_BASE + 8, 8), Ok(0x55));
+}